@@ -0,0 +1,274 @@
+//! The `builder` module provides a fluent builder for webmachine resources, so that callbacks
+//! can be provided as closures without having to wrap each one with `callback`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::context::{BodyStream, WebmachineContext};
+use crate::{async_callback, owned_callback, LazyBody, RawResponseFuture, WebmachineError, WebmachineResource};
+
+macro_rules! callback_setters {
+  ($($name:ident: $result:ty),* $(,)?) => {
+    $(
+      #[doc = concat!("Sets the `", stringify!($name), "` callback (see `WebmachineResource::", stringify!($name), "`)")]
+      #[allow(clippy::unused_unit)]
+      pub fn $name<F>(mut self, cb: F) -> Self
+        where F: Fn(&mut WebmachineContext, &WebmachineResource) -> $result + Send + Sync + 'a {
+        self.resource.$name = owned_callback(cb);
+        self
+      }
+    )*
+  }
+}
+
+macro_rules! optional_callback_setters {
+  ($($name:ident: $result:ty),* $(,)?) => {
+    $(
+      #[doc = concat!("Sets the `", stringify!($name), "` callback (see `WebmachineResource::", stringify!($name), "`)")]
+      #[allow(clippy::unused_unit)]
+      pub fn $name<F>(mut self, cb: F) -> Self
+        where F: Fn(&mut WebmachineContext, &WebmachineResource) -> $result + Send + Sync + 'a {
+        self.resource.$name = Some(owned_callback(cb));
+        self
+      }
+    )*
+  }
+}
+
+macro_rules! async_callback_setters {
+  ($($name:ident: $result:ty),* $(,)?) => {
+    $(
+      #[doc = concat!("Sets the `", stringify!($name), "` async callback (see `WebmachineResource::", stringify!($name), "`)")]
+      pub fn $name<F, FT>(mut self, cb: F) -> Self
+        where F: Fn(&mut WebmachineContext, &WebmachineResource) -> FT + Send + Sync + 'a,
+              FT: Future<Output = $result> + Send + 'a {
+        self.resource.$name = Some(async_callback(cb));
+        self
+      }
+    )*
+  }
+}
+
+macro_rules! list_setters {
+  ($($name:ident),* $(,)?) => {
+    $(
+      #[doc = concat!("Sets the `", stringify!($name), "` list (see `WebmachineResource::", stringify!($name), "`)")]
+      pub fn $name(mut self, values: &[&'a str]) -> Self {
+        self.resource.$name = values.to_vec();
+        self
+      }
+    )*
+  }
+}
+
+macro_rules! flag_setters {
+  ($($name:ident),* $(,)?) => {
+    $(
+      #[doc = concat!("Sets the `", stringify!($name), "` flag (see `WebmachineResource::", stringify!($name), "`)")]
+      pub fn $name(mut self, value: bool) -> Self {
+        self.resource.$name = value;
+        self
+      }
+    )*
+  }
+}
+
+/// Builder for a `WebmachineResource`. Anything that is not set will have the same default
+/// as `WebmachineResource::default()`.
+///
+/// ```
+/// use webmachine_rust::builder::WebmachineResourceBuilder;
+///
+/// let resource = WebmachineResourceBuilder::new()
+///   .allowed_methods(&["OPTIONS", "GET", "HEAD", "POST"])
+///   .resource_exists(|context, _| context.request.request_path != "/missing")
+///   .render_response(|_, _| Some("{\"data\": [1, 2, 3, 4]}".to_string()))
+///   .process_post(|_, _| Ok(true))
+///   .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct WebmachineResourceBuilder<'a> {
+  resource: WebmachineResource<'a>
+}
+
+impl <'a> WebmachineResourceBuilder<'a> {
+  /// Creates a new builder with all the defaults
+  pub fn new() -> Self {
+    WebmachineResourceBuilder::default()
+  }
+
+  /// Builds the resource
+  pub fn build(self) -> WebmachineResource<'a> {
+    self.resource
+  }
+
+  /// Sets the raw request handler, which bypasses the state machine (see
+  /// `WebmachineResource::raw_request_handler`)
+  pub fn raw_request_handler<F>(mut self, handler: F) -> Self
+    where F: Fn(hyper::Request<hyper::Body>) -> RawResponseFuture + Send + Sync + 'a {
+    self.resource.raw_request_handler = Some(Arc::new(handler));
+    self
+  }
+
+  /// Sets the maximum size of the request body (see `WebmachineResource::max_body_size`)
+  pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+    self.resource.max_body_size = Some(max_body_size);
+    self
+  }
+
+  /// Sets the status code to return if `process_post` returns `Ok(false)` (see
+  /// `WebmachineResource::post_not_processed_status`)
+  pub fn post_not_processed_status(mut self, status: u16) -> Self {
+    self.resource.post_not_processed_status = Some(status);
+    self
+  }
+
+  callback_setters! {
+    render_response: Option<String>,
+    render_response_stream: Option<BodyStream>,
+    render_error: Option<String>,
+    body_length: Option<usize>,
+    available: bool,
+    uri_too_long: bool,
+    malformed_request: bool,
+    not_authorized: Option<String>,
+    forbidden: bool,
+    unsupported_content_headers: bool,
+    valid_entity_length: bool,
+    finish_request: (),
+    options: Option<HashMap<String, Vec<String>>>,
+    resource_exists: bool,
+    previously_existed: bool,
+    moved_permanently: Option<String>,
+    moved_temporarily: Option<String>,
+    is_conflict: bool,
+    allow_missing_post: bool,
+    generate_etag: Option<String>,
+    last_modified: Option<DateTime<FixedOffset>>,
+    delete_resource: Result<bool, WebmachineError>,
+    post_is_create: bool,
+    process_post: Result<bool, WebmachineError>,
+    create_path: Result<String, WebmachineError>,
+    process_put: Result<bool, WebmachineError>,
+    process_patch: Result<bool, WebmachineError>,
+    multiple_choices: bool,
+    memento_timegate: Option<String>,
+    expires: Option<DateTime<FixedOffset>>
+  }
+
+  optional_callback_setters! {
+    finalise_response: (),
+    allowed_methods_fn: Vec<String>,
+    content_types_accepted_fn: bool,
+    render_response_lazy: Option<LazyBody>
+  }
+
+  async_callback_setters! {
+    render_response_async: Option<String>,
+    resource_exists_async: bool,
+    delete_resource_async: Result<bool, WebmachineError>,
+    process_post_async: Result<bool, WebmachineError>,
+    process_put_async: Result<bool, WebmachineError>,
+    process_patch_async: Result<bool, WebmachineError>,
+    not_authorized_async: Option<String>,
+    forbidden_async: bool,
+    moved_permanently_async: Option<String>,
+    moved_temporarily_async: Option<String>,
+    is_conflict_async: bool,
+    generate_etag_async: Option<String>,
+    create_path_async: Result<String, WebmachineError>
+  }
+
+  list_setters! {
+    known_methods,
+    allowed_methods,
+    allowed_query_params,
+    acceptable_content_types,
+    produces,
+    languages_provided,
+    charsets_provided,
+    encodings_provided,
+    variances
+  }
+
+  flag_setters! {
+    allow_head,
+    cors_allow_null_origin,
+    delete_returns_no_content,
+    options_allow_known_methods,
+    options_requires_existing_resource,
+    negotiation_fallback_to_default,
+    media_type_suffix_matching,
+    accept_ranges,
+    require_conditional_for_writes,
+    supports_webdav_if,
+    prevent_content_sniffing,
+    reject_unknown_query_params
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use crate::context::{WebmachineContext, WebmachineRequest};
+
+  use super::*;
+
+  #[test]
+  fn builder_defaults_test() {
+    let resource = WebmachineResourceBuilder::new().build();
+    let default = WebmachineResource::default();
+    expect!(resource.allowed_methods).to(be_equal_to(default.allowed_methods));
+    expect!(resource.produces).to(be_equal_to(default.produces));
+    expect!(resource.accept_ranges).to(be_equal_to(default.accept_ranges));
+    expect!(resource.finalise_response.is_none()).to(be_true());
+    expect!(resource.resource_exists_async.is_none()).to(be_true());
+  }
+
+  #[test]
+  fn builder_async_callback_test() {
+    let resource = WebmachineResourceBuilder::new()
+      .resource_exists_async(|context, _| {
+        let path = context.request.request_path.clone();
+        async move { path == "/exists" }
+      })
+      .build();
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        request_path: "/exists".to_string(),
+        .. WebmachineRequest::default()
+      },
+      .. WebmachineContext::default()
+    };
+    let exists = futures::executor::block_on(resource.resource_exists_async.as_ref().unwrap()(&mut context, &resource));
+    expect!(exists).to(be_true());
+  }
+
+  #[test]
+  fn builder_test() {
+    let resource = WebmachineResourceBuilder::new()
+      .allowed_methods(&["GET", "POST"])
+      .accept_ranges(true)
+      .resource_exists(|context, _| context.request.request_path == "/exists")
+      .finalise_response(|context, _| context.response.status = 299)
+      .build();
+    expect!(resource.allowed_methods.clone()).to(be_equal_to(vec!["GET", "POST"]));
+    expect!(resource.accept_ranges).to(be_true());
+
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        request_path: "/exists".to_string(),
+        .. WebmachineRequest::default()
+      },
+      .. WebmachineContext::default()
+    };
+    let exists = resource.resource_exists.lock().unwrap()(&mut context, &resource);
+    expect!(exists).to(be_true());
+    resource.finalise_response.as_ref().unwrap().lock().unwrap()(&mut context, &resource);
+    expect!(context.response.status).to(be_equal_to(299));
+  }
+}
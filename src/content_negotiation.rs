@@ -3,6 +3,7 @@
 
 use itertools::Itertools;
 use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
 use crate::headers::HeaderValue;
 use crate::context::WebmachineRequest;
 use crate::WebmachineResource;
@@ -12,6 +13,9 @@ use crate::WebmachineResource;
 pub enum MediaTypeMatch {
     /// Full match
     Full,
+    /// Match where the structured syntax suffix of the sub-type (i.e. `ld+json`) matched the
+    /// sub-type (i.e. `json`)
+    Suffix,
     /// Match where the sub-type was a wild card
     SubStar,
     /// Full whild card match (type and sub-type)
@@ -32,7 +36,10 @@ pub struct MediaType {
 }
 
 impl MediaType {
-    /// Parse a string into a MediaType struct
+    /// Parse a string into a MediaType struct. Parsing is lenient and never fails: a missing
+    /// sub-type is treated as a wildcard (i.e. `text/` and `text` are both parsed as `text/*`),
+    /// and an empty string is parsed as `*/*`. Parameters are not parsed, so should be removed
+    /// first (see `HeaderValue::as_media_type`).
     pub fn parse_string(media_type: &str) -> MediaType {
       let types: Vec<&str> = media_type.splitn(2, '/').collect_vec();
       if types.is_empty() || types[0].is_empty() {
@@ -50,12 +57,13 @@ impl MediaType {
       }
     }
 
-    /// Adds a quality weight to the media type
-    pub fn with_weight(&self, weight: &String) -> MediaType {
+    /// Adds a quality weight to the media type. Weights that are not valid (i.e. not a number
+    /// between 0 and 1) are treated as 1.
+    pub fn with_weight(&self, weight: &str) -> MediaType {
         MediaType {
             main: self.main.clone(),
             sub: self.sub.clone(),
-            weight: weight.parse().unwrap_or(1.0)
+            weight: weight.parse().ok().filter(|weight| (0.0..=1.0).contains(weight)).unwrap_or(1.0)
         }
     }
 
@@ -83,9 +91,28 @@ impl MediaType {
         }
     }
 
+    /// Returns the structured syntax suffix of the sub-type (i.e. `json` for `application/ld+json`),
+    /// if there is one
+    pub fn suffix(&self) -> Option<&str> {
+        self.sub.rsplit_once('+').map(|(_, suffix)| suffix).filter(|suffix| !suffix.is_empty())
+    }
+
+    /// If this media type matches the other media type, also matching the structured syntax
+    /// suffix of this media type against the other sub-type (i.e. `application/ld+json` will
+    /// match `application/json` with `MediaTypeMatch::Suffix`)
+    pub fn matches_with_suffix(&self, other: &MediaType) -> MediaTypeMatch {
+        match self.matches(other) {
+            MediaTypeMatch::None if self.main == other.main && self.suffix() == Some(other.sub.as_str()) =>
+              MediaTypeMatch::Suffix,
+            media_type_match => media_type_match
+        }
+    }
+}
+
+impl Display for MediaType {
     /// Converts this media type into a string
-    pub fn to_string(&self) -> String {
-        format!("{}/{}", self.main, self.sub)
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.main, self.sub)
     }
 }
 
@@ -100,36 +127,80 @@ impl HeaderValue {
   }
 }
 
-/// Sorts the list of media types by their weights
-pub fn sort_media_types(media_types: &Vec<HeaderValue>) -> Vec<HeaderValue> {
-  media_types.into_iter().cloned().sorted_by(|a, b| {
+/// Sorts the list of media types by their weights. Media types with the same quality weight are
+/// sorted with the most specific first (i.e. `text/html;level=1`, then `text/html`, then `text/*`
+/// and lastly `*/*`). Otherwise, the original order is kept.
+pub fn sort_media_types(media_types: &[HeaderValue]) -> Vec<HeaderValue> {
+  media_types.iter().cloned().sorted_by(|a, b| {
     let media_a = a.as_media_type().weight();
     let media_b = b.as_media_type().weight();
-    let order = media_a.0.partial_cmp(&media_b.0).unwrap_or(Ordering::Greater);
-    if order == Ordering::Equal {
-      Ord::cmp(&media_a.1, &media_b.1)
-    } else {
-      order.reverse()
-    }
+    media_b.0.total_cmp(&media_a.0)
+      .then_with(|| Ord::cmp(&media_a.1, &media_b.1))
+      .then_with(|| Ord::cmp(&media_type_params(b), &media_type_params(a)))
   }).collect()
 }
 
+/// Number of media type parameters, excluding the quality weight
+fn media_type_params(media_type: &HeaderValue) -> usize {
+  media_type.params.keys().filter(|key| !key.eq_ignore_ascii_case("q")).count()
+}
+
+/// If the media type has been excluded by the client. This is the case if the most specific
+/// acceptable media type that matches it has a quality weight of 0 (i.e. `application/json;q=0`).
+fn media_type_excluded(
+  media_type: &MediaType,
+  acceptable_media_types: &[HeaderValue],
+  match_suffix: bool
+) -> bool {
+  acceptable_media_types.iter()
+    .map(|acceptable| acceptable.as_media_type())
+    .map(|acceptable| (media_type_match(media_type, &acceptable, match_suffix), acceptable))
+    .filter(|(media_type_match, _)| *media_type_match != MediaTypeMatch::None)
+    .min_by(|a, b| Ord::cmp(&a.0, &b.0))
+    .map(|(_, acceptable)| acceptable.weight <= 0.0)
+    .unwrap_or(false)
+}
+
+fn media_type_match(media_type: &MediaType, other: &MediaType, match_suffix: bool) -> MediaTypeMatch {
+  if match_suffix {
+    media_type.matches_with_suffix(other)
+  } else {
+    media_type.matches(other)
+  }
+}
+
 /// Determines if the media types produced by the resource matches the acceptable media types
-/// provided by the client. Returns the match if there is one.
+/// provided by the client. Returns the match if there is one. If the resource has
+/// `media_type_suffix_matching` set, produced media types with a structured syntax suffix will
+/// also match (i.e. `application/ld+json` will match `application/json`).
 pub fn matching_content_type(resource: &WebmachineResource, request: &WebmachineRequest) -> Option<String> {
+  negotiate_content_type(resource, parse_accept(request).as_deref())
+}
+
+fn parse_accept(request: &WebmachineRequest) -> Option<Vec<HeaderValue>> {
   if request.has_accept_header() {
-    let acceptable_media_types = sort_media_types(&request.accept());
+    Some(sort_media_types(&request.accept()))
+  } else {
+    None
+  }
+}
+
+fn negotiate_content_type(resource: &WebmachineResource, acceptable_media_types: Option<&[HeaderValue]>) -> Option<String> {
+  if let Some(acceptable_media_types) = acceptable_media_types {
+    let match_suffix = resource.media_type_suffix_matching;
     resource.produces.iter()
-      .cloned()
+      .map(|produced| MediaType::parse_string(produced))
+      .filter(|produced| !media_type_excluded(produced, acceptable_media_types, match_suffix))
       .cartesian_product(acceptable_media_types.iter())
-      .map(|(produced, acceptable)| {
+      .map(|(produced_media_type, acceptable)| {
         let acceptable_media_type = acceptable.as_media_type();
-        let produced_media_type =  MediaType::parse_string(produced);
-        (produced_media_type.clone(), acceptable_media_type.clone(), produced_media_type.matches(&acceptable_media_type))
+        let media_type_match = media_type_match(&produced_media_type, &acceptable_media_type, match_suffix);
+        (produced_media_type, acceptable_media_type, media_type_match)
       })
+      .filter(|val| val.1.weight > 0.0)
       .sorted_by(|a, b| Ord::cmp(&a.2, &b.2))
-      .filter(|val| val.2 != MediaTypeMatch::None)
-      .next().map(|result| result.0.to_string())
+      .find(|val| val.2 != MediaTypeMatch::None)
+      .map(|result| result.0.to_string())
   } else {
     resource.produces.first().map(|s| s.to_string())
   }
@@ -165,12 +236,13 @@ impl MediaLanguage {
     }
   }
 
-  /// Adds a quality weight to the media language
+  /// Adds a quality weight to the media language. Weights that are not valid (i.e. not a number
+  /// between 0 and 1) are treated as 1.
   pub fn with_weight(&self, weight: &str) -> MediaLanguage {
     MediaLanguage {
       main: self.main.clone(),
       sub: self.sub.clone(),
-      weight: weight.parse().unwrap_or(1.0)
+      weight: weight.parse().ok().filter(|weight| (0.0..=1.0).contains(weight)).unwrap_or(1.0)
     }
   }
 
@@ -179,17 +251,19 @@ impl MediaLanguage {
     if other.main == "*" || (self.main == other.main && self.sub == other.sub) {
       true
     } else {
-      let check = format!("{}-", self.to_string());
+      let check = format!("{}-", self);
       other.to_string().starts_with(&check)
     }
   }
+}
 
+impl Display for MediaLanguage {
   /// Converts this media language into a string
-  pub fn to_string(&self) -> String {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     if self.sub.is_empty() {
-      self.main.clone()
+      write!(f, "{}", self.main)
     } else {
-      format!("{}-{}", self.main, self.sub)
+      write!(f, "{}-{}", self.main, self.sub)
     }
   }
 }
@@ -206,9 +280,8 @@ impl HeaderValue {
 }
 
 /// Sorts the list of media types by weighting
-pub fn sort_media_languages(media_languages: &Vec<HeaderValue>) -> Vec<MediaLanguage> {
+pub fn sort_media_languages(media_languages: &[HeaderValue]) -> Vec<MediaLanguage> {
     media_languages.iter()
-        .cloned()
         .map(|lang| lang.as_media_language())
         .filter(|lang| lang.weight > 0.0)
         .sorted_by(|a, b| {
@@ -222,8 +295,19 @@ pub fn sort_media_languages(media_languages: &Vec<HeaderValue>) -> Vec<MediaLang
 /// Determines if the languages produced by the resource matches the acceptable languages
 /// provided by the client. Returns the match if there is one.
 pub fn matching_language(resource: &WebmachineResource, request: &WebmachineRequest) -> Option<String> {
+  negotiate_language(resource, parse_accept_language(request).as_deref())
+}
+
+fn parse_accept_language(request: &WebmachineRequest) -> Option<Vec<MediaLanguage>> {
   if request.has_accept_language_header() && !request.accept_language().is_empty() {
-    let acceptable_languages = sort_media_languages(&request.accept_language());
+    Some(sort_media_languages(&request.accept_language()))
+  } else {
+    None
+  }
+}
+
+fn negotiate_language(resource: &WebmachineResource, acceptable_languages: Option<&[MediaLanguage]>) -> Option<String> {
+  if let Some(acceptable_languages) = acceptable_languages {
     if resource.languages_provided.is_empty() {
       acceptable_languages.first().map(|lang| lang.to_string())
     } else {
@@ -231,7 +315,7 @@ pub fn matching_language(resource: &WebmachineResource, request: &WebmachineRequ
         .cartesian_product(resource.languages_provided.iter())
         .map(|(acceptable_language, produced_language)| {
           let produced_language = MediaLanguage::parse_string(produced_language);
-          (produced_language.clone(), produced_language.matches(&acceptable_language))
+          (produced_language.clone(), produced_language.matches(acceptable_language))
         })
         .find(|val| val.1)
         .map(|result| result.0.to_string())
@@ -261,11 +345,12 @@ impl Charset {
     }
   }
 
-  /// Adds a quality weight to the charset
+  /// Adds a quality weight to the charset. Weights that are not valid (i.e. not a number
+  /// between 0 and 1) are treated as 1.
   pub fn with_weight(&self, weight: &str) -> Charset {
     Charset {
       charset: self.charset.clone(),
-      weight: weight.parse().unwrap_or(1.0)
+      weight: weight.parse().ok().filter(|weight| (0.0..=1.0).contains(weight)).unwrap_or(1.0)
     }
   }
 
@@ -273,10 +358,12 @@ impl Charset {
   pub fn matches(&self, other: &Charset) -> bool {
     other.charset == "*" || (self.charset.to_uppercase() == other.charset.to_uppercase())
   }
+}
 
+impl Display for Charset {
   /// Converts this charset into a string
-  pub fn to_string(&self) -> String {
-      self.charset.clone()
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.charset)
   }
 }
 
@@ -293,8 +380,8 @@ impl HeaderValue {
 
 /// Sorts the list of charsets by weighting as per https://tools.ietf.org/html/rfc2616#section-14.2.
 /// Note that ISO-8859-1 is added as a default with a weighting of 1 if not all ready supplied.
-pub fn sort_media_charsets(charsets: &Vec<HeaderValue>) -> Vec<Charset> {
-    let mut charsets = charsets.clone();
+pub fn sort_media_charsets(charsets: &[HeaderValue]) -> Vec<Charset> {
+    let mut charsets = charsets.to_vec();
     if charsets.iter().find(|cs| cs.value == "*" || cs.value.to_uppercase() == "ISO-8859-1").is_none() {
         charsets.push(h!("ISO-8859-1"));
     }
@@ -312,8 +399,19 @@ pub fn sort_media_charsets(charsets: &Vec<HeaderValue>) -> Vec<Charset> {
 /// Determines if the charsets produced by the resource matches the acceptable charsets
 /// provided by the client. Returns the match if there is one.
 pub fn matching_charset(resource: &WebmachineResource, request: &WebmachineRequest) -> Option<String> {
+  negotiate_charset(resource, parse_accept_charset(request).as_deref())
+}
+
+fn parse_accept_charset(request: &WebmachineRequest) -> Option<Vec<Charset>> {
   if request.has_accept_charset_header() && !request.accept_charset().is_empty() {
-    let acceptable_charsets = sort_media_charsets(&request.accept_charset());
+    Some(sort_media_charsets(&request.accept_charset()))
+  } else {
+    None
+  }
+}
+
+fn negotiate_charset(resource: &WebmachineResource, acceptable_charsets: Option<&[Charset]>) -> Option<String> {
+  if let Some(acceptable_charsets) = acceptable_charsets {
     if resource.charsets_provided.is_empty() {
       acceptable_charsets.first().map(|cs| cs.to_string())
     } else {
@@ -321,7 +419,7 @@ pub fn matching_charset(resource: &WebmachineResource, request: &WebmachineReque
         .cartesian_product(resource.charsets_provided.iter())
         .map(|(acceptable_charset, provided_charset)| {
             let provided_charset = Charset::parse_string(provided_charset);
-            (provided_charset.clone(), provided_charset.matches(&acceptable_charset))
+            (provided_charset.clone(), provided_charset.matches(acceptable_charset))
         })
         .find(|val| val.1)
         .map(|result| result.0.to_string())
@@ -351,11 +449,12 @@ impl Encoding {
     }
   }
 
-  /// Adds a quality weight to the charset
+  /// Adds a quality weight to the encoding. Weights that are not valid (i.e. not a number
+  /// between 0 and 1) are treated as 1.
   pub fn with_weight(&self, weight: &str) -> Encoding {
     Encoding {
       encoding: self.encoding.to_string(),
-      weight: weight.parse().unwrap_or(1.0)
+      weight: weight.parse().ok().filter(|weight| (0.0..=1.0).contains(weight)).unwrap_or(1.0)
     }
   }
 
@@ -363,11 +462,13 @@ impl Encoding {
   pub fn matches(&self, other: &Encoding) -> bool {
     other.encoding == "*" || (self.encoding.to_lowercase() == other.encoding.to_lowercase())
   }
+}
 
+impl Display for Encoding {
   /// Converts this encoding into a string
-  pub fn to_string(&self) -> String {
-        self.encoding.clone()
-    }
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.encoding)
+  }
 }
 
 impl HeaderValue {
@@ -383,8 +484,8 @@ impl HeaderValue {
 
 /// Sorts the list of encodings by weighting as per https://tools.ietf.org/html/rfc2616#section-14.3.
 /// Note that identity encoding is awlays added with a weight of 1 if not already present.
-pub fn sort_encodings(encodings: &Vec<HeaderValue>) -> Vec<Encoding> {
-    let mut encodings = encodings.clone();
+pub fn sort_encodings(encodings: &[HeaderValue]) -> Vec<Encoding> {
+    let mut encodings = encodings.to_vec();
     if encodings.iter().find(|e| e.value == "*" || e.value.to_lowercase() == "identity").is_none() {
         encodings.push(h!("identity"));
     }
@@ -402,9 +503,20 @@ pub fn sort_encodings(encodings: &Vec<HeaderValue>) -> Vec<Encoding> {
 /// Determines if the encodings supported by the resource matches the acceptable encodings
 /// provided by the client. Returns the match if there is one.
 pub fn matching_encoding(resource: &WebmachineResource, request: &WebmachineRequest) -> Option<String> {
-  let identity = Encoding::parse_string("identity");
+  negotiate_encoding(resource, parse_accept_encoding(request).as_deref())
+}
+
+fn parse_accept_encoding(request: &WebmachineRequest) -> Option<Vec<Encoding>> {
   if request.has_accept_encoding_header() {
-    let acceptable_encodings = sort_encodings(&request.accept_encoding());
+    Some(sort_encodings(&request.accept_encoding()))
+  } else {
+    None
+  }
+}
+
+fn negotiate_encoding(resource: &WebmachineResource, acceptable_encodings: Option<&[Encoding]>) -> Option<String> {
+  let identity = Encoding::parse_string("identity");
+  if let Some(acceptable_encodings) = acceptable_encodings {
     if resource.encodings_provided.is_empty() {
       if acceptable_encodings.contains(&identity) {
         Some("identity".to_string())
@@ -416,7 +528,7 @@ pub fn matching_encoding(resource: &WebmachineResource, request: &WebmachineRequ
         .cartesian_product(resource.encodings_provided.iter())
         .map(|(acceptable_encoding, provided_encoding)| {
           let provided_encoding = Encoding::parse_string(provided_encoding);
-          (provided_encoding.clone(), provided_encoding.matches(&acceptable_encoding))
+          (provided_encoding.clone(), provided_encoding.matches(acceptable_encoding))
         })
         .find(|val| val.1)
         .map(|result| { result.0.to_string() })
@@ -427,3 +539,50 @@ pub fn matching_encoding(resource: &WebmachineResource, request: &WebmachineRequ
     resource.encodings_provided.first().map(|s| s.to_string())
   }
 }
+
+/// The acceptable media types, languages, charsets and encodings from the Accept headers of a
+/// request, parsed and sorted once so they can be used for all the content negotiation decisions.
+/// Each value is None if the request does not have the header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcceptHeaders {
+  /// Acceptable media types from the Accept header, sorted with `sort_media_types`
+  pub media_types: Option<Vec<HeaderValue>>,
+  /// Acceptable languages from the Accept-Language header, sorted with `sort_media_languages`
+  pub languages: Option<Vec<MediaLanguage>>,
+  /// Acceptable charsets from the Accept-Charset header, sorted with `sort_media_charsets`
+  pub charsets: Option<Vec<Charset>>,
+  /// Acceptable encodings from the Accept-Encoding header, sorted with `sort_encodings`
+  pub encodings: Option<Vec<Encoding>>
+}
+
+impl AcceptHeaders {
+  /// Parses the Accept headers of the request
+  pub fn parse(request: &WebmachineRequest) -> AcceptHeaders {
+    AcceptHeaders {
+      media_types: parse_accept(request),
+      languages: parse_accept_language(request),
+      charsets: parse_accept_charset(request),
+      encodings: parse_accept_encoding(request)
+    }
+  }
+
+  /// Same as `matching_content_type`, using the parsed Accept header
+  pub fn matching_content_type(&self, resource: &WebmachineResource) -> Option<String> {
+    negotiate_content_type(resource, self.media_types.as_deref())
+  }
+
+  /// Same as `matching_language`, using the parsed Accept-Language header
+  pub fn matching_language(&self, resource: &WebmachineResource) -> Option<String> {
+    negotiate_language(resource, self.languages.as_deref())
+  }
+
+  /// Same as `matching_charset`, using the parsed Accept-Charset header
+  pub fn matching_charset(&self, resource: &WebmachineResource) -> Option<String> {
+    negotiate_charset(resource, self.charsets.as_deref())
+  }
+
+  /// Same as `matching_encoding`, using the parsed Accept-Encoding header
+  pub fn matching_encoding(&self, resource: &WebmachineResource) -> Option<String> {
+    negotiate_encoding(resource, self.encodings.as_deref())
+  }
+}
@@ -109,25 +109,174 @@ fn matches_most_specific() {
   expect!(matching_content_type(&resource5, &request)).to(be_some().value("application/pdf"));
 }
 
+#[test]
+fn does_not_match_media_type_excluded_with_zero_quality_even_if_wild_card_present() {
+  let resource = WebmachineResource {
+    produces: vec!["application/json"],
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json;q=0"), h!("*/*")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_none());
+}
+
+#[test]
+fn matches_other_media_type_if_one_is_excluded_with_zero_quality() {
+  let resource = WebmachineResource {
+    produces: vec!["application/json", "text/plain"],
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json;q=0"), h!("*/*")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("text/plain"));
+}
+
+#[test]
+fn does_not_match_json_ld_for_json_by_default() {
+  let resource = WebmachineResource {
+    produces: vec!["application/ld+json"],
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_none());
+}
+
+#[test]
+fn matches_json_ld_for_json_if_suffix_matching_is_enabled() {
+  let resource = WebmachineResource {
+    produces: vec!["application/ld+json"],
+    media_type_suffix_matching: true,
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/ld+json"));
+
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json;q=0")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_none());
+}
+
+#[test]
+fn matches_vendor_specific_suffix_media_types_if_suffix_matching_is_enabled() {
+  let resource = WebmachineResource {
+    produces: vec!["application/vnd.api+json", "application/hal+xml"],
+    media_type_suffix_matching: true,
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/vnd.api+json"));
+
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/xml")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/hal+xml"));
+
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json"), h!("application/hal+xml")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/hal+xml"));
+
+  let resource = WebmachineResource {
+    produces: vec!["application/vnd.api+json"],
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_none());
+}
+
+#[test]
+fn prefers_an_exact_match_over_a_suffix_match() {
+  let resource = WebmachineResource {
+    produces: vec!["application/ld+json", "application/json"],
+    media_type_suffix_matching: true,
+    ..WebmachineResource::default()
+  };
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/json"));
+
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => vec![h!("application/ld+json")]
+    },
+    ..WebmachineRequest::default()
+  };
+  expect!(matching_content_type(&resource, &request)).to(be_some().value("application/ld+json"));
+}
+
 #[test]
 fn sort_media_types_basic_test() {
-  expect!(sort_media_types(&vec![h!("text/plain")])).to(be_equal_to(vec![h!("text/plain")]));
-  expect!(sort_media_types(&vec![h!("text/plain"), h!("text/html")])).to(be_equal_to(vec![h!("text/plain"), h!("text/html")]));
-  expect!(sort_media_types(&vec![h!("text/*"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/*")]));
-  expect!(sort_media_types(&vec![h!("*/*"), h!("text/*"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/*"), h!("*/*")]));
+  expect!(sort_media_types(&[h!("text/plain")])).to(be_equal_to(vec![h!("text/plain")]));
+  expect!(sort_media_types(&[h!("text/plain"), h!("text/html")])).to(be_equal_to(vec![h!("text/plain"), h!("text/html")]));
+  expect!(sort_media_types(&[h!("text/*"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/*")]));
+  expect!(sort_media_types(&[h!("*/*"), h!("text/*"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/*"), h!("*/*")]));
 }
 
 #[test]
 fn sort_media_types_with_quality_weighting() {
-  expect!(sort_media_types(&vec![h!("text/plain;q=0.2")])).to(be_equal_to(vec![h!("text/plain;q=0.2")]));
-  expect!(sort_media_types(&vec![h!("text/plain;q=0.2"), h!("text/html;q=0.3")])).to(be_equal_to(vec![h!("text/html;q=0.3"), h!("text/plain;q=0.2")]));
-  expect!(sort_media_types(&vec![h!("text/plain;q=0.2"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/plain;q=0.2")]));
-  expect!(sort_media_types(&vec![h!("audio/*; q=0.2"), h!("audio/basic")])).to(be_equal_to(vec![h!("audio/basic"), h!("audio/*;q=0.2")]));
-  expect!(sort_media_types(&vec![h!("audio/*;q=1"), h!("audio/basic;q=0.5")])).to(be_equal_to(vec![h!("audio/*;q=1"), h!("audio/basic;q=0.5")]));
-  expect!(sort_media_types(&vec![h!("text/plain; q=0.5"), h!("text/html"), h!("text/x-dvi; q=0.8"), h!("text/x-c")]))
+  expect!(sort_media_types(&[h!("text/plain;q=0.2")])).to(be_equal_to(vec![h!("text/plain;q=0.2")]));
+  expect!(sort_media_types(&[h!("text/plain;q=0.2"), h!("text/html;q=0.3")])).to(be_equal_to(vec![h!("text/html;q=0.3"), h!("text/plain;q=0.2")]));
+  expect!(sort_media_types(&[h!("text/plain;q=0.2"), h!("text/html")])).to(be_equal_to(vec![h!("text/html"), h!("text/plain;q=0.2")]));
+  expect!(sort_media_types(&[h!("audio/*; q=0.2"), h!("audio/basic")])).to(be_equal_to(vec![h!("audio/basic"), h!("audio/*;q=0.2")]));
+  expect!(sort_media_types(&[h!("audio/*;q=1"), h!("audio/basic;q=0.5")])).to(be_equal_to(vec![h!("audio/*;q=1"), h!("audio/basic;q=0.5")]));
+  expect!(sort_media_types(&[h!("text/plain; q=0.5"), h!("text/html"), h!("text/x-dvi; q=0.8"), h!("text/x-c")]))
     .to(be_equal_to(vec![h!("text/html"), h!("text/x-c"), h!("text/x-dvi;q=0.8"), h!("text/plain;q=0.5")]));
 }
 
+#[test]
+fn sort_media_types_with_equal_weights_sorts_the_most_specific_first() {
+  expect!(sort_media_types(&[h!("text/*"), h!("text/html"), h!("*/*")]))
+    .to(be_equal_to(vec![h!("text/html"), h!("text/*"), h!("*/*")]));
+  expect!(sort_media_types(&[h!("*/*;q=0.5"), h!("text/*;q=0.5"), h!("text/html;q=0.5")]))
+    .to(be_equal_to(vec![h!("text/html;q=0.5"), h!("text/*;q=0.5"), h!("*/*;q=0.5")]));
+  expect!(sort_media_types(&[h!("text/html"), h!("text/html;level=1")]))
+    .to(be_equal_to(vec![h!("text/html;level=1"), h!("text/html")]));
+  expect!(sort_media_types(&[h!("*/*"), h!("text/html;q=NaN")]))
+    .to(be_equal_to(vec![h!("text/html;q=NaN"), h!("*/*")]));
+}
+
 #[test]
 fn parse_media_type_test() {
   expect!(MediaType::parse_string("text/plain")).to(be_equal_to(MediaType { main: "text".to_string(), sub: "plain".to_string(), weight: 1.0 }));
@@ -147,6 +296,17 @@ fn media_type_matches_test() {
   expect!(media_type.matches(&MediaType { main: "application".to_string(), sub: "application".to_string(), weight: 1.0 })).to(be_equal_to(MediaTypeMatch::None));
 }
 
+#[test]
+fn media_type_matches_with_suffix_test() {
+  let media_type = MediaType::parse_string("application/ld+json");
+  expect!(media_type.suffix()).to(be_some().value("json"));
+  expect!(MediaType::parse_string("application/json").suffix()).to(be_none());
+  expect!(media_type.matches(&MediaType::parse_string("application/json"))).to(be_equal_to(MediaTypeMatch::None));
+  expect!(media_type.matches_with_suffix(&MediaType::parse_string("application/json"))).to(be_equal_to(MediaTypeMatch::Suffix));
+  expect!(media_type.matches_with_suffix(&MediaType::parse_string("application/ld+json"))).to(be_equal_to(MediaTypeMatch::Full));
+  expect!(media_type.matches_with_suffix(&MediaType::parse_string("text/json"))).to(be_equal_to(MediaTypeMatch::None));
+}
+
 #[test]
 fn matching_language_matches_if_no_accept_header_is_provided() {
   let resource = WebmachineResource {
@@ -439,15 +599,15 @@ fn matches_most_specific_charset() {
 
 #[test]
 fn sort_charsets_with_quality_weighting() {
-  expect!(sort_media_charsets(&vec![h!("iso-8859-5")]))
+  expect!(sort_media_charsets(&[h!("iso-8859-5")]))
     .to(be_equal_to(vec![Charset::parse_string("iso-8859-5"), Charset::parse_string("ISO-8859-1")]));
-  expect!(sort_media_charsets(&vec![h!("unicode-1-1;q=0.8"), h!("iso-8859-5")]))
+  expect!(sort_media_charsets(&[h!("unicode-1-1;q=0.8"), h!("iso-8859-5")]))
     .to(be_equal_to(vec![Charset::parse_string("iso-8859-5"),
       Charset::parse_string("ISO-8859-1"), Charset::parse_string("unicode-1-1").with_weight("0.8")]));
-  expect!(sort_media_charsets(&vec![h!("US-ASCII;q=0.8"), h!("*;q=0.5")]))
+  expect!(sort_media_charsets(&[h!("US-ASCII;q=0.8"), h!("*;q=0.5")]))
     .to(be_equal_to(vec![Charset::parse_string("US-ASCII").with_weight("0.8"),
       Charset::parse_string("*").with_weight("0.5")]));
-  expect!(sort_media_charsets(&vec![h!("iso-8859-1; q=0.2"), h!("iso-8859-5")]))
+  expect!(sort_media_charsets(&[h!("iso-8859-1; q=0.2"), h!("iso-8859-5")]))
     .to(be_equal_to(vec![Charset::parse_string("iso-8859-5"),
       Charset::parse_string("iso-8859-1").with_weight("0.2")]));
 }
@@ -638,19 +798,27 @@ fn matches_most_specific_encoding() {
 
 #[test]
 fn sort_encodings_with_quality_weighting() {
-  expect!(sort_encodings(&vec![h!("gzip")]))
+  expect!(sort_encodings(&[h!("gzip")]))
     .to(be_equal_to(vec![Encoding::parse_string("gzip"), Encoding::parse_string("identity")]));
-  expect!(sort_encodings(&vec![h!("gzip;q=0.8"), h!("compress")]))
+  expect!(sort_encodings(&[h!("gzip;q=0.8"), h!("compress")]))
     .to(be_equal_to(vec![Encoding::parse_string("compress"),
                          Encoding::parse_string("identity"),
                          Encoding::parse_string("gzip").with_weight("0.8")]));
-  expect!(sort_encodings(&vec![h!("gzip;q=0.8"), h!("*;q=0.5")]))
+  expect!(sort_encodings(&[h!("gzip;q=0.8"), h!("*;q=0.5")]))
     .to(be_equal_to(vec![Encoding::parse_string("gzip").with_weight("0.8"),
                          Encoding::parse_string("*").with_weight("0.5")]));
-  expect!(sort_encodings(&vec![h!("gzip; q=0.2"), h!("compress;q=0"), h!("*;q=0")]))
+  expect!(sort_encodings(&[h!("gzip; q=0.2"), h!("compress;q=0"), h!("*;q=0")]))
     .to(be_equal_to(vec![Encoding::parse_string("gzip").with_weight("0.2")]));
 }
 
+#[test]
+fn invalid_quality_weights_are_treated_as_one() {
+  expect!(MediaLanguage::parse_string("en").with_weight("2").weight).to(be_equal_to(1.0));
+  expect!(Charset::parse_string("UTF-8").with_weight("-1").weight).to(be_equal_to(1.0));
+  expect!(Encoding::parse_string("gzip").with_weight("high").weight).to(be_equal_to(1.0));
+  expect!(Encoding::parse_string("gzip").with_weight("0").weight).to(be_equal_to(0.0));
+}
+
 #[test]
 fn encoding_matches_test() {
   expect!(Encoding::parse_string("identity").matches(&Encoding::parse_string("identity"))).to(be_true());
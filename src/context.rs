@@ -1,13 +1,22 @@
 //! The `context` module encapsulates the context of the environment that the webmachine is
 //! executing in. Basically wraps the request and response.
 
+use std::any::{Any, TypeId};
 use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
-use chrono::{DateTime, FixedOffset};
-use maplit::hashmap;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use futures::Stream;
+use hyper::body::Bytes;
+use maplit::{btreemap, hashmap};
 use itertools::Itertools;
 
-use crate::headers::HeaderValue;
+use crate::content_negotiation::AcceptHeaders;
+use crate::headers::{format_http_date, HeaderValue};
+use crate::range::{parse_content_range_header, ContentRange};
 
 /// Request that the state machine is executing against
 #[derive(Debug, Clone, PartialEq)]
@@ -23,7 +32,9 @@ pub struct WebmachineRequest {
   /// Request body
   pub body: Option<Vec<u8>>,
   /// Query parameters
-  pub query: HashMap<String, Vec<String>>
+  pub query: HashMap<String, Vec<String>>,
+  /// HTTP version of the request (i.e. HTTP/1.1)
+  pub http_version: String
 }
 
 impl Default for WebmachineRequest {
@@ -35,7 +46,8 @@ impl Default for WebmachineRequest {
       method: "GET".to_string(),
       headers: HashMap::new(),
       body: None,
-      query: HashMap::new()
+      query: HashMap::new(),
+      http_version: "HTTP/1.1".to_string()
     }
   }
 }
@@ -53,16 +65,67 @@ impl WebmachineRequest {
       }
     }
 
+    /// Returns the parameters of the request content type (i.e. the `charset` or multipart
+    /// `boundary`), with the parameter names in lower case. Returns an empty map if there is no
+    /// Content-Type header.
+    pub fn content_type_params(&self) -> HashMap<String, String> {
+      match self.find_header("CONTENT-TYPE").first() {
+        Some(value) => value.params.iter()
+          .map(|(name, value)| (name.to_lowercase(), value.clone()))
+          .collect(),
+        None => HashMap::new()
+      }
+    }
+
+    /// If the content type of the request is JSON (`application/json` or a type with a `+json` suffix)
+    pub fn is_json_content(&self) -> bool {
+      let content_type = self.content_type().to_lowercase();
+      content_type == "application/json" || content_type.ends_with("+json")
+    }
+
+    /// Deserialises the JSON request body. Returns an error if the request does not have a JSON
+    /// content type, or the body is not valid JSON.
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+      if self.is_json_content() {
+        serde_json::from_slice(self.body.as_deref().unwrap_or_default())
+      } else {
+        Err(serde::de::Error::custom(format!("Expected a JSON request body, but the content type is '{}'",
+          self.content_type())))
+      }
+    }
+
+    /// Parses an `application/x-www-form-urlencoded` request body into a map of names and values.
+    /// Returns an error if the request does not have a form content type.
+    pub fn form_body(&self) -> Result<HashMap<String, Vec<String>>, String> {
+      if self.content_type().eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        let body = self.body.as_ref().map(|body| String::from_utf8_lossy(body).to_string()).unwrap_or_default();
+        Ok(crate::parse_query(&body))
+      } else {
+        Err(format!("Expected a form request body, but the content type is '{}'", self.content_type()))
+      }
+    }
+
     /// If the request is a put or post
     pub fn is_put_or_post(&self) -> bool {
         ["PUT", "POST"].contains(&self.method.to_uppercase().as_str())
     }
 
+    /// If the request is a method that carries a request body (PUT, POST or PATCH)
+    pub fn has_body_method(&self) -> bool {
+        ["PUT", "POST", "PATCH"].contains(&self.method.to_uppercase().as_str())
+    }
+
     /// If the request is a get or head request
     pub fn is_get_or_head(&self) -> bool {
         ["GET", "HEAD"].contains(&self.method.to_uppercase().as_str())
     }
 
+    /// If the request is a head
+    pub fn is_head(&self) -> bool {
+        self.method.to_uppercase() == "HEAD"
+    }
+
     /// If the request is a get
     pub fn is_get(&self) -> bool {
         self.method.to_uppercase() == "GET"
@@ -78,6 +141,11 @@ impl WebmachineRequest {
         self.method.to_uppercase() == "PUT"
     }
 
+    /// If the request is a patch
+    pub fn is_patch(&self) -> bool {
+        self.method.to_uppercase() == "PATCH"
+    }
+
     /// If the request is a post
     pub fn is_post(&self) -> bool {
         self.method.to_uppercase() == "POST"
@@ -108,6 +176,28 @@ impl WebmachineRequest {
         self.find_header("ACCEPT-LANGUAGE")
     }
 
+    /// Returns the cookies sent with the request in the `Cookie` header(s), as a map of cookie
+    /// names to values. If a cookie is repeated (in the same header or in different headers), the
+    /// first value is used. The header is split on `;` and each pair on the first `=`, so values
+    /// containing `=` (i.e. base64) are returned as sent, with only surrounding quotes removed.
+    /// The dispatcher does not parse the `Cookie` header into parameters, so that no values are
+    /// lost or altered.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        for header in self.find_header("COOKIE") {
+            for pair in header.to_string().split(';') {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let name = name.trim();
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                if !name.is_empty() {
+                    cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+                }
+            }
+        }
+        cookies
+    }
+
     /// If an Accept-Charset header exists
     pub fn has_accept_charset_header(&self) -> bool {
         self.has_header("ACCEPT-CHARSET")
@@ -128,6 +218,92 @@ impl WebmachineRequest {
         self.find_header("ACCEPT-ENCODING")
     }
 
+    /// Returns the values from the Connection header
+    pub fn connection(&self) -> Vec<HeaderValue> {
+        self.find_header("CONNECTION")
+    }
+
+    /// If the connection should be kept open after this request. HTTP/1.0 connections are only
+    /// kept alive if the client sends `Connection: keep-alive`, while later versions are kept
+    /// alive unless the client sends `Connection: close`.
+    pub fn is_keep_alive(&self) -> bool {
+        let connection = self.connection();
+        let has_option = |option: &str| connection.iter()
+          .any(|value| value.value.eq_ignore_ascii_case(option));
+        if self.http_version == "HTTP/0.9" || self.http_version == "HTTP/1.0" {
+          has_option("keep-alive")
+        } else {
+          !has_option("close")
+        }
+    }
+
+    /// Returns the declared length of the request body from the Content-Length header. Returns
+    /// None if there is no Content-Length header, or if it is not a valid length.
+    pub fn content_length(&self) -> Option<u64> {
+        self.find_header("CONTENT-LENGTH").first()
+          .and_then(|value| value.value.trim().parse().ok())
+    }
+
+    /// Returns the parsed byte range from the Content-Range header, which is used with PUT
+    /// requests for partial updates. Returns None if there is no Content-Range header, or if
+    /// the header is malformed.
+    pub fn content_range(&self) -> Option<ContentRange> {
+        let header = self.find_header("CONTENT-RANGE").iter()
+          .map(|value| value.value.clone())
+          .join(",");
+        parse_content_range_header(&header)
+    }
+
+    /// Returns the first value of the query parameter with the given name, if there is one
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).and_then(|values| values.first()).map(|value| value.as_str())
+    }
+
+    /// Returns all the values of the query parameter with the given name. An empty slice is
+    /// returned if there is no query parameter with the name.
+    pub fn query_params(&self, name: &str) -> &[String] {
+        self.query.get(name).map(|values| values.as_slice()).unwrap_or_default()
+    }
+
+    /// Returns all the values of a list query parameter, which can be given either as repeated keys
+    /// (`items=a&items=b`) or with bracket notation (`items[]=a&items[]=b`). A trailing `[]` is
+    /// removed from the key, and the values of both forms are returned, with the repeated key
+    /// values first. The values of a repeated key are always all returned, as the query parameters
+    /// keep every value in the order they appear in the query string.
+    pub fn query_array(&self, key: &str) -> Vec<String> {
+        let key = key.strip_suffix("[]").unwrap_or(key);
+        self.query_params(key).iter()
+          .chain(self.query_params(&format!("{}[]", key)))
+          .cloned()
+          .collect()
+    }
+
+    /// Returns the first value of the query parameter as an integer. Returns None if there is no
+    /// query parameter with the name, or the value is not a valid integer.
+    pub fn query_int(&self, key: &str) -> Option<i64> {
+        self.query_param(key).and_then(|value| value.trim().parse().ok())
+    }
+
+    /// Returns the segments of the request path, with any percent-encoded characters decoded. An
+    /// encoded slash (`%2F`) is decoded as part of the segment, and is not treated as a separator.
+    pub fn path_segments(&self) -> Vec<String> {
+        crate::decoded_path_segments(&self.request_path)
+    }
+
+    /// Returns the deadline for the request, based on either a `grpc-timeout` header (a timeout
+    /// relative to `received`, i.e. `100m` for 100 milliseconds) or an `X-Deadline` header (an
+    /// RFC 3339 date and time). Returns None if neither header is present or valid.
+    pub fn deadline_from_headers(&self, received: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let timeout = self.find_header("GRPC-TIMEOUT").first()
+          .and_then(|value| parse_grpc_timeout(&value.value));
+        match timeout {
+          Some(timeout) => Some(received + timeout),
+          None => self.find_header("X-DEADLINE").first()
+            .and_then(|value| DateTime::parse_from_rfc3339(value.value.trim()).ok())
+            .map(|deadline| deadline.with_timezone(&Utc))
+        }
+    }
+
     /// If the request has the provided header
     pub fn has_header(&self, header: &str) -> bool {
       self.headers.keys().find(|k| k.to_uppercase() == header.to_uppercase()).is_some()
@@ -142,18 +318,89 @@ impl WebmachineRequest {
         }
     }
 
+    /// Returns the first value of the provided request header (without any parameters). The
+    /// header name is case-insensitive. Returns None if the header is not present.
+    pub fn header_value(&self, header: &str) -> Option<String> {
+        self.find_header(header).first().map(|value| value.value.clone())
+    }
+
+    /// Returns all the values of the provided request header (including any parameters) joined
+    /// with `, `. The header name is case-insensitive. Returns None if the header is not present.
+    pub fn header_values_joined(&self, header: &str) -> Option<String> {
+        let values = self.find_header(header);
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().map(|value| value.to_string()).join(", "))
+        }
+    }
+
     /// If the header has a matching value
     pub fn has_header_value(&self, header: &str, value: &str) -> bool {
         match self.headers.keys().find(|k| k.to_uppercase() == header.to_uppercase()) {
-            Some(header) => match self.headers.get(header).unwrap().iter().find(|val| *val == value) {
-                Some(_) => true,
-                None => false
-            },
+            Some(header) => self.headers.get(header).unwrap().iter().find(|val| *val == value).is_some(),
             None => false
         }
     }
 }
 
+/// Parses a gRPC timeout value, which is up to 8 digits followed by a unit (H for hours,
+/// M for minutes, S for seconds, m for milliseconds, u for microseconds or n for nanoseconds)
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+  let value = value.trim();
+  let unit = value.chars().last()?;
+  let amount = &value[..value.len() - unit.len_utf8()];
+  if amount.is_empty() || amount.len() > 8 || !amount.chars().all(|ch| ch.is_ascii_digit()) {
+    return None;
+  }
+  let amount: i64 = amount.parse().ok()?;
+  match unit {
+    'H' => Some(Duration::hours(amount)),
+    'M' => Some(Duration::minutes(amount)),
+    'S' => Some(Duration::seconds(amount)),
+    'm' => Some(Duration::milliseconds(amount)),
+    'u' => Some(Duration::microseconds(amount)),
+    'n' => Some(Duration::nanoseconds(amount)),
+    _ => None
+  }
+}
+
+/// Type of a stream of chunks used for a streaming response body
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Streaming response body. The stream can only be consumed once, which will be when the HTTP
+/// response is generated.
+#[derive(Clone)]
+pub struct StreamingBody {
+  stream: Arc<Mutex<Option<BodyStream>>>
+}
+
+impl StreamingBody {
+  /// Wraps the stream as a streaming body
+  pub fn new(stream: BodyStream) -> StreamingBody {
+    StreamingBody {
+      stream: Arc::new(Mutex::new(Some(stream)))
+    }
+  }
+
+  /// Takes the stream out of the body. Returns None if it has already been taken.
+  pub fn take(&self) -> Option<BodyStream> {
+    self.stream.lock().unwrap().take()
+  }
+}
+
+impl Debug for StreamingBody {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "StreamingBody")
+  }
+}
+
+impl PartialEq for StreamingBody {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.stream, &other.stream)
+  }
+}
+
 /// Response that is generated as a result of the webmachine execution
 #[derive(Debug, Clone, PartialEq)]
 pub struct WebmachineResponse {
@@ -162,18 +409,45 @@ pub struct WebmachineResponse {
     /// headers to return
     pub headers: BTreeMap<String, Vec<HeaderValue>>,
     /// Response Body
-    pub body: Option<Vec<u8>>
+    pub body: Option<Vec<u8>>,
+    /// Streaming response body. If set, it will be used instead of `body`
+    pub stream: Option<StreamingBody>,
+    /// Custom reason phrase to return in the status line instead of the canonical one for the
+    /// status code (i.e. `422 Validation Failed`). This only applies to HTTP/1 connections.
+    pub reason: Option<String>
 }
 
-impl WebmachineResponse {
+impl Default for WebmachineResponse {
     /// Creates a default response (200 OK)
-    pub fn default() -> WebmachineResponse {
+    fn default() -> WebmachineResponse {
         WebmachineResponse {
             status: 200,
             headers: BTreeMap::new(),
-            body: None
+            body: None,
+            stream: None,
+            reason: None
         }
     }
+}
+
+impl WebmachineResponse {
+    /// Creates a response with the given status, and a body with the given content type. This is
+    /// useful for returning errors before a request reaches a resource.
+    pub fn error(status: u16, content_type: &str, body: &str) -> WebmachineResponse {
+      WebmachineResponse {
+        status,
+        headers: btreemap!{ "Content-Type".to_string() => vec![HeaderValue::parse_string(content_type)] },
+        body: Some(body.as_bytes().to_vec()),
+        stream: None,
+        reason: None
+      }
+    }
+
+    /// Creates a response with the given status and the JSON value as the body
+    #[cfg(feature = "serde")]
+    pub fn json(status: u16, value: &serde_json::Value) -> WebmachineResponse {
+      WebmachineResponse::error(status, "application/json", &value.to_string())
+    }
 
     /// If the response has the provided header
     pub fn has_header(&self, header: &str) -> bool {
@@ -185,6 +459,39 @@ impl WebmachineResponse {
       self.headers.insert(header.to_string(), values);
     }
 
+    /// Adds a `Set-Cookie` header to the response, with any attributes (i.e. `Path=/` or
+    /// `HttpOnly`). Each cookie is sent as a separate `Set-Cookie` header line.
+    pub fn add_cookie(&mut self, name: &str, value: &str, attributes: &[&str]) {
+      let cookie = std::iter::once(format!("{}={}", name, value))
+        .chain(attributes.iter().map(|attr| attr.to_string()))
+        .join("; ");
+      let key = self.headers.keys()
+        .find(|k| k.eq_ignore_ascii_case("Set-Cookie"))
+        .cloned()
+        .unwrap_or_else(|| "Set-Cookie".to_string());
+      self.headers.entry(key).or_default().push(HeaderValue::basic(cookie));
+    }
+
+    /// Sets the `Retry-After` header to the number of seconds the client should wait before
+    /// retrying the request (i.e. for a '503 Service Unavailable' or '429 Too Many Requests'
+    /// response).
+    pub fn set_retry_after(&mut self, seconds: u64) {
+      self.add_header("Retry-After", vec![HeaderValue::basic(seconds.to_string())]);
+    }
+
+    /// Sets the `Deprecation` header (RFC 9745) to indicate that the resource is, or will be,
+    /// deprecated from the given date. The date is formatted as a structured field date
+    /// (i.e. `@1688169599`).
+    pub fn set_deprecation(&mut self, date: &DateTime<FixedOffset>) {
+      self.add_header("Deprecation", vec![HeaderValue::basic(format!("@{}", date.timestamp()))]);
+    }
+
+    /// Sets the `Sunset` header (RFC 8594) to indicate the date the resource is expected to be
+    /// removed. The date is formatted as an HTTP date.
+    pub fn set_sunset(&mut self, date: &DateTime<FixedOffset>) {
+      self.add_header("Sunset", vec![HeaderValue::basic(format_http_date(date))]);
+    }
+
     /// Adds the headers from a HashMap to the headers
     pub fn add_headers(&mut self, headers: HashMap<String, Vec<String>>) {
       for (k, v) in headers {
@@ -192,11 +499,14 @@ impl WebmachineResponse {
       }
     }
 
-    /// Adds standard CORS headers to the response
+    /// Adds standard CORS headers to the response. Any CORS headers already set on the response
+    /// (i.e. by the `options` callback) are not replaced.
     pub fn add_cors_headers(&mut self, allowed_methods: &Vec<&str>) {
       let cors_headers = WebmachineResponse::cors_headers(allowed_methods);
       for (k, v) in cors_headers {
-        self.add_header(k.as_str(), v.iter().map(HeaderValue::basic).collect());
+        if !self.has_header(&k) {
+          self.add_header(k.as_str(), v.iter().map(HeaderValue::basic).collect());
+        }
       }
     }
 
@@ -209,15 +519,71 @@ impl WebmachineResponse {
       }
     }
 
-    /// If the response has a body
+    /// If the response has a body. An empty body is treated as no body.
     pub fn has_body(&self) -> bool {
         match &self.body {
-            &None => false,
-            &Some(ref body) => !body.is_empty()
+            None => false,
+            Some(body) => !body.is_empty()
         }
     }
 }
 
+/// The representation that was selected by content negotiation
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Negotiated {
+  /// selected media type
+  pub media_type: Option<String>,
+  /// selected language
+  pub language: Option<String>,
+  /// selected charset
+  pub charset: Option<String>,
+  /// selected encoding
+  pub encoding: Option<String>
+}
+
+/// Type map of values keyed by their type. This allows callbacks to store strongly-typed values
+/// (like an authenticated user) on the context as the webmachine executes.
+#[derive(Clone, Default)]
+pub struct Extensions {
+  map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>
+}
+
+impl Extensions {
+  /// Inserts a value into the extensions, replacing any existing value of the same type
+  pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+    self.map.insert(TypeId::of::<T>(), Arc::new(value));
+  }
+
+  /// Returns a reference to the value of the given type, if one has been stored
+  pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+    self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref::<T>())
+  }
+
+  /// If a value of the given type has been stored
+  pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+    self.map.contains_key(&TypeId::of::<T>())
+  }
+
+  /// Removes the value of the given type, returning true if there was one
+  pub fn remove<T: Any + Send + Sync>(&mut self) -> bool {
+    self.map.remove(&TypeId::of::<T>()).is_some()
+  }
+}
+
+impl Debug for Extensions {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+  }
+}
+
+impl PartialEq for Extensions {
+  fn eq(&self, other: &Self) -> bool {
+    self.map.len() == other.map.len() && self.map.iter().all(|(k, v)| {
+      other.map.get(k).map(|o| Arc::ptr_eq(v, o)).unwrap_or(false)
+    })
+  }
+}
+
 /// Main context struct that holds the request and response.
 #[derive(Debug, Clone, PartialEq)]
 pub struct WebmachineContext {
@@ -239,10 +605,27 @@ pub struct WebmachineContext {
   pub if_modified_since: Option<DateTime<FixedOffset>>,
   /// If the response should be a redirect
   pub redirect: bool,
+  /// Location to redirect to, set with `redirect_to`
+  pub redirect_location: Option<String>,
   /// If a new resource was created
   pub new_resource: bool,
   /// General store of metadata. You can use this to store attributes as the webmachine executes.
-  pub metadata: HashMap<String, String>
+  pub metadata: HashMap<String, String>,
+  /// Store of typed values. You can use this to store values as the webmachine executes.
+  pub extensions: Extensions,
+  /// Trace of the decisions made by the state machine. Each entry is the decision (i.e. `B13Available`),
+  /// the branch that was taken, and the resulting decision or end state (i.e. `End(404)`).
+  pub decisions: Vec<(String, bool, String)>,
+  /// Values of the `{name}` segments of the route that matched the request path
+  pub path_params: HashMap<String, String>,
+  /// The route that matched the request path (i.e. `/users/{id}`), set by the dispatcher
+  pub matched_route: Option<String>,
+  /// The parsed Accept headers of the request, used for content negotiation. These are parsed
+  /// once when the context is created by the dispatcher (or when first needed), so will not
+  /// reflect any changes made to the request headers after that.
+  pub accept_headers: Option<AcceptHeaders>,
+  /// Deadline for the request, parsed from the request headers when the request was received
+  pub request_deadline: Option<DateTime<Utc>>
 }
 
 impl Default for WebmachineContext {
@@ -258,10 +641,68 @@ impl Default for WebmachineContext {
       if_unmodified_since: None,
       if_modified_since: None,
       redirect: false,
+      redirect_location: None,
       new_resource: false,
-      metadata: HashMap::new()
+      metadata: HashMap::new(),
+      extensions: Extensions::default(),
+      decisions: Vec::new(),
+      path_params: HashMap::new(),
+      matched_route: None,
+      accept_headers: None,
+      request_deadline: None
+    }
+  }
+}
+
+impl WebmachineContext {
+  /// Returns the parsed Accept headers of the request, parsing them if that has not already
+  /// been done
+  pub fn parse_accept_headers(&mut self) -> &AcceptHeaders {
+    if self.accept_headers.is_none() {
+      self.accept_headers = Some(AcceptHeaders::parse(&self.request));
+    }
+    self.accept_headers.as_ref().unwrap()
+  }
+
+  /// Returns the representation selected by content negotiation
+  pub fn negotiated(&self) -> Negotiated {
+    Negotiated {
+      media_type: self.selected_media_type.clone(),
+      language: self.selected_language.clone(),
+      charset: self.selected_charset.clone(),
+      encoding: self.selected_encoding.clone()
     }
   }
+
+  /// Marks the response as a redirect to the given location, which will be used as the Location
+  /// header. This is used when processing a POST (in `process_post` or `create_path`), and will
+  /// result in a '303 See Other' response. To redirect a request for a resource that has moved,
+  /// use the `moved_permanently` (301) or `moved_temporarily` (307) callbacks instead.
+  pub fn redirect_to(&mut self, location: &str) {
+    self.redirect = true;
+    self.redirect_location = Some(location.to_string());
+  }
+
+  /// Returns the deadline the client set for the request (see `WebmachineRequest::deadline_from_headers`)
+  pub fn deadline(&self) -> Option<DateTime<Utc>> {
+    self.request_deadline
+  }
+
+  /// Returns the time remaining until the request deadline, if there is one. Returns a zero
+  /// duration if the deadline has passed.
+  pub fn remaining_time(&self) -> Option<Duration> {
+    self.request_deadline.map(|deadline| (deadline - Utc::now()).max(Duration::zero()))
+  }
+
+  /// Stores a typed value on the context, replacing any existing value of the same type
+  pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+    self.extensions.insert(value);
+  }
+
+  /// Returns the typed value stored on the context, if there is one
+  pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+    self.extensions.get::<T>()
+  }
 }
 
 #[cfg(test)]
@@ -313,4 +754,341 @@ mod tests {
       expect!(request.has_header_value("HeaderA", "other")).to(be_true());
       expect!(request.has_header_value("HeaderA", "other2")).to(be_false());
   }
+
+  #[test]
+  fn request_header_value_test() {
+      let request = WebmachineRequest {
+          headers: hashmap!{
+            "X-Request-Id".to_string() => vec![h!("1234")],
+            "X-Forwarded-For".to_string() => vec![h!("10.0.0.1"), h!("10.0.0.2")]
+          },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.header_value("x-request-id")).to(be_some().value("1234"));
+      expect!(request.header_value("X-Forwarded-For")).to(be_some().value("10.0.0.1"));
+      expect!(request.header_value("Authorization")).to(be_none());
+      expect!(request.header_values_joined("x-forwarded-for")).to(be_some().value("10.0.0.1, 10.0.0.2"));
+      expect!(request.header_values_joined("X-REQUEST-ID")).to(be_some().value("1234"));
+      expect!(request.header_values_joined("Authorization")).to(be_none());
+  }
+
+  fn default_via_trait<T: Default>() -> T {
+      T::default()
+  }
+
+  #[test]
+  fn default_trait_test() {
+      let request: WebmachineRequest = default_via_trait();
+      expect!(request.request_path).to(be_equal_to("/"));
+      expect!(request.method).to(be_equal_to("GET"));
+
+      let response: WebmachineResponse = default_via_trait();
+      expect!(response.status).to(be_equal_to(200));
+
+      let context = WebmachineContext {
+          request: WebmachineRequest { method: "POST".to_string(), ..Default::default() },
+          ..Default::default()
+      };
+      expect!(context.request.method).to(be_equal_to("POST"));
+      expect!(context.response.status).to(be_equal_to(200));
+  }
+
+  #[test]
+  fn request_content_type_params_test() {
+      let request = WebmachineRequest {
+          headers: hashmap!{
+            "Content-Type".to_string() => vec![HeaderValue::parse_string("multipart/form-data; boundary=xyz")]
+          },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.content_type()).to(be_equal_to("multipart/form-data"));
+      expect!(request.content_type_params()).to(be_equal_to(hashmap!{
+        "boundary".to_string() => "xyz".to_string()
+      }));
+
+      let request = WebmachineRequest {
+          headers: hashmap!{
+            "content-type".to_string() => vec![HeaderValue::parse_string("text/plain; Charset=\"utf-8\"")]
+          },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.content_type_params()).to(be_equal_to(hashmap!{
+        "charset".to_string() => "utf-8".to_string()
+      }));
+      expect!(WebmachineRequest::default().content_type_params().is_empty()).to(be_true());
+  }
+
+  #[test]
+  fn response_deprecation_and_sunset_test() {
+      let mut response = WebmachineResponse::default();
+      let deprecation = DateTime::parse_from_rfc3339("2023-06-30T23:59:59Z").unwrap();
+      let sunset = DateTime::parse_from_rfc3339("2024-06-30T23:59:59+10:00").unwrap();
+      response.set_deprecation(&deprecation);
+      response.set_sunset(&sunset);
+      expect!(response.headers.get("Deprecation").cloned())
+        .to(be_some().value(vec![HeaderValue::basic("@1688169599")]));
+      expect!(response.headers.get("Sunset").cloned())
+        .to(be_some().value(vec![HeaderValue::basic("Sun, 30 Jun 2024 13:59:59 GMT")]));
+  }
+
+  #[test]
+  fn request_cookies_test() {
+      let request = WebmachineRequest {
+          headers: hashmap!{
+            "Cookie".to_string() => vec![HeaderValue::basic("session=abc123; theme=\"dark\"; session=other")]
+          },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.cookies()).to(be_equal_to(hashmap!{
+        "session".to_string() => "abc123".to_string(),
+        "theme".to_string() => "dark".to_string()
+      }));
+      expect!(WebmachineRequest::default().cookies().is_empty()).to(be_true());
+  }
+
+  #[test]
+  fn request_cookies_keeps_the_first_value_and_values_containing_equals() {
+      let request = WebmachineRequest {
+          headers: hashmap!{
+            "Cookie".to_string() => vec![
+              HeaderValue::basic("a=1; b=2; a=3; token=YWJjZA==; empty="),
+              HeaderValue::basic("b=4; quoted=\"x=y\"")
+            ]
+          },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.cookies()).to(be_equal_to(hashmap!{
+        "a".to_string() => "1".to_string(),
+        "b".to_string() => "2".to_string(),
+        "token".to_string() => "YWJjZA==".to_string(),
+        "empty".to_string() => "".to_string(),
+        "quoted".to_string() => "x=y".to_string()
+      }));
+  }
+
+  #[test]
+  fn response_add_cookie_test() {
+      let mut response = WebmachineResponse::default();
+      response.add_cookie("session", "abc123", &["Path=/", "HttpOnly"]);
+      response.add_cookie("theme", "dark", &[]);
+      expect!(response.headers.get("Set-Cookie").cloned()).to(be_some().value(vec![
+        HeaderValue::basic("session=abc123; Path=/; HttpOnly"),
+        HeaderValue::basic("theme=dark")
+      ]));
+  }
+
+  #[test]
+  fn request_content_length_test() {
+      let request = WebmachineRequest {
+          headers: hashmap!{ "Content-Length".to_string() => vec![h!("1024")] },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.content_length()).to(be_some().value(1024));
+      let request = WebmachineRequest {
+          headers: hashmap!{ "Content-Length".to_string() => vec![h!("lots")] },
+          .. WebmachineRequest::default()
+      };
+      expect!(request.content_length()).to(be_none());
+      expect!(WebmachineRequest::default().content_length()).to(be_none());
+  }
+
+  #[derive(Debug, PartialEq)]
+  struct User {
+    name: String
+  }
+
+  #[test]
+  fn extensions_store_values_by_type() {
+    let mut extensions = Extensions::default();
+    expect!(extensions.get::<User>()).to(be_none());
+
+    extensions.insert(User { name: "Fred".to_string() });
+    extensions.insert(100_u32);
+    expect!(extensions.contains::<User>()).to(be_true());
+    expect!(extensions.get::<User>()).to(be_some().value(&User { name: "Fred".to_string() }));
+    expect!(extensions.get::<u32>()).to(be_some().value(&100));
+    expect!(extensions.get::<String>()).to(be_none());
+
+    expect!(extensions.remove::<User>()).to(be_true());
+    expect!(extensions.get::<User>()).to(be_none());
+  }
+
+  #[test]
+  fn request_keep_alive_test() {
+    let request = WebmachineRequest::default();
+    expect!(request.is_keep_alive()).to(be_true());
+
+    let request = WebmachineRequest {
+      headers: hashmap!{ "Connection".to_string() => vec![h!("close")] },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.is_keep_alive()).to(be_false());
+
+    let request = WebmachineRequest {
+      http_version: "HTTP/1.0".to_string(),
+      .. WebmachineRequest::default()
+    };
+    expect!(request.is_keep_alive()).to(be_false());
+
+    let request = WebmachineRequest {
+      http_version: "HTTP/1.0".to_string(),
+      headers: hashmap!{ "Connection".to_string() => vec![h!("Keep-Alive")] },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.is_keep_alive()).to(be_true());
+  }
+
+  #[test]
+  #[cfg(feature = "serde")]
+  fn request_json_body_test() {
+    let request = WebmachineRequest {
+      headers: hashmap!{ "Content-Type".to_string() => vec![h!("application/json;charset=UTF-8")] },
+      body: Some("{\"a\": [1, 2]}".as_bytes().to_vec()),
+      .. WebmachineRequest::default()
+    };
+    let body: HashMap<String, Vec<u32>> = request.json().unwrap();
+    expect!(body).to(be_equal_to(hashmap!{ "a".to_string() => vec![1, 2] }));
+
+    let request = WebmachineRequest {
+      headers: hashmap!{ "Content-Type".to_string() => vec![h!("text/plain")] },
+      body: Some("{\"a\": [1, 2]}".as_bytes().to_vec()),
+      .. WebmachineRequest::default()
+    };
+    let result: Result<HashMap<String, Vec<u32>>, _> = request.json();
+    expect!(result.unwrap_err().to_string()).to(be_equal_to("Expected a JSON request body, but the content type is 'text/plain'"));
+  }
+
+  #[test]
+  fn request_form_body_test() {
+    let request = WebmachineRequest {
+      headers: hashmap!{ "Content-Type".to_string() => vec![h!("application/x-www-form-urlencoded")] },
+      body: Some("a=1&b=hello+world&a=2".as_bytes().to_vec()),
+      .. WebmachineRequest::default()
+    };
+    expect!(request.form_body()).to(be_ok().value(hashmap!{
+      "a".to_string() => vec!["1".to_string(), "2".to_string()],
+      "b".to_string() => vec!["hello world".to_string()]
+    }));
+
+    let request = WebmachineRequest {
+      body: Some("a=1".as_bytes().to_vec()),
+      .. WebmachineRequest::default()
+    };
+    expect!(request.form_body()).to(be_err().value("Expected a form request body, but the content type is 'application/json'".to_string()));
+  }
+
+  #[test]
+  fn request_deadline_from_headers_test() {
+    let received = DateTime::parse_from_rfc3339("2026-10-15T10:00:00Z").unwrap().with_timezone(&Utc);
+
+    let request = WebmachineRequest {
+      headers: hashmap!{ "grpc-timeout".to_string() => vec![h!("1500m")] },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.deadline_from_headers(received)).to(be_some().value(received + Duration::milliseconds(1500)));
+
+    let request = WebmachineRequest {
+      headers: hashmap!{ "X-Deadline".to_string() => vec![h!("2026-10-15T10:00:30+00:00")] },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.deadline_from_headers(received)).to(be_some().value(received + Duration::seconds(30)));
+
+    let request = WebmachineRequest {
+      headers: hashmap!{ "grpc-timeout".to_string() => vec![h!("10x")] },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.deadline_from_headers(received)).to(be_none());
+    expect!(WebmachineRequest::default().deadline_from_headers(received)).to(be_none());
+  }
+
+  #[test]
+  fn parse_grpc_timeout_test() {
+    expect!(parse_grpc_timeout("2H")).to(be_some().value(Duration::hours(2)));
+    expect!(parse_grpc_timeout("5M")).to(be_some().value(Duration::minutes(5)));
+    expect!(parse_grpc_timeout("10S")).to(be_some().value(Duration::seconds(10)));
+    expect!(parse_grpc_timeout("100m")).to(be_some().value(Duration::milliseconds(100)));
+    expect!(parse_grpc_timeout("100u")).to(be_some().value(Duration::microseconds(100)));
+    expect!(parse_grpc_timeout("100n")).to(be_some().value(Duration::nanoseconds(100)));
+    expect!(parse_grpc_timeout("123456789S")).to(be_none());
+    expect!(parse_grpc_timeout("S")).to(be_none());
+    expect!(parse_grpc_timeout("")).to(be_none());
+  }
+
+  #[test]
+  fn context_deadline_test() {
+    let deadline = Utc::now() + Duration::seconds(60);
+    let context = WebmachineContext {
+      request_deadline: Some(deadline),
+      .. WebmachineContext::default()
+    };
+    expect!(context.deadline()).to(be_some().value(deadline));
+    expect!(context.remaining_time().unwrap() > Duration::zero()).to(be_true());
+    expect!(WebmachineContext::default().remaining_time()).to(be_none());
+  }
+
+  #[test]
+  fn response_error_test() {
+    let response = WebmachineResponse::error(503, "text/plain; charset=utf-8", "try again later");
+    expect!(response.status).to(be_equal_to(503));
+    expect!(response.headers.get("Content-Type").unwrap()[0].to_string()).to(be_equal_to("text/plain; charset=utf-8"));
+    expect!(response.body).to(be_some().value("try again later".as_bytes().to_vec()));
+  }
+
+  #[test]
+  fn response_has_body_test() {
+    expect!(WebmachineResponse::default().has_body()).to(be_false());
+    expect!(WebmachineResponse { body: Some(vec![]), .. WebmachineResponse::default() }.has_body()).to(be_false());
+    expect!(WebmachineResponse { body: Some(vec![1]), .. WebmachineResponse::default() }.has_body()).to(be_true());
+  }
+
+  #[test]
+  fn request_query_param_test() {
+    let request = WebmachineRequest {
+      query: hashmap!{
+        "a".to_string() => vec!["1".to_string(), "2".to_string()],
+        "b".to_string() => vec![]
+      },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.query_param("a")).to(be_some().value("1"));
+    expect!(request.query_param("b")).to(be_none());
+    expect!(request.query_param("c")).to(be_none());
+    expect!(request.query_params("a")).to(be_equal_to(&["1".to_string(), "2".to_string()][..]));
+    expect!(request.query_params("c").iter()).to(be_empty());
+  }
+
+  #[test]
+  fn request_query_array_test() {
+    let request = WebmachineRequest {
+      query: hashmap!{
+        "items[]".to_string() => vec!["a".to_string(), "b".to_string()],
+        "ids".to_string() => vec!["1".to_string(), "2".to_string()],
+        "tags".to_string() => vec!["x".to_string()],
+        "tags[]".to_string() => vec!["y".to_string()]
+      },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.query_array("items")).to(be_equal_to(vec!["a", "b"]));
+    expect!(request.query_array("items[]")).to(be_equal_to(vec!["a", "b"]));
+    expect!(request.query_array("ids")).to(be_equal_to(vec!["1", "2"]));
+    expect!(request.query_array("tags")).to(be_equal_to(vec!["x", "y"]));
+    expect!(request.query_array("other").iter()).to(be_empty());
+    expect!(request.query.contains_key("items[]")).to(be_true());
+  }
+
+  #[test]
+  fn request_query_int_test() {
+    let request = WebmachineRequest {
+      query: hashmap!{
+        "page".to_string() => vec!["2".to_string(), "3".to_string()],
+        "offset".to_string() => vec!["-10".to_string()],
+        "size".to_string() => vec!["large".to_string()]
+      },
+      .. WebmachineRequest::default()
+    };
+    expect!(request.query_int("page")).to(be_some().value(2));
+    expect!(request.query_int("offset")).to(be_some().value(-10));
+    expect!(request.query_int("size")).to(be_none());
+    expect!(request.query_int("limit")).to(be_none());
+  }
 }
@@ -0,0 +1,119 @@
+//! The `debugger` module provides a basic visual debugger, rendering the path taken through the
+//! webmachine decision diagram (https://webmachine.github.io/images/http-headers-status-v3.png)
+//! from the decisions recorded on the context.
+
+use itertools::Itertools;
+
+use crate::context::WebmachineContext;
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; }
+.diagram { display: grid; grid-template-columns: repeat(16, 5em); grid-auto-rows: 2.5em; gap: 0.25em; }
+.column { font-weight: bold; text-align: center; }
+.decision { border-radius: 0.5em; text-align: center; line-height: 2.5em; color: white; }
+.true { background-color: #2e7d32; }
+.false { background-color: #c62828; }
+.status { font-size: 1.5em; margin: 1em 0; }
+"#;
+
+/// Splits a decision name (i.e. `B13Available`) into the diagram column (1 for A through to 16
+/// for P) and row
+fn diagram_position(label: &str) -> Option<(usize, usize)> {
+  let mut chars = label.chars();
+  let column = chars.next().filter(|ch| ('A'..='P').contains(ch))?;
+  let row: String = chars.take_while(|ch| ch.is_ascii_digit()).collect();
+  let row = row.parse().ok()?;
+  Some((column as usize - 'A' as usize + 1, row))
+}
+
+/// Returns the diagram label (i.e. `B13`) for a decision name (i.e. `B13Available`)
+fn diagram_label(decision: &str) -> String {
+  decision.chars()
+    .take(1)
+    .chain(decision.chars().skip(1).take_while(|ch| ch.is_ascii_digit()))
+    .collect()
+}
+
+fn escape_html(value: &str) -> String {
+  value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl WebmachineContext {
+  /// Renders a standalone HTML page showing the path taken through the webmachine decision
+  /// diagram, using the decisions recorded while the state machine executed. Each visited decision
+  /// is placed at its position in the diagram (B13, C3, etc.) and coloured green if the true branch
+  /// was taken or red if the false branch was taken. The final status code is also shown.
+  pub fn render_decision_diagram(&self) -> String {
+    let columns = ('A'..='P')
+      .enumerate()
+      .map(|(index, column)| format!("<div class=\"column\" style=\"grid-column: {}; grid-row: 1;\">{}</div>",
+        index + 1, column))
+      .join("\n");
+    let nodes = self.decisions.iter()
+      .filter_map(|(decision, result, next)| diagram_position(decision).map(|(column, row)| {
+        format!("<div class=\"decision {}\" style=\"grid-column: {}; grid-row: {};\" title=\"{} -&gt; {}\">{}</div>",
+          if *result { "true" } else { "false" }, column, row + 1, escape_html(decision), escape_html(next),
+          escape_html(&diagram_label(decision)))
+      }))
+      .join("\n");
+    let steps = self.decisions.iter()
+      .map(|(decision, result, next)| format!("<li class=\"{}\">{} ({}) &rarr; {}</li>",
+        if *result { "true" } else { "false" }, escape_html(decision), result, escape_html(next)))
+      .join("\n");
+
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Webmachine Decisions: {method} {path}</title>
+<style>{style}</style>
+</head>
+<body>
+<h1>{method} {path}</h1>
+<div class="status">Status: {status}</div>
+<div class="diagram">
+{columns}
+{nodes}
+</div>
+<h2>Decisions</h2>
+<ol>
+{steps}
+</ol>
+</body>
+</html>
+"#, method = escape_html(&self.request.method), path = escape_html(&self.request.request_path),
+      style = STYLE, status = self.response.status, columns = columns, nodes = nodes, steps = steps)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn diagram_position_test() {
+    expect!(diagram_position("B13Available")).to(be_some().value((2, 13)));
+    expect!(diagram_position("C3AcceptExists")).to(be_some().value((3, 3)));
+    expect!(diagram_position("P11NewResource")).to(be_some().value((16, 11)));
+    expect!(diagram_position("End(404)")).to(be_none());
+    expect!(diagram_position("Start")).to(be_none());
+  }
+
+  #[test]
+  fn render_decision_diagram_test() {
+    let mut context = WebmachineContext::default();
+    context.response.status = 403;
+    context.decisions = vec![
+      ("B13Available".to_string(), true, "B12KnownMethod".to_string()),
+      ("B7Forbidden".to_string(), true, "End(403)".to_string()),
+      ("B11UriTooLong".to_string(), false, "B10MethodAllowed".to_string())
+    ];
+    let html = context.render_decision_diagram();
+    expect!(html.contains("Status: 403")).to(be_true());
+    expect!(html.contains("<div class=\"decision true\" style=\"grid-column: 2; grid-row: 14;\" title=\"B13Available -&gt; B12KnownMethod\">B13</div>")).to(be_true());
+    expect!(html.contains("<div class=\"decision false\" style=\"grid-column: 2; grid-row: 12;\" title=\"B11UriTooLong -&gt; B10MethodAllowed\">B11</div>")).to(be_true());
+    expect!(html.contains("<li class=\"true\">B7Forbidden (true) &rarr; End(403)</li>")).to(be_true());
+  }
+}
@@ -1,17 +1,19 @@
 //! The `headers` deals with parsing and formatting request and response headers
 
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::iter::Peekable;
 use std::str::Chars;
 
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
 use itertools::Itertools;
 
 const SEPERATORS: [char; 10] = ['(', ')', '<', '>', '@', ',', ';', '=', '{', '}'];
 const VALUE_SEPERATORS: [char; 9] = ['(', ')', '<', '>', '@', ',', ';', '{', '}'];
 
 fn batch(values: &[String]) -> Vec<(String, String)> {
-  values.into_iter().batching(|it| {
+  values.iter().batching(|it| {
     match it.next() {
      None => None,
      Some(x) => match it.next() {
@@ -159,26 +161,6 @@ impl HeaderValue {
       }
     }
 
-    /// Converts this header value into a string representation
-    pub fn to_string(&self) -> String {
-        let sparams = self.params.iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .join("; ");
-        if self.quote {
-            if sparams.is_empty() {
-                format!("\"{}\"", self.value)
-            } else {
-                format!("\"{}\"; {}", self.value, sparams)
-            }
-        } else {
-            if self.params.is_empty() {
-                self.value.clone()
-            } else {
-                format!("{}; {}", self.value, sparams)
-            }
-        }
-    }
-
     /// Parses a weak ETag value. Weak etags are in the form W/<quoted-string>. Returns the
     /// contents of the qouted string if it matches, otherwise returns None.
     pub fn weak_etag(&self) -> Option<String> {
@@ -196,6 +178,26 @@ impl HeaderValue {
     }
 }
 
+impl Display for HeaderValue {
+    /// Converts this header value into a string representation
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sparams = self.params.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .join("; ");
+        if self.quote {
+            if sparams.is_empty() {
+                write!(f, "\"{}\"", self.value)
+            } else {
+                write!(f, "\"{}\"; {}", self.value, sparams)
+            }
+        } else if self.params.is_empty() {
+            write!(f, "{}", self.value)
+        } else {
+            write!(f, "{}; {}", self.value, sparams)
+        }
+    }
+}
+
 impl PartialEq<HeaderValue> for HeaderValue {
     fn eq(&self, other: &HeaderValue) -> bool {
         self.value == other.value && self.params == other.params
@@ -224,6 +226,22 @@ impl Hash for HeaderValue {
     }
 }
 
+/// Parses an HTTP date (i.e. from an If-Modified-Since header). The preferred RFC 1123 format
+/// (`Sun, 06 Nov 1994 08:49:37 GMT`) and RFC 2822 dates are supported, as well as the obsolete
+/// RFC 850 (`Sunday, 06-Nov-94 08:49:37 GMT`) and asctime (`Sun Nov  6 08:49:37 1994`) formats.
+pub fn parse_http_date(value: &str) -> Option<DateTime<FixedOffset>> {
+  let value = value.trim();
+  DateTime::parse_from_rfc2822(value).ok()
+    .or_else(|| ["%a, %d %b %Y %H:%M:%S GMT", "%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"].iter()
+      .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+      .map(|datetime| Utc.from_utc_datetime(&datetime).fixed_offset()))
+}
+
+/// Formats the date and time as an RFC 1123 HTTP date (i.e. `Sun, 06 Nov 1994 08:49:37 GMT`)
+pub fn format_http_date(datetime: &DateTime<FixedOffset>) -> String {
+  datetime.with_timezone(&Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
 /// Simple macro to convert a string to a `HeaderValue` struct.
 #[macro_export]
 macro_rules! h {
@@ -325,7 +343,7 @@ mod tests {
         }));
         expect!(header.weak_etag()).to(be_none());
 
-        let weak_etag_value = HeaderValue::parse_string(weak_etag.clone());
+        let weak_etag_value = HeaderValue::parse_string(weak_etag);
         expect!(weak_etag_value.clone()).to(be_equal_to(HeaderValue {
             value: weak_etag.to_string(),
             params: hashmap!{},
@@ -333,4 +351,23 @@ mod tests {
         }));
         expect!(weak_etag_value.weak_etag()).to(be_some().value("1234567890"));
     }
+
+    #[test]
+    fn parse_http_date_test() {
+        let expected = DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z").unwrap();
+        // curl and Chrome send RFC 1123 dates
+        expect!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT")).to(be_some().value(expected));
+        expect!(parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT")).to(be_some().value(
+          DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z").unwrap()));
+        expect!(parse_http_date("Sun, 06 Nov 1994 18:49:37 +1000")).to(be_some().value(expected));
+        expect!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT")).to(be_some().value(expected));
+        expect!(parse_http_date("Sun Nov  6 08:49:37 1994")).to(be_some().value(expected));
+        expect!(parse_http_date("not a date")).to(be_none());
+    }
+
+    #[test]
+    fn format_http_date_test() {
+        let datetime = DateTime::parse_from_rfc3339("1994-11-06T18:49:37+10:00").unwrap();
+        expect!(format_http_date(&datetime)).to(be_equal_to("Sun, 06 Nov 1994 08:49:37 GMT"));
+    }
 }
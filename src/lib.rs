@@ -16,22 +16,17 @@ REQUEST -> Hyper Handler -> WebmachineDispatcher -> WebmachineResource -> Your a
 
 - Handles the hard parts of content negotiation, conditional requests, and response codes for you.
 - Provides a resource struct with points of extension to let you describe what is relevant about your particular resource.
-
-## Missing Features
-
-Currently, the following features from webmachine-ruby have not been implemented:
-
-- Visual debugger
-- Streaming response bodies
+- Renders bodies for different content types (e.g. JSON vs. XML) with `WebmachineResource::produces_map`, or
+  serializes a typed value for the negotiated content type with `serializers::SerializerRegistry`.
+- Routes can have path parameters (`/users/{id}`) and can match sub-paths with a splat segment as the last
+  segment of the route (i.e. `*path`).
 
 ## Implementation Deficiencies:
 
 This implementation has the following deficiencies:
 
-- Automatically decoding request bodies and encoding response bodies.
-- No easy mechanism to generate bodies with different content types (e.g. JSON vs. XML).
-- No easy mechanism for handling sub-paths in a resource.
-- Dynamically determining the methods allowed on the resource.
+- Automatically decoding request bodies (other than JSON and form bodies, see `WebmachineRequest::json` and
+  `WebmachineRequest::form_body`) and encoding response bodies.
 
 ## Getting started with Hyper
 
@@ -78,7 +73,8 @@ Note: This example uses the maplit crate to provide the `btreemap` macro and the
             // default everything else
             .. WebmachineResource::default()
           }
-      }
+      },
+      .. WebmachineDispatcher::default()
    }
  }
 
@@ -107,6 +103,9 @@ For an example of a project using this crate, have a look at the [Pact Mock Serv
 #![warn(missing_docs)]
 
 use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::ops::Deref;
 use std::pin::Pin;
@@ -114,44 +113,192 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
 
-use chrono::{DateTime, FixedOffset, Utc};
-use futures::TryStreamExt;
+use chrono::{DateTime, FixedOffset, Timelike, Utc};
 use http::{Request, Response};
 use http::request::Parts;
 use hyper::Body;
+use hyper::body::{Buf, HttpBody};
+use hyper::ext::ReasonPhrase;
 use hyper::service::Service;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use maplit::hashmap;
-use tracing::{debug, error, trace};
+use tracing::{debug, error, trace, warn};
 
-use context::{WebmachineContext, WebmachineRequest, WebmachineResponse};
-use headers::HeaderValue;
+use content_negotiation::AcceptHeaders;
+use context::{BodyStream, StreamingBody, WebmachineContext, WebmachineRequest, WebmachineResponse};
+use headers::{format_http_date, parse_http_date, HeaderValue};
 
 #[macro_use] pub mod headers;
 pub mod context;
 pub mod content_negotiation;
+pub mod range;
+pub mod builder;
+pub mod sse;
+pub mod serializers;
+mod debugger;
 
 /// Type of a Webmachine resource callback
 pub type WebmachineCallback<'a, T> = Arc<Mutex<Box<dyn Fn(&mut WebmachineContext, &WebmachineResource) -> T + Send + Sync + 'a>>>;
 
 /// Wrap a callback in a structure that is safe to call between threads
-pub fn callback<T, RT>(cb: &T) -> WebmachineCallback<RT>
+pub fn callback<T, RT>(cb: &T) -> WebmachineCallback<'_, RT>
   where T: Fn(&mut WebmachineContext, &WebmachineResource) -> RT + Send + Sync {
   Arc::new(Mutex::new(Box::new(cb)))
 }
 
-/// Struct to represent a resource in webmachine
+/// Wrap a callback that is owned by the resource (i.e. a closure that captures values) in a
+/// structure that is safe to call between threads
+pub(crate) fn owned_callback<'a, T, RT>(cb: T) -> WebmachineCallback<'a, RT>
+  where T: Fn(&mut WebmachineContext, &WebmachineResource) -> RT + Send + Sync + 'a {
+  Arc::new(Mutex::new(Box::new(cb)))
+}
+
+/// Type of the future returned by an async resource callback
+pub type CallbackFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Type of an async Webmachine resource callback. The returned future can not borrow the context
+/// or resource, so any values required from them need to be cloned before the future is created.
+pub type AsyncWebmachineCallback<'a, T> = Arc<dyn Fn(&mut WebmachineContext, &WebmachineResource) -> CallbackFuture<'a, T> + Send + Sync + 'a>;
+
+/// Wrap an async callback (a function returning a future) so that it can be used with a resource.
+/// Only the callbacks that are likely to need to do I/O have an async version (`not_authorized`,
+/// `forbidden`, `resource_exists`, `moved_permanently`, `moved_temporarily`, `is_conflict`,
+/// `generate_etag`, `delete_resource`, `create_path`, `process_post`, `process_put`,
+/// `process_patch` and `render_response`), all the other callbacks are synchronous.
+pub fn async_callback<'a, F, FT, RT>(cb: F) -> AsyncWebmachineCallback<'a, RT>
+  where F: Fn(&mut WebmachineContext, &WebmachineResource) -> FT + Send + Sync + 'a,
+        FT: Future<Output = RT> + Send + 'a {
+  Arc::new(move |context: &mut WebmachineContext, resource: &WebmachineResource| -> CallbackFuture<'a, RT> {
+    Box::pin(cb(context, resource))
+  })
+}
+
+/// Invokes the async version of a callback if it has been provided, otherwise the sync one
+async fn invoke_callback<'a, T>(
+  callback: &WebmachineCallback<'a, T>,
+  async_callback: &Option<AsyncWebmachineCallback<'a, T>>,
+  context: &mut WebmachineContext,
+  resource: &WebmachineResource<'_>
+) -> T {
+  match async_callback {
+    Some(async_callback) => async_callback(context, resource).await,
+    None => {
+      let callback = callback.lock().unwrap();
+      callback.deref()(context, resource)
+    }
+  }
+}
+
+/// Type of a lazily rendered response body. It is only evaluated if the body is actually sent
+pub type LazyBody = Box<dyn FnOnce() -> String + Send>;
+
+/// Type of the future returned by a raw request handler
+pub type RawResponseFuture = Pin<Box<dyn Future<Output = http::Result<Response<Body>>> + Send>>;
+
+/// Type of a raw request handler. This receives ownership of the Hyper request and returns the
+/// complete response, bypassing the webmachine state machine
+pub type RawRequestHandler<'a> = Arc<dyn Fn(Request<Body>) -> RawResponseFuture + Send + Sync + 'a>;
+
+/// Type of a dispatcher interceptor. Interceptors are run for every request before the resource
+/// is executed, and can return a response to short-circuit the request
+pub type Interceptor<'a> = Arc<dyn Fn(&mut WebmachineContext) -> Option<WebmachineResponse> + Send + Sync + 'a>;
+
+/// Error that can be returned from the resource callbacks that process a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebmachineError {
+  /// The response should have the given status code
+  Status(u16),
+  /// The response should have the given status code and body
+  StatusWithBody(u16, String)
+}
+
+impl WebmachineError {
+  /// Status code for the response
+  pub fn status(&self) -> u16 {
+    match self {
+      WebmachineError::Status(status) => *status,
+      WebmachineError::StatusWithBody(status, _) => *status
+    }
+  }
+
+  /// Body for the response, if there is one
+  pub fn body(&self) -> Option<&str> {
+    match self {
+      WebmachineError::Status(_) => None,
+      WebmachineError::StatusWithBody(_, body) => Some(body.as_str())
+    }
+  }
+}
+
+impl From<u16> for WebmachineError {
+  fn from(status: u16) -> Self {
+    WebmachineError::Status(status)
+  }
+}
+
+impl Display for WebmachineError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      WebmachineError::Status(status) => write!(f, "Request failed with status {}", status),
+      WebmachineError::StatusWithBody(status, body) => write!(f, "Request failed with status {} - {}", status, body)
+    }
+  }
+}
+
+impl std::error::Error for WebmachineError {}
+
+/// Struct to represent a resource in webmachine. See `builder::WebmachineResourceBuilder` for a
+/// fluent way to construct one.
 #[derive(Clone)]
 pub struct WebmachineResource<'a> {
   /// This is called just before the final response is constructed and sent. It allows the resource
-  /// an opportunity to modify the response after the webmachine has executed.
+  /// an opportunity to modify the response after the webmachine has executed. It is the last
+  /// callback invoked for a request, after `finish_request` and after the body has been rendered,
+  /// so any headers set here (i.e. CORS headers) will override those set by `finish_request`.
+  /// The Content-Length header has already been set from the body, so it needs to be updated if
+  /// the body is changed here.
   pub finalise_response: Option<WebmachineCallback<'a, ()>>,
-  /// This is invoked to render the response for the resource
+  /// This is invoked to render the response for the resource. It is only invoked when the body
+  /// is actually required (a GET request that results in a 200 response), so the body is not
+  /// generated for conditional requests that result in a 304. For HEAD requests, it is invoked to
+  /// determine the Content-Length header and the body is then discarded, unless the length is
+  /// provided by `body_length`. Use `render_response_lazy` to avoid building the body for HEAD
+  /// requests.
+  ///
+  /// It is invoked after `finish_request` and after the Content-Type, ETag and Last-Modified
+  /// headers have been added to the response, so any headers it sets on `context.response`
+  /// (i.e. a checksum of the rendered body) are kept and will replace those set earlier. Only
+  /// `finalise_response` runs after it.
   pub render_response: WebmachineCallback<'a, Option<String>>,
+  /// Async version of `render_response` (see `async_callback`), which is used instead of
+  /// `render_response` if set. Default is None.
+  pub render_response_async: Option<AsyncWebmachineCallback<'a, Option<String>>>,
+  /// Lazy version of `render_response`, which is used instead of `render_response` if set. It
+  /// returns a closure that builds the body, which is only evaluated for GET requests. For HEAD
+  /// requests the closure is dropped without being evaluated, so an expensive body is never built
+  /// just to be discarded (the Content-Length header is then only set if `body_length` provides
+  /// it). Default is None.
+  pub render_response_lazy: Option<WebmachineCallback<'a, Option<LazyBody>>>,
+  /// This is invoked to render a streaming response for the resource (i.e. for large files that
+  /// should not be loaded into memory). If it returns a stream, it will be used as the response
+  /// body and `render_response` will not be invoked. Like `render_response`, it is only invoked
+  /// when the body is actually required. Default is None.
+  pub render_response_stream: WebmachineCallback<'a, Option<BodyStream>>,
+  /// This is invoked to render the response body for error responses (4xx and 5xx status codes)
+  /// where a body has not already been set. The status code is available in `context.response.status`.
+  /// If this returns None for a '406 Not Acceptable' response due to the Accept-Encoding header, a
+  /// plain text body listing the `encodings_provided` is returned. Default is None.
+  pub render_error: WebmachineCallback<'a, Option<String>>,
+  /// If the length of the response body can be determined cheaply without rendering it (e.g.
+  /// the size of a file), this should return it. It will be used as the Content-Length header
+  /// for GET and HEAD requests, and allows HEAD requests to be answered without calling
+  /// `render_response`. Default is None.
+  pub body_length: WebmachineCallback<'a, Option<usize>>,
   /// Is the resource available? Returning false will result in a '503 Service Not Available'
-  /// response. Defaults to true. If the resource is only temporarily not available,
-  /// add a 'Retry-After' response header.
+  /// response. Defaults to true. If the resource is only temporarily not available, add a
+  /// 'Retry-After' response header with `context.response.set_retry_after`. A body for the 503
+  /// response (i.e. a maintenance page) can be provided with the `render_error` callback.
   pub available: WebmachineCallback<'a, bool>,
   /// HTTP methods that are known to the resource. Default includes all standard HTTP methods.
   /// One could override this to allow additional methods
@@ -161,35 +308,108 @@ pub struct WebmachineResource<'a> {
   pub uri_too_long: WebmachineCallback<'a, bool>,
   /// HTTP methods that are allowed on this resource. Defaults to GET','HEAD and 'OPTIONS'.
   pub allowed_methods: Vec<&'a str>,
+  /// If set, this is called to determine the HTTP methods that are allowed on this resource for
+  /// the current request, instead of using `allowed_methods`. Defaults to None.
+  pub allowed_methods_fn: Option<WebmachineCallback<'a, Vec<String>>>,
+  /// If false, HEAD requests will result in a '405 Method Not Allowed' response even if HEAD is
+  /// in the allowed methods (i.e. when calculating the response headers is expensive), and HEAD
+  /// will not be included in the Allow header. Defaults to true.
+  pub allow_head: bool,
+  /// If true, the `Allow` header returned for an OPTIONS request will list all the `known_methods`
+  /// instead of the `allowed_methods`. A '405 Method Not Allowed' response always lists only the
+  /// allowed methods. Defaults to false.
+  pub options_allow_known_methods: bool,
+  /// OPTIONS requests are handled before checking if the resource exists, so by default they
+  /// will return a '204 No Content' response for a nonexistent resource. If this is true,
+  /// `resource_exists` will be called for OPTIONS requests, and a '404 Not Found' response will
+  /// be returned if the resource does not exist. Defaults to false.
+  pub options_requires_existing_resource: bool,
   /// If the request is malformed, this should return true, which will result in a
   /// '400 Malformed Request' response. Defaults to false.
   pub malformed_request: WebmachineCallback<'a, bool>,
+  /// The names of the query parameters the resource accepts. This is only used if
+  /// `reject_unknown_query_params` is set. Defaults to an empty list.
+  pub allowed_query_params: Vec<&'a str>,
+  /// If true, a request with a query parameter that is not in `allowed_query_params` will result
+  /// in a '400 Malformed Request' response. Otherwise, unknown query parameters are ignored.
+  /// Default is false.
+  pub reject_unknown_query_params: bool,
   /// Is the client or request not authorized? Returning a Some<String>
   /// will result in a '401 Unauthorized' response.  Defaults to None. If a Some(String) is
   /// returned, the string will be used as the value in the WWW-Authenticate header.
   pub not_authorized: WebmachineCallback<'a, Option<String>>,
+  /// Async version of `not_authorized` (see `async_callback`), which is used instead of
+  /// `not_authorized` if set. Default is None.
+  pub not_authorized_async: Option<AsyncWebmachineCallback<'a, Option<String>>>,
   /// Is the request or client forbidden? Returning true will result in a '403 Forbidden' response.
   /// Defaults to false.
   pub forbidden: WebmachineCallback<'a, bool>,
+  /// Async version of `forbidden` (see `async_callback`), which is used instead of
+  /// `forbidden` if set. Default is None.
+  pub forbidden_async: Option<AsyncWebmachineCallback<'a, bool>>,
   /// If the request includes any invalid Content-* headers, this should return true, which will
   /// result in a '501 Not Implemented' response. Defaults to false.
   pub unsupported_content_headers: WebmachineCallback<'a, bool>,
   /// The list of acceptable content types. Defaults to 'application/json'. If the content type
-  /// of the request is not in this list, a '415 Unsupported Media Type' response is returned.
+  /// of a PUT, POST or PATCH request is not in this list, a '415 Unsupported Media Type' response
+  /// is returned.
   pub acceptable_content_types: Vec<&'a str>,
-  /// If the entity length on PUT or POST is invalid, this should return false, which will result
-  /// in a '413 Request Entity Too Large' response. Defaults to true.
+  /// If set, this is called to determine if the content type of a PUT, POST or PATCH request is
+  /// acceptable (i.e. to accept any `application/*+json` type), instead of using
+  /// `acceptable_content_types`. It should return false to reject the request with a
+  /// '415 Unsupported Media Type' response. Defaults to None.
+  pub content_types_accepted_fn: Option<WebmachineCallback<'a, bool>>,
+  /// If the entity length on PUT, POST or PATCH is invalid, this should return false, which will result
+  /// in a '413 Request Entity Too Large' response. The declared length of the body is available
+  /// from `context.request.content_length()`. Note that the body will have already been read by
+  /// the time this is called; use `max_body_size` to reject large bodies without reading them.
+  /// For requests with an `Expect: 100-continue` header, this is also called by the dispatcher
+  /// before the body is read, in which case `context.request.body` will be None.
+  /// Defaults to true.
   pub valid_entity_length: WebmachineCallback<'a, bool>,
-  /// This is called just before the final response is constructed and sent. This allows the
-  /// response to be modified. The default implementation adds CORS headers to the response
+  /// Maximum size of the request body in bytes. This is enforced by the dispatcher while reading
+  /// the body, so a '413 Request Entity Too Large' response is returned without buffering the
+  /// rest of the body once the limit is exceeded. Defaults to None (no limit).
+  pub max_body_size: Option<usize>,
+  /// If true, the `X-Content-Type-Options: nosniff` header is added to responses, and
+  /// `application/octet-stream` is used as the Content-Type if the media type is not known
+  /// (i.e. when serving user uploaded content as a download). Default is false.
+  pub prevent_content_sniffing: bool,
+  /// Escape hatch for advanced uses (i.e. upgrading the connection or Server-Sent Events). If set,
+  /// the dispatcher will pass the Hyper request (including the unread body) to this handler and
+  /// return the response it produces. None of the other callbacks will be called, and the state
+  /// machine will not be executed. Default is None.
+  pub raw_request_handler: Option<RawRequestHandler<'a>>,
+  /// This is called for every request once the state machine has finished executing, before the
+  /// response headers are finalised and the body is rendered. This allows the response to be
+  /// modified. The default implementation adds CORS headers to the response. As it is invoked
+  /// before the body is rendered, any values it sets on the context (i.e. a nonce that must also
+  /// appear in the body) can be used by `render_response`. It is followed by `finalise_response`,
+  /// which is invoked last.
   pub finish_request: WebmachineCallback<'a, ()>,
+  /// If CORS requests with an `Origin: null` header (i.e. from sandboxed iframes or `file://`
+  /// pages) are allowed. If false, the CORS headers are removed from the response for these
+  /// requests, and `null` is never returned in the `Access-Control-Allow-Origin` header. This is
+  /// applied before `finalise_response` is called. Default is false.
+  pub cors_allow_null_origin: bool,
   /// If the OPTIONS method is supported and is used, this returns a HashMap of headers that
-  /// should appear in the response. Defaults to CORS headers.
+  /// should appear in the response. Defaults to CORS headers for the allowed methods. An `Allow`
+  /// header listing the allowed methods is added to the response, unless one is returned from this
+  /// callback.
   pub options: WebmachineCallback<'a, Option<HashMap<String, Vec<String>>>>,
   /// The list of content types that this resource produces. Defaults to 'application/json'. If
   /// more than one is provided, and the client does not supply an Accept header, the first one
   /// will be selected.
   pub produces: Vec<&'a str>,
+  /// If true, when none of the content types the resource produces are acceptable to the client,
+  /// the first one will be used instead of returning a '406 Not Acceptable' response. Default is false.
+  pub negotiation_fallback_to_default: bool,
+  /// If true, a produced media type with a structured syntax suffix will satisfy an Accept header
+  /// for the suffix, as per RFC 6839 (i.e. `application/vnd.api+json` will be served for
+  /// `Accept: application/json`, and `application/hal+xml` for `Accept: application/xml`). This
+  /// is useful for JSON:API and HAL style resources. An exact match is always preferred. Default
+  /// is false.
+  pub media_type_suffix_matching: bool,
   /// The list of content languages that this resource provides. Defaults to an empty list,
   /// which represents all languages. If more than one is provided, and the client does not
   /// supply an Accept-Language header, the first one will be selected.
@@ -197,9 +417,17 @@ pub struct WebmachineResource<'a> {
   /// The list of charsets that this resource provides. Defaults to an empty list,
   /// which represents all charsets with ISO-8859-1 as the default. If more than one is provided,
   /// and the client does not supply an Accept-Charset header, the first one will be selected.
+  ///
+  /// If a charset is selected from the Accept-Charset header, the body returned from
+  /// `render_response` or `render_error` is transcoded to it. Only ISO-8859-1 and US-ASCII are
+  /// supported (characters that can not be represented are replaced with `?` and a warning is
+  /// logged), and the body is sent as UTF-8 for all other charsets, so only UTF-8 compatible
+  /// charsets should be provided in addition to those.
   pub charsets_provided: Vec<&'a str>,
-  /// The list of encodings your resource wants to provide. The encoding will be applied to the
-  /// response body automatically by Webmachine. Default includes only the 'identity' encoding.
+  /// The list of encodings your resource wants to provide. The selected encoding will be set in
+  /// `context.selected_encoding` and the `Content-Encoding` header, but the encoding is NOT applied
+  /// to the response body by Webmachine. You need to encode the body (i.e. in `render_response`)
+  /// using the selected encoding. Default includes only the 'identity' encoding.
   pub encodings_provided: Vec<&'a str>,
   /// The list of header names that should be included in the response's Vary header. The standard
   /// content negotiation headers (Accept, Accept-Encoding, Accept-Charset, Accept-Language) do
@@ -209,22 +437,64 @@ pub struct WebmachineResource<'a> {
   /// Does the resource exist? Returning a false value will result in a '404 Not Found' response
   /// unless it is a PUT or POST. Defaults to true.
   pub resource_exists: WebmachineCallback<'a, bool>,
+  /// Async version of `resource_exists` (see `async_callback`), which is used instead of
+  /// `resource_exists` if set. Default is None.
+  pub resource_exists_async: Option<AsyncWebmachineCallback<'a, bool>>,
   /// If this resource is known to have existed previously, this should return true. Default is false.
+  ///
+  /// Returning true for a resource that does not exist results in a '410 Gone' response for GET
+  /// requests. To return '410 Gone' for resources that have been deleted, `delete_resource` can
+  /// record a tombstone for the deleted resource which is then checked by this callback:
+  ///
+  /// ```
+  /// use std::collections::HashSet;
+  /// use std::sync::{Arc, Mutex};
+  /// use webmachine_rust::builder::WebmachineResourceBuilder;
+  ///
+  /// let items = Arc::new(Mutex::new(HashSet::from(["/1".to_string()])));
+  /// let tombstones = Arc::new(Mutex::new(HashSet::new()));
+  /// let (exists_items, delete_items) = (items.clone(), items.clone());
+  /// let (delete_tombstones, existed_tombstones) = (tombstones.clone(), tombstones.clone());
+  /// let resource = WebmachineResourceBuilder::new()
+  ///   .allowed_methods(&["GET", "DELETE"])
+  ///   .resource_exists(move |context, _| exists_items.lock().unwrap().contains(&context.request.request_path))
+  ///   .delete_resource(move |context, _| {
+  ///     delete_items.lock().unwrap().remove(&context.request.request_path);
+  ///     delete_tombstones.lock().unwrap().insert(context.request.request_path.clone());
+  ///     Ok(true)
+  ///   })
+  ///   .previously_existed(move |context, _| existed_tombstones.lock().unwrap().contains(&context.request.request_path))
+  ///   .build();
+  /// ```
   pub previously_existed: WebmachineCallback<'a, bool>,
   /// If this resource has moved to a new location permanently, this should return the new
   /// location as a String. Default is to return None
   pub moved_permanently: WebmachineCallback<'a, Option<String>>,
+  /// Async version of `moved_permanently` (see `async_callback`), which is used instead of
+  /// `moved_permanently` if set. Default is None.
+  pub moved_permanently_async: Option<AsyncWebmachineCallback<'a, Option<String>>>,
   /// If this resource has moved to a new location temporarily, this should return the new
   /// location as a String. Default is to return None
   pub moved_temporarily: WebmachineCallback<'a, Option<String>>,
+  /// Async version of `moved_temporarily` (see `async_callback`), which is used instead of
+  /// `moved_temporarily` if set. Default is None.
+  pub moved_temporarily_async: Option<AsyncWebmachineCallback<'a, Option<String>>>,
   /// If this returns true, the client will receive a '409 Conflict' response. This is only
   /// called for PUT requests. Default is false.
   pub is_conflict: WebmachineCallback<'a, bool>,
+  /// Async version of `is_conflict` (see `async_callback`), which is used instead of
+  /// `is_conflict` if set. Default is None.
+  pub is_conflict_async: Option<AsyncWebmachineCallback<'a, bool>>,
   /// Return true if the resource accepts POST requests to nonexistent resources. Defaults to false.
   pub allow_missing_post: WebmachineCallback<'a, bool>,
   /// If this returns a value, it will be used as the value of the ETag header and for
-  /// comparison in conditional requests. Default is None.
+  /// comparison in conditional requests. The value will be quoted, unless it is a weak entity tag
+  /// (i.e. `W/"1234"`). If-Match uses the strong comparison, so never matches a weak entity tag,
+  /// while If-None-Match uses the weak comparison. Default is None.
   pub generate_etag: WebmachineCallback<'a, Option<String>>,
+  /// Async version of `generate_etag` (see `async_callback`), which is used instead of
+  /// `generate_etag` if set. Default is None.
+  pub generate_etag_async: Option<AsyncWebmachineCallback<'a, Option<String>>>,
   /// Returns the last modified date and time of the resource which will be added as the
   /// Last-Modified header in the response and used in negotiating conditional requests.
   /// Default is None
@@ -232,20 +502,36 @@ pub struct WebmachineResource<'a> {
   /// Called when a DELETE request should be enacted. Return `Ok(true)` if the deletion succeeded,
   /// and `Ok(false)` if the deletion was accepted but cannot yet be guaranteed to have finished.
   /// If the delete fails for any reason, return an Err with the status code you wish returned
-  /// (a 500 status makes sense).
+  /// (a 500 status makes sense) and optionally a response body (see `WebmachineError`).
   /// Defaults to `Ok(true)`.
-  pub delete_resource: WebmachineCallback<'a, Result<bool, u16>>,
+  pub delete_resource: WebmachineCallback<'a, Result<bool, WebmachineError>>,
+  /// Async version of `delete_resource` (see `async_callback`), which is used instead of
+  /// `delete_resource` if set. Default is None.
+  pub delete_resource_async: Option<AsyncWebmachineCallback<'a, Result<bool, WebmachineError>>>,
+  /// If true, a successful DELETE request will always result in a '204 No Content' response, and
+  /// any body set by `delete_resource` will be discarded. Otherwise, a '200 OK' response is
+  /// returned if a body was set. Default is false.
+  pub delete_returns_no_content: bool,
   /// If POST requests should be treated as a request to put content into a (potentially new)
   /// resource as opposed to a generic submission for processing, then this should return true.
   /// If it does return true, then `create_path` will be called and the rest of the request will
   /// be treated much like a PUT to the path returned by that call. Default is false.
   pub post_is_create: WebmachineCallback<'a, bool>,
   /// If `post_is_create` returns false, then this will be called to process any POST request.
-  /// If it succeeds, return `Ok(true)`, `Ok(false)` otherwise. If it fails for any reason,
+  /// If it succeeds, return `Ok(true)`, `Ok(false)` otherwise (see `post_not_processed_status`
+  /// for the response returned for `Ok(false)`). If it fails for any reason,
   /// return an Err with the status code you wish returned (e.g., a 500 status makes sense).
-  /// Default is false. If you want the result of processing the POST to be a redirect, set
-  /// `context.redirect` to true.
-  pub process_post: WebmachineCallback<'a, Result<bool, u16>>,
+  /// Default is false. If you want the result of processing the POST to be a redirect (a
+  /// '303 See Other' response), call `context.redirect_to` with the location to redirect to.
+  pub process_post: WebmachineCallback<'a, Result<bool, WebmachineError>>,
+  /// Async version of `process_post` (see `async_callback`), which is used instead of
+  /// `process_post` if set. Default is None.
+  pub process_post_async: Option<AsyncWebmachineCallback<'a, Result<bool, WebmachineError>>>,
+  /// The status code to return if `process_post` returns `Ok(false)` (i.e. 422 if the POST could
+  /// not be processed). If this is None, `Ok(false)` is treated the same as `Ok(true)`, and the
+  /// response will be a '204 No Content' or '200 OK' depending on whether a body was set.
+  /// Default is None.
+  pub post_not_processed_status: Option<u16>,
   /// This will be called on a POST request if `post_is_create` returns true. It should create
   /// the new resource and return the path as a valid URI part following the dispatcher prefix.
   /// That path will replace the previous one in the return value of `WebmachineRequest.request_path`
@@ -253,18 +539,74 @@ pub struct WebmachineResource<'a> {
   /// as the value of the Location header of the response. If it fails for any reason,
   /// return an Err with the status code you wish returned (e.g., a 500 status makes sense).
   /// Default will return an `Ok(WebmachineRequest.request_path)`. If you want the result of
-  /// processing the POST to be a redirect, set `context.redirect` to true.
-  pub create_path: WebmachineCallback<'a, Result<String, u16>>,
+  /// processing the POST to be a redirect (a '303 See Other' response), set `context.redirect`
+  /// to true to redirect to the new path, or call `context.redirect_to` with a different location.
+  pub create_path: WebmachineCallback<'a, Result<String, WebmachineError>>,
+  /// Async version of `create_path` (see `async_callback`), which is used instead of
+  /// `create_path` if set. Default is None.
+  pub create_path_async: Option<AsyncWebmachineCallback<'a, Result<String, WebmachineError>>>,
   /// This will be called to process any PUT request. If it succeeds, return `Ok(true)`,
   /// `Ok(false)` otherwise. If it fails for any reason, return an Err with the status code
-  /// you wish returned (e.g., a 500 status makes sense). Default is `Ok(true)`
-  pub process_put: WebmachineCallback<'a, Result<bool, u16>>,
+  /// you wish returned (e.g., a 500 status makes sense). Default is `Ok(true)`. For partial
+  /// updates, the range from the Content-Range header is available from
+  /// `context.request.content_range()` (a malformed Content-Range header will result in a
+  /// '400 Bad Request' response). If the PUT created a new resource, a '201 Created' response is
+  /// returned with a Location header for the request path (or the location set with
+  /// `context.redirect_to`), and any body set on the response by this callback.
+  pub process_put: WebmachineCallback<'a, Result<bool, WebmachineError>>,
+  /// Async version of `process_put` (see `async_callback`), which is used instead of
+  /// `process_put` if set. Default is None.
+  pub process_put_async: Option<AsyncWebmachineCallback<'a, Result<bool, WebmachineError>>>,
+  /// This will be called to process any PATCH request to an existing resource. PATCH requests are
+  /// handled after the DELETE, POST and PUT branches of the state machine, and like PUT requests
+  /// will first check `is_conflict`. If it succeeds, return `Ok(true)`, `Ok(false)` otherwise. If it
+  /// fails for any reason, return an Err with the status code you wish returned (e.g., a 422 status
+  /// for a patch document that can not be applied). PATCH requests to a resource that does not
+  /// exist will result in a '404 Not Found' response. Default is `Ok(true)`.
+  pub process_patch: WebmachineCallback<'a, Result<bool, WebmachineError>>,
+  /// Async version of `process_patch` (see `async_callback`), which is used instead of
+  /// `process_patch` if set. Default is None.
+  pub process_patch_async: Option<AsyncWebmachineCallback<'a, Result<bool, WebmachineError>>>,
   /// If this returns true, then it is assumed that multiple representations of the response are
   /// possible and a single one cannot be automatically chosen, so a 300 Multiple Choices will
   /// be sent instead of a 200. Default is false.
   pub multiple_choices: WebmachineCallback<'a, bool>,
+  /// If the resource supports datetime negotiation with the Memento framework (RFC 7089), this
+  /// should return the URI of the timegate for the resource. Requests with an Accept-Datetime
+  /// header will then be redirected to the timegate with a '302 Found' response, with Link headers
+  /// for the original resource and the timegate, and a Memento-Datetime header with the date
+  /// returned by `last_modified` (if there is one). Default is None.
+  pub memento_timegate: WebmachineCallback<'a, Option<String>>,
   /// If the resource expires, this should return the date/time it expires. Default is None.
-  pub expires: WebmachineCallback<'a, Option<DateTime<FixedOffset>>>
+  pub expires: WebmachineCallback<'a, Option<DateTime<FixedOffset>>>,
+  /// If the resource supports byte range requests. If true, an `Accept-Ranges: bytes` header
+  /// will be added to GET responses, and a GET request with a Range header will result in a
+  /// '206 Partial Content' response with the requested range of the rendered body. If more than
+  /// one range is requested, the ranges are returned as a `multipart/byteranges` body, with
+  /// each part having its own Content-Type and Content-Range headers. Overlapping and adjacent
+  /// ranges are coalesced, and if there are still more than `range::MAX_RANGES` ranges, the
+  /// Range header is ignored and the full body is returned. If the request has an
+  /// If-Range header that does not match the ETag or Last-Modified date of the response, the
+  /// range is ignored and the full body is returned. Default is false.
+  pub accept_ranges: bool,
+  /// If true, PUT and DELETE requests must be conditional (have an If-Match or If-Unmodified-Since
+  /// header), otherwise a '428 Precondition Required' response is returned. Default is false.
+  pub require_conditional_for_writes: bool,
+  /// If the resource supports handling the WebDAV If header (RFC 4918). If false, requests with
+  /// an If header will result in a '501 Not Implemented' response, as the preconditions in the
+  /// header can not be evaluated. Default is false.
+  pub supports_webdav_if: bool
+}
+
+fn unknown_query_param(context: &WebmachineContext, resource: &WebmachineResource) -> Option<String> {
+  if resource.reject_unknown_query_params {
+    context.request.query.keys()
+      .sorted()
+      .find(|name| !resource.allowed_query_params.contains(&name.as_str()))
+      .cloned()
+  } else {
+    None
+  }
 }
 
 fn true_fn(_: &mut WebmachineContext, _: &WebmachineResource) -> bool {
@@ -287,35 +629,137 @@ impl <'a> Default for WebmachineResource<'a> {
       known_methods: vec!["OPTIONS", "GET", "POST", "PUT", "DELETE", "HEAD", "TRACE", "CONNECT", "PATCH"],
       uri_too_long: callback(&false_fn),
       allowed_methods: vec!["OPTIONS", "GET", "HEAD"],
+      allowed_methods_fn: None,
+      allow_head: true,
+      options_allow_known_methods: false,
+      options_requires_existing_resource: false,
       malformed_request: callback(&false_fn),
+      allowed_query_params: Vec::new(),
+      reject_unknown_query_params: false,
       not_authorized: callback(&none_fn),
+      not_authorized_async: None,
       forbidden: callback(&false_fn),
+      forbidden_async: None,
       unsupported_content_headers: callback(&false_fn),
       acceptable_content_types: vec!["application/json"],
+      content_types_accepted_fn: None,
       valid_entity_length: callback(&true_fn),
+      max_body_size: None,
+      prevent_content_sniffing: false,
+      raw_request_handler: None,
       finish_request: callback(&|context, resource| context.response.add_cors_headers(&resource.allowed_methods)),
-      options: callback(&|_, resource| Some(WebmachineResponse::cors_headers(&resource.allowed_methods))),
+      cors_allow_null_origin: false,
+      options: callback(&|context, resource| {
+        let methods = allowed_methods(context, resource);
+        Some(WebmachineResponse::cors_headers(&methods.iter().map(|m| m.as_str()).collect()))
+      }),
       produces: vec!["application/json"],
+      negotiation_fallback_to_default: false,
+      media_type_suffix_matching: false,
       languages_provided: Vec::new(),
       charsets_provided: Vec::new(),
       encodings_provided: vec!["identity"],
       variances: Vec::new(),
       resource_exists: callback(&true_fn),
+      resource_exists_async: None,
       previously_existed: callback(&false_fn),
       moved_permanently: callback(&none_fn),
+      moved_permanently_async: None,
       moved_temporarily: callback(&none_fn),
+      moved_temporarily_async: None,
       is_conflict: callback(&false_fn),
+      is_conflict_async: None,
       allow_missing_post: callback(&false_fn),
       generate_etag: callback(&none_fn),
+      generate_etag_async: None,
       last_modified: callback(&none_fn),
       delete_resource: callback(&|_, _| Ok(true)),
+      delete_resource_async: None,
+      delete_returns_no_content: false,
       post_is_create: callback(&false_fn),
       process_post: callback(&|_, _| Ok(false)),
+      process_post_async: None,
+      post_not_processed_status: None,
       process_put: callback(&|_, _| Ok(true)),
+      process_put_async: None,
+      process_patch: callback(&|_, _| Ok(true)),
+      process_patch_async: None,
       multiple_choices: callback(&false_fn),
       create_path: callback(&|context, _| Ok(context.request.request_path.clone())),
+      create_path_async: None,
+      memento_timegate: callback(&none_fn),
       expires: callback(&none_fn),
-      render_response: callback(&none_fn)
+      accept_ranges: false,
+      require_conditional_for_writes: false,
+      supports_webdav_if: false,
+      render_response: callback(&none_fn),
+      render_response_async: None,
+      render_response_lazy: None,
+      render_response_stream: callback(&none_fn),
+      render_error: callback(&none_fn),
+      body_length: callback(&none_fn)
+    }
+  }
+}
+
+impl <'a> WebmachineResource<'a> {
+  /// Adds the methods to the known methods of the resource (i.e. a custom `PURGE` method),
+  /// keeping the existing ones
+  pub fn with_additional_known_methods(mut self, methods: &[&'a str]) -> Self {
+    add_methods(&mut self.known_methods, methods);
+    self
+  }
+
+  /// Adds the methods to the allowed methods of the resource, keeping the existing ones
+  pub fn with_additional_allowed_methods(mut self, methods: &[&'a str]) -> Self {
+    add_methods(&mut self.allowed_methods, methods);
+    self
+  }
+
+  /// Sets the callbacks to generate the ETag for the resource and to render the response. GET and
+  /// HEAD requests with an If-None-Match header matching the ETag will result in a
+  /// '304 Not Modified' response without the response being rendered, and the ETag header will be
+  /// returned with any other successful GET responses.
+  pub fn cacheable(
+    mut self,
+    generate_etag: WebmachineCallback<'a, Option<String>>,
+    render_response: WebmachineCallback<'a, Option<String>>
+  ) -> Self {
+    self.generate_etag = generate_etag;
+    self.render_response = render_response;
+    self
+  }
+
+  /// Sets the media types the resource produces, along with the callback to render the response
+  /// for each one. The `render_response` callback is replaced with one that invokes the callback
+  /// for the media type selected by content negotiation (or the first one if the request did not
+  /// have an Accept header).
+  ///
+  /// ```
+  /// use webmachine_rust::{callback, WebmachineResource};
+  ///
+  /// let resource = WebmachineResource::default().produces_map(vec![
+  ///   ("application/json", callback(&|_, _| Some("{\"id\": 1}".to_string()))),
+  ///   ("application/xml", callback(&|_, _| Some("<item id=\"1\"/>".to_string())))
+  /// ]);
+  /// ```
+  pub fn produces_map(mut self, renderers: Vec<(&'a str, WebmachineCallback<'a, Option<String>>)>) -> Self {
+    self.produces = renderers.iter().map(|(media_type, _)| *media_type).collect();
+    self.render_response = owned_callback(move |context: &mut WebmachineContext, resource: &WebmachineResource| {
+      let renderer = match &context.selected_media_type {
+        Some(selected) => renderers.iter().find(|(media_type, _)| media_type.eq_ignore_ascii_case(selected)),
+        None => renderers.first()
+      };
+      renderer.and_then(|(_, callback)| callback.lock().unwrap().deref()(context, resource))
+    });
+    self
+  }
+}
+
+fn add_methods<'a>(existing: &mut Vec<&'a str>, methods: &[&'a str]) {
+  for method in methods {
+    if !existing.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+      existing.push(method);
     }
   }
 }
@@ -324,10 +768,50 @@ fn sanitise_path(path: &str) -> Vec<String> {
   path.split("/").filter(|p| !p.is_empty()).map(|p| p.to_string()).collect()
 }
 
-fn join_paths(base: &Vec<String>, path: &Vec<String>) -> String {
-  let mut paths = base.clone();
+fn is_path_param(segment: &str) -> bool {
+  segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+fn is_splat(segment: &str) -> bool {
+  segment.len() > 1 && segment.starts_with('*')
+}
+
+/// Splits a trailing `*name` splat segment off the route, returning the remaining route segments
+/// and the name of the splat parameter
+fn split_splat(route: &[String]) -> (&[String], Option<&str>) {
+  match route.split_last() {
+    Some((last, rest)) if is_splat(last) => (rest, Some(&last[1..])),
+    _ => (route, None)
+  }
+}
+
+/// Matches the route against the start of the request path, segment by segment. `{name}` segments
+/// in the route will match any request path segment, and a trailing `*name` segment will match the
+/// rest of the request path (which may be empty). Returns the values of the matched parameters if
+/// the route matches.
+fn match_route(route: &[String], request_path: &[String]) -> Option<HashMap<String, String>> {
+  let (route, splat) = split_splat(route);
+  if route.len() > request_path.len() {
+    return None;
+  }
+  let mut params = HashMap::new();
+  for (route_segment, path_segment) in route.iter().zip(request_path) {
+    if is_path_param(route_segment) {
+      params.insert(route_segment[1..route_segment.len() - 1].to_string(), path_segment.clone());
+    } else if route_segment != path_segment {
+      return None;
+    }
+  }
+  if let Some(name) = splat {
+    params.insert(name.to_string(), request_path[route.len()..].join("/"));
+  }
+  Some(params)
+}
+
+fn join_paths(base: &[String], path: &[String]) -> String {
+  let mut paths = base.to_vec();
   paths.extend_from_slice(path);
-  let filtered: Vec<String> = paths.iter().cloned().filter(|p| !p.is_empty()).collect();
+  let filtered: Vec<String> = paths.iter().filter(|&p| !p.is_empty()).cloned().collect();
   if filtered.is_empty() {
     "/".to_string()
   } else {
@@ -340,7 +824,8 @@ fn join_paths(base: &Vec<String>, path: &Vec<String>) -> String {
   }
 }
 
-const MAX_STATE_MACHINE_TRANSITIONS: u8 = 100;
+/// Default maximum number of transitions the state machine can make before the request is failed
+pub const MAX_STATE_MACHINE_TRANSITIONS: usize = 100;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Decision {
@@ -366,6 +851,8 @@ enum Decision {
     E6AcceptableCharsetAvailable,
     F6AcceptEncodingExists,
     F7AcceptableEncodingAvailable,
+    G4MementoTimegate,
+    G6PreconditionRequired,
     G7ResourceExists,
     G8IfMatchExists,
     G9IfMatchStarExists,
@@ -397,6 +884,7 @@ enum Decision {
     N16Post,
     O14Conflict,
     O16Put,
+    O17Patch,
     O18MultipleRepresentations,
     O20ResponseHasBody,
     P3Conflict,
@@ -405,11 +893,7 @@ enum Decision {
 
 impl Decision {
     fn is_terminal(&self) -> bool {
-        match self {
-            &Decision::End(_) => true,
-            &Decision::A3Options => true,
-            _ => false
-        }
+        matches!(self, Decision::End(_) | Decision::A3Options)
     }
 }
 
@@ -455,8 +939,10 @@ lazy_static! {
         Decision::D5AcceptableLanguageAvailable => Transition::Branch(Decision::E5AcceptCharsetExists, Decision::End(406)),
         Decision::E5AcceptCharsetExists => Transition::Branch(Decision::E6AcceptableCharsetAvailable, Decision::F6AcceptEncodingExists),
         Decision::E6AcceptableCharsetAvailable => Transition::Branch(Decision::F6AcceptEncodingExists, Decision::End(406)),
-        Decision::F6AcceptEncodingExists => Transition::Branch(Decision::F7AcceptableEncodingAvailable, Decision::G7ResourceExists),
-        Decision::F7AcceptableEncodingAvailable => Transition::Branch(Decision::G7ResourceExists, Decision::End(406)),
+        Decision::F6AcceptEncodingExists => Transition::Branch(Decision::F7AcceptableEncodingAvailable, Decision::G6PreconditionRequired),
+        Decision::F7AcceptableEncodingAvailable => Transition::Branch(Decision::G6PreconditionRequired, Decision::End(406)),
+        Decision::G4MementoTimegate => Transition::Branch(Decision::End(302), Decision::G7ResourceExists),
+        Decision::G6PreconditionRequired => Transition::Branch(Decision::End(428), Decision::G4MementoTimegate),
         Decision::G7ResourceExists => Transition::Branch(Decision::G8IfMatchExists, Decision::H7IfMatchStarExists),
         Decision::G8IfMatchExists => Transition::Branch(Decision::G9IfMatchStarExists, Decision::H10IfUnmodifiedSinceExists),
         Decision::G9IfMatchStarExists => Transition::Branch(Decision::H10IfUnmodifiedSinceExists, Decision::G11EtagInIfMatch),
@@ -487,7 +973,8 @@ lazy_static! {
         Decision::N11Redirect => Transition::Branch(Decision::End(303), Decision::P11NewResource),
         Decision::N16Post => Transition::Branch(Decision::N11Redirect, Decision::O16Put),
         Decision::O14Conflict => Transition::Branch(Decision::End(409), Decision::P11NewResource),
-        Decision::O16Put => Transition::Branch(Decision::O14Conflict, Decision::O18MultipleRepresentations),
+        Decision::O16Put => Transition::Branch(Decision::O14Conflict, Decision::O17Patch),
+        Decision::O17Patch => Transition::Branch(Decision::O14Conflict, Decision::O18MultipleRepresentations),
         Decision::P3Conflict => Transition::Branch(Decision::End(409), Decision::P11NewResource),
         Decision::P11NewResource => Transition::Branch(Decision::End(201), Decision::O20ResponseHasBody),
         Decision::O18MultipleRepresentations => Transition::Branch(Decision::End(300), Decision::End(200)),
@@ -495,22 +982,38 @@ lazy_static! {
     };
 }
 
-fn resource_etag_matches_header_values(
-  resource: &WebmachineResource,
+/// HTTP dates only have second precision, so any sub-second component of a date needs to be
+/// removed before comparing it to a date from a header
+fn truncate_to_seconds(datetime: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+  datetime.with_nanosecond(0).unwrap_or(datetime)
+}
+
+/// Splits an entity tag into whether it is weak and its opaque value (without the quotes)
+fn parse_etag(etag: &HeaderValue) -> (bool, String) {
+  match etag.weak_etag() {
+    Some(value) => (true, value),
+    None => (false, etag.value.clone())
+  }
+}
+
+/// If the ETag of the resource matches any of the entity tags in the header. With the strong
+/// comparison (used for If-Match), both entity tags must not be weak and must have the same
+/// value, while with the weak comparison (used for If-None-Match) only the values must be the
+/// same. See RFC 7232 section 2.3.2.
+async fn resource_etag_matches_header_values(
+  resource: &WebmachineResource<'_>,
   context: &mut WebmachineContext,
-  header: &str
+  header: &str,
+  strong: bool
 ) -> bool {
   let header_values = context.request.find_header(header);
-  let callback = resource.generate_etag.lock().unwrap();
-  match callback.deref()(context, resource) {
+  match invoke_callback(&resource.generate_etag, &resource.generate_etag_async, context, resource).await {
     Some(etag) => {
-      header_values.iter().find(|val| {
-        if val.value.starts_with("W/") {
-          val.weak_etag().unwrap() == etag
-        } else {
-          val.value == etag
-        }
-      }).is_some()
+      let (etag_weak, etag) = parse_etag(&HeaderValue::basic(etag));
+      header_values.iter().any(|val| {
+        let (weak, value) = parse_etag(val);
+        value == etag && !(strong && (weak || etag_weak))
+      })
     },
     None => false
   }
@@ -522,38 +1025,65 @@ fn validate_header_date(
   context_meta: &mut Option<DateTime<FixedOffset>>
 ) -> bool {
   let header_values = request.find_header(header);
-  if let Some(date_value) = header_values.first() {
-    match DateTime::parse_from_rfc2822(&date_value.value) {
-      Ok(datetime) => {
-        *context_meta = Some(datetime.clone());
+  if header_values.is_empty() {
+    false
+  } else {
+    // HTTP dates contain a comma, so will have been split into multiple header values
+    let date_value = header_values.iter().map(|value| value.value.as_str()).join(", ");
+    match parse_http_date(&date_value) {
+      Some(datetime) => {
+        *context_meta = Some(datetime);
         true
       },
-      Err(err) => {
-        debug!("Failed to parse '{}' header value '{:?}' - {}", header, date_value, err);
+      None => {
+        debug!("Failed to parse '{}' header value '{}'", header, date_value);
         false
       }
     }
+  }
+}
+
+fn allowed_methods(context: &mut WebmachineContext, resource: &WebmachineResource) -> Vec<String> {
+  let methods: Vec<String> = match &resource.allowed_methods_fn {
+    Some(callback) => callback.lock().unwrap().deref()(context, resource),
+    None => resource.allowed_methods.iter().map(|m| m.to_string()).collect()
+  };
+  if resource.allow_head {
+    methods
   } else {
-    false
+    methods.into_iter().filter(|m| !m.eq_ignore_ascii_case("HEAD")).collect()
   }
 }
 
-fn execute_decision(
+fn error_result(context: &mut WebmachineContext, error: WebmachineError) -> DecisionResult {
+  if let Some(body) = error.body() {
+    context.response.body = Some(body.as_bytes().to_vec());
+  }
+  DecisionResult::StatusCode(error.status())
+}
+
+async fn execute_decision(
   decision: &Decision,
   context: &mut WebmachineContext,
-  resource: &WebmachineResource
+  resource: &WebmachineResource<'_>
 ) -> DecisionResult {
   match decision {
     Decision::B10MethodAllowed => {
-      match resource.allowed_methods
+      let allowed_methods = allowed_methods(context, resource);
+      match allowed_methods
         .iter().find(|m| m.to_uppercase() == context.request.method.to_uppercase()) {
         Some(_) => DecisionResult::True("method is in the list of allowed methods".to_string()),
         None => {
-          context.response.add_header("Allow", resource.allowed_methods
+          context.response.add_header("Allow", allowed_methods
             .iter()
-            .cloned()
             .map(HeaderValue::basic)
             .collect());
+          if allowed_methods.iter().any(|m| m.eq_ignore_ascii_case("OPTIONS")) {
+            // Point the client at an OPTIONS request on the resource for discovery
+            let path = join_paths(&sanitise_path(&context.request.base_path),
+              &sanitise_path(&context.request.request_path));
+            context.response.add_header("Link", vec![HeaderValue::basic(format!("<{}>; rel=\"describedby\"", path))]);
+          }
           DecisionResult::False("method is not in the list of allowed methods".to_string())
         }
       }
@@ -569,13 +1099,17 @@ fn execute_decision(
       let callback = resource.available.lock().unwrap();
       DecisionResult::wrap(callback.deref()(context, resource), "available")
     },
-    Decision::B9MalformedRequest => {
+    Decision::B9MalformedRequest => if context.request.is_put() && context.request.has_header("Content-Range")
+      && context.request.content_range().is_none() {
+      DecisionResult::True("Content-Range header is malformed".to_string())
+    } else if let Some(param) = unknown_query_param(context, resource) {
+      DecisionResult::True(format!("query parameter '{}' is not allowed", param))
+    } else {
       let callback = resource.malformed_request.lock().unwrap();
       DecisionResult::wrap(callback.deref()(context, resource), "malformed request")
     },
     Decision::B8Authorized => {
-      let callback = resource.not_authorized.lock().unwrap();
-      match callback.deref()(context, resource) {
+      match invoke_callback(&resource.not_authorized, &resource.not_authorized_async, context, resource).await {
         Some(realm) => {
           context.response.add_header("WWW-Authenticate", vec![HeaderValue::parse_string(realm.as_str())]);
           DecisionResult::False("is not authorized".to_string())
@@ -584,35 +1118,50 @@ fn execute_decision(
       }
     },
     Decision::B7Forbidden => {
-      let callback = resource.forbidden.lock().unwrap();
-      DecisionResult::wrap(callback.deref()(context, resource), "forbidden")
+      DecisionResult::wrap(invoke_callback(&resource.forbidden, &resource.forbidden_async, context, resource).await,
+        "forbidden")
     },
     Decision::B6UnsupportedContentHeader => {
-      let callback = resource.unsupported_content_headers.lock().unwrap();
-      DecisionResult::wrap(callback.deref()(context, resource), "unsupported content headers")
+      if !resource.supports_webdav_if && context.request.has_header("If") {
+        DecisionResult::True("has a WebDAV If header which is not supported".to_string())
+      } else {
+        let callback = resource.unsupported_content_headers.lock().unwrap();
+        DecisionResult::wrap(callback.deref()(context, resource), "unsupported content headers")
+      }
     },
-    Decision::B5UnknownContentType => {
-      DecisionResult::wrap(context.request.is_put_or_post() && resource.acceptable_content_types
+    Decision::B5UnknownContentType => if !context.request.has_body_method() {
+      DecisionResult::False("acceptable content types".to_string())
+    } else if let Some(callback) = &resource.content_types_accepted_fn {
+      let callback = callback.lock().unwrap();
+      DecisionResult::wrap(!callback.deref()(context, resource), "acceptable content types")
+    } else {
+      DecisionResult::wrap(resource.acceptable_content_types
         .iter().find(|ct| context.request.content_type().to_uppercase() == ct.to_uppercase() )
         .is_none(), "acceptable content types")
     },
     Decision::B4RequestEntityTooLarge => {
       let callback = resource.valid_entity_length.lock().unwrap();
-      DecisionResult::wrap(context.request.is_put_or_post() && !callback.deref()(context, resource),
+      DecisionResult::wrap(context.request.has_body_method() && !callback.deref()(context, resource),
         "valid entity length")
     },
     Decision::B3Options => DecisionResult::wrap(context.request.is_options(), "options"),
     Decision::C3AcceptExists => DecisionResult::wrap(context.request.has_accept_header(), "has accept header"),
-    Decision::C4AcceptableMediaTypeAvailable => match content_negotiation::matching_content_type(resource, &context.request) {
+    Decision::C4AcceptableMediaTypeAvailable => match context.parse_accept_headers().matching_content_type(resource) {
       Some(media_type) => {
         context.selected_media_type = Some(media_type);
         DecisionResult::True("acceptable media type is available".to_string())
       },
-      None => DecisionResult::False("acceptable media type is not available".to_string())
+      None => match resource.produces.first().filter(|_| resource.negotiation_fallback_to_default) {
+        Some(media_type) => {
+          context.selected_media_type = Some(media_type.to_string());
+          DecisionResult::True("acceptable media type is not available, falling back to the default".to_string())
+        },
+        None => DecisionResult::False("acceptable media type is not available".to_string())
+      }
     },
     Decision::D4AcceptLanguageExists => DecisionResult::wrap(context.request.has_accept_language_header(),
                                                              "has accept language header"),
-    Decision::D5AcceptableLanguageAvailable => match content_negotiation::matching_language(resource, &context.request) {
+    Decision::D5AcceptableLanguageAvailable => match context.parse_accept_headers().matching_language(resource) {
       Some(language) => {
         if language != "*" {
           context.selected_language = Some(language.clone());
@@ -624,7 +1173,7 @@ fn execute_decision(
     },
     Decision::E5AcceptCharsetExists => DecisionResult::wrap(context.request.has_accept_charset_header(),
                                                             "accept charset exists"),
-    Decision::E6AcceptableCharsetAvailable => match content_negotiation::matching_charset(resource, &context.request) {
+    Decision::E6AcceptableCharsetAvailable => match context.parse_accept_headers().matching_charset(resource) {
       Some(charset) => {
         if charset != "*" {
             context.selected_charset = Some(charset.clone());
@@ -635,7 +1184,7 @@ fn execute_decision(
     },
     Decision::F6AcceptEncodingExists => DecisionResult::wrap(context.request.has_accept_encoding_header(),
                                                              "accept encoding exists"),
-    Decision::F7AcceptableEncodingAvailable => match content_negotiation::matching_encoding(resource, &context.request) {
+    Decision::F7AcceptableEncodingAvailable => match context.parse_accept_headers().matching_encoding(resource) {
       Some(encoding) => {
         context.selected_encoding = Some(encoding.clone());
         if encoding != "identity" {
@@ -643,17 +1192,46 @@ fn execute_decision(
         }
         DecisionResult::True("acceptable encoding is available".to_string())
       },
-      None => DecisionResult::False("acceptable encoding is not available".to_string())
+      None => {
+        context.insert(NoAcceptableEncoding);
+        DecisionResult::False("acceptable encoding is not available".to_string())
+      }
+    },
+    Decision::G6PreconditionRequired => DecisionResult::wrap(resource.require_conditional_for_writes &&
+      (context.request.is_put() || context.request.is_delete()) &&
+      !context.request.has_header("If-Match") && !context.request.has_header("If-Unmodified-Since"),
+      "precondition required"),
+    Decision::G4MementoTimegate => if context.request.has_header("Accept-Datetime") {
+      let callback = resource.memento_timegate.lock().unwrap();
+      match callback.deref()(context, resource) {
+        Some(timegate) => {
+          let original = join_paths(&sanitise_path(&context.request.base_path),
+            &sanitise_path(&context.request.request_path));
+          context.response.add_header("Location", vec![HeaderValue::basic(&timegate)]);
+          context.response.add_header("Link", vec![
+            HeaderValue::basic(format!("<{}>; rel=\"original\"", original)),
+            HeaderValue::basic(format!("<{}>; rel=\"timegate\"", timegate))
+          ]);
+          context.response.add_header("Vary", vec![h!("Accept-Datetime")]);
+          if let Some(datetime) = resource.last_modified.lock().unwrap().deref()(context, resource) {
+            context.response.add_header("Memento-Datetime", vec![HeaderValue::basic(format_http_date(&datetime))]);
+          }
+          DecisionResult::True("resource has a Memento timegate".to_string())
+        },
+        None => DecisionResult::False("resource does not have a Memento timegate".to_string())
+      }
+    } else {
+      DecisionResult::False("does not have an Accept-Datetime header".to_string())
     },
     Decision::G7ResourceExists => {
-      let callback = resource.resource_exists.lock().unwrap();
-      DecisionResult::wrap(callback.deref()(context, resource), "resource exists")
+      let exists = invoke_callback(&resource.resource_exists, &resource.resource_exists_async, context, resource).await;
+      DecisionResult::wrap(exists, "resource exists")
     },
     Decision::G8IfMatchExists => DecisionResult::wrap(context.request.has_header("If-Match"),
                                                       "match exists"),
     Decision::G9IfMatchStarExists | &Decision::H7IfMatchStarExists => DecisionResult::wrap(
         context.request.has_header_value("If-Match", "*"), "match star exists"),
-    Decision::G11EtagInIfMatch => DecisionResult::wrap(resource_etag_matches_header_values(resource, context, "If-Match"),
+    Decision::G11EtagInIfMatch => DecisionResult::wrap(resource_etag_matches_header_values(resource, context, "If-Match", true).await,
                                                        "etag in if match"),
     Decision::H10IfUnmodifiedSinceExists => DecisionResult::wrap(context.request.has_header("If-Unmodified-Since"),
                                                                  "unmodified since exists"),
@@ -664,7 +1242,7 @@ fn execute_decision(
         Some(unmodified_since) => {
           let callback = resource.last_modified.lock().unwrap();
           match callback.deref()(context, resource) {
-            Some(datetime) => DecisionResult::wrap(datetime > unmodified_since,
+            Some(datetime) => DecisionResult::wrap(truncate_to_seconds(datetime) > truncate_to_seconds(unmodified_since),
                                                    "resource last modified date is greater than unmodified since"),
             None => DecisionResult::False("resource has no last modified date".to_string())
           }
@@ -688,11 +1266,10 @@ fn execute_decision(
       let callback = resource.previously_existed.lock().unwrap();
       DecisionResult::wrap(callback.deref()(context, resource), "resource previously existed")
     },
-    Decision::K13ETagInIfNoneMatch => DecisionResult::wrap(resource_etag_matches_header_values(resource, context, "If-None-Match"),
+    Decision::K13ETagInIfNoneMatch => DecisionResult::wrap(resource_etag_matches_header_values(resource, context, "If-None-Match", false).await,
                                                            "ETag in if none match"),
     Decision::L5HasMovedTemporarily => {
-      let callback = resource.moved_temporarily.lock().unwrap();
-      match callback.deref()(context, resource) {
+      match invoke_callback(&resource.moved_temporarily, &resource.moved_temporarily_async, context, resource).await {
         Some(location) => {
           context.response.add_header("Location", vec![HeaderValue::basic(&location)]);
           DecisionResult::True("resource has moved temporarily".to_string())
@@ -717,7 +1294,7 @@ fn execute_decision(
         Some(unmodified_since) => {
           let callback = resource.last_modified.lock().unwrap();
           match callback.deref()(context, resource) {
-            Some(datetime) => DecisionResult::wrap(datetime > unmodified_since,
+            Some(datetime) => DecisionResult::wrap(truncate_to_seconds(datetime) > truncate_to_seconds(unmodified_since),
                                                    "last modified greater than modified since"),
             None => DecisionResult::False("resource has no last modified date".to_string())
           }
@@ -726,8 +1303,7 @@ fn execute_decision(
       }
     },
     Decision::I4HasMovedPermanently | &Decision::K5HasMovedPermanently => {
-      let callback = resource.moved_permanently.lock().unwrap();
-      match callback.deref()(context, resource) {
+      match invoke_callback(&resource.moved_permanently, &resource.moved_permanently_async, context, resource).await {
         Some(location) => {
           context.response.add_header("Location", vec![HeaderValue::basic(&location)]);
           DecisionResult::True("resource has moved permanently".to_string())
@@ -747,77 +1323,108 @@ fn execute_decision(
     Decision::M16Delete => DecisionResult::wrap(context.request.is_delete(),
                                                 "a DELETE request"),
     Decision::M20DeleteEnacted => {
-      let callback = resource.delete_resource.lock().unwrap();
-      match callback.deref()(context, resource) {
+      match invoke_callback(&resource.delete_resource, &resource.delete_resource_async, context, resource).await {
         Ok(result) => DecisionResult::wrap(result, "resource DELETE succeeded"),
-        Err(status) => DecisionResult::StatusCode(status)
+        Err(err) => error_result(context, err)
       }
     },
     Decision::N11Redirect => {
-      let callback = resource.post_is_create.lock().unwrap();
-      if callback.deref()(context, resource) {
-        let callback = resource.create_path.lock().unwrap();
-        match callback.deref()(context, resource) {
+      let post_is_create = resource.post_is_create.lock().unwrap().deref()(context, resource);
+      if post_is_create {
+        match invoke_callback(&resource.create_path, &resource.create_path_async, context, resource).await {
           Ok(path) => {
             let base_path = sanitise_path(&context.request.base_path);
             let new_path = join_paths(&base_path, &sanitise_path(&path));
             context.request.request_path = path.clone();
-            context.response.add_header("Location", vec![HeaderValue::basic(&new_path)]);
+            let location = context.redirect_location.clone().unwrap_or(new_path);
+            context.response.add_header("Location", vec![HeaderValue::basic(location)]);
             DecisionResult::wrap(context.redirect, "should redirect")
           },
-          Err(status) => DecisionResult::StatusCode(status)
+          Err(err) => error_result(context, err)
         }
       } else {
-        let callback = resource.process_post.lock().unwrap();
-        match callback.deref()(context, resource) {
-          Ok(_) => DecisionResult::wrap(context.redirect, "processing POST succeeded"),
-          Err(status) => DecisionResult::StatusCode(status)
+        match invoke_callback(&resource.process_post, &resource.process_post_async, context, resource).await {
+          Ok(processed) => match resource.post_not_processed_status.filter(|_| !processed) {
+            Some(status) => DecisionResult::StatusCode(status),
+            None => {
+              if let Some(location) = context.redirect_location.clone() {
+                context.response.add_header("Location", vec![HeaderValue::basic(location)]);
+              }
+              DecisionResult::wrap(context.redirect, "processing POST succeeded")
+            }
+          },
+          Err(err) => error_result(context, err)
         }
       }
     },
     Decision::P3Conflict | &Decision::O14Conflict => {
-      let callback = resource.is_conflict.lock().unwrap();
-      DecisionResult::wrap(callback.deref()(context, resource), "resource conflict")
+      DecisionResult::wrap(invoke_callback(&resource.is_conflict, &resource.is_conflict_async, context, resource).await,
+        "resource conflict")
     },
     Decision::P11NewResource => {
       if context.request.is_put() {
-        let callback = resource.process_put.lock().unwrap();
-        match callback.deref()(context, resource) {
-          Ok(_) => DecisionResult::wrap(context.new_resource, "process PUT succeeded"),
-          Err(status) => DecisionResult::StatusCode(status)
+        match invoke_callback(&resource.process_put, &resource.process_put_async, context, resource).await {
+          Ok(_) => {
+            if context.new_resource && !context.response.has_header("Location") {
+              let location = context.redirect_location.clone().unwrap_or_else(|| {
+                join_paths(&sanitise_path(&context.request.base_path), &sanitise_path(&context.request.request_path))
+              });
+              context.response.add_header("Location", vec![HeaderValue::basic(location)]);
+            }
+            DecisionResult::wrap(context.new_resource, "process PUT succeeded")
+          },
+          Err(err) => error_result(context, err)
+        }
+      } else if context.request.is_patch() {
+        match invoke_callback(&resource.process_patch, &resource.process_patch_async, context, resource).await {
+          Ok(_) => DecisionResult::wrap(context.new_resource, "process PATCH succeeded"),
+          Err(err) => error_result(context, err)
         }
       } else {
         DecisionResult::wrap(context.new_resource, "new resource creation succeeded")
       }
     },
     Decision::O16Put => DecisionResult::wrap(context.request.is_put(), "a PUT request"),
+    Decision::O17Patch => DecisionResult::wrap(context.request.is_patch(), "a PATCH request"),
     Decision::O18MultipleRepresentations => {
       let callback = resource.multiple_choices.lock().unwrap();
       DecisionResult::wrap(callback.deref()(context, resource), "multiple choices exist")
     },
-    Decision::O20ResponseHasBody => DecisionResult::wrap(context.response.has_body(), "response has a body"),
+    Decision::O20ResponseHasBody => if resource.delete_returns_no_content && context.request.is_delete() {
+      context.response.body = None;
+      DecisionResult::False("DELETE requests always return no content".to_string())
+    } else {
+      DecisionResult::wrap(context.response.has_body(), "response has a body")
+    },
     _ => DecisionResult::False("default decision is false".to_string())
   }
 }
 
-fn execute_state_machine(context: &mut WebmachineContext, resource: &WebmachineResource) {
+async fn execute_state_machine(
+  context: &mut WebmachineContext,
+  resource: &WebmachineResource<'_>,
+  max_transitions: usize
+) {
   let mut state = Decision::Start;
   let mut decisions: Vec<(Decision, bool, Decision)> = Vec::new();
   let mut loop_count = 0;
   while !state.is_terminal() {
     loop_count += 1;
-    if loop_count >= MAX_STATE_MACHINE_TRANSITIONS {
-      panic!("State machine has not terminated within {} transitions!", loop_count);
+    if loop_count >= max_transitions {
+      error!("State machine has not terminated within {} transitions, failing the request", loop_count);
+      decisions.push((state, false, Decision::End(500)));
+      state = Decision::End(500);
+      break;
     }
     trace!("state is {:?}", state);
     state = match TRANSITION_MAP.get(&state) {
       Some(transition) => match transition {
-        &Transition::To(ref decision) => {
+        Transition::To(decision) => {
           trace!("Transitioning to {:?}", decision);
           decision.clone()
         },
-        &Transition::Branch(ref decision_true, ref decision_false) => {
-          match execute_decision(&state, context, resource) {
+        Transition::Branch(decision_true, decision_false) => {
+          match execute_decision(&state, context, resource).await {
             DecisionResult::True(reason) => {
               trace!("Transitioning from {:?} to {:?} as decision is true -> {}", state, decision_true, reason);
               decisions.push((state, true, decision_true.clone()));
@@ -845,22 +1452,50 @@ fn execute_state_machine(context: &mut WebmachineContext, resource: &WebmachineR
     }
   }
   trace!("Final state is {:?}", state);
+  context.decisions = decisions.iter()
+    .map(|(decision, result, next)| (format!("{:?}", decision), *result, format!("{:?}", next)))
+    .collect();
   match state {
     Decision::End(status) => context.response.status = status,
-    Decision::A3Options => {
+    Decision::A3Options => if resource.options_requires_existing_resource
+      && !invoke_callback(&resource.resource_exists, &resource.resource_exists_async, context, resource).await {
+      context.response.status = 404;
+    } else {
       context.response.status = 204;
       let callback = resource.options.lock().unwrap();
-      match callback.deref()(context, resource) {
-        Some(headers) => context.response.add_headers(headers),
-        None => ()
+      if let Some(headers) = callback.deref()(context, resource) {
+        context.response.add_headers(headers);
+      }
+      if !context.response.has_header("Allow") {
+        let methods = if resource.options_allow_known_methods {
+          resource.known_methods.iter().map(|m| m.to_string()).collect()
+        } else {
+          allowed_methods(context, resource)
+        };
+        context.response.add_header("Allow", methods.iter()
+          .map(HeaderValue::basic)
+          .collect());
       }
     },
     _ => ()
   }
 }
 
+/// Collapses any runs of slashes in the path into a single slash (i.e. `/a//b` becomes `/a/b`)
+fn normalise_path(path: &str) -> String {
+  let mut normalised = String::with_capacity(path.len());
+  for ch in path.chars() {
+    if ch != '/' || !normalised.ends_with('/') {
+      normalised.push(ch);
+    }
+  }
+  normalised
+}
+
 fn update_paths_for_resource(request: &mut WebmachineRequest, base_path: &str) {
-  request.base_path = base_path.into();
+  let base_path = normalise_path(base_path);
+  request.request_path = normalise_path(&request.request_path);
+  request.base_path = base_path.clone();
   if request.request_path.len() > base_path.len() {
     let request_path = request.request_path.clone();
     let subpath = request_path.split_at(base_path.len()).1;
@@ -883,52 +1518,61 @@ fn parse_header_values(value: &str) -> Vec<HeaderValue> {
 }
 
 fn headers_from_http_request(req: &Parts) -> HashMap<String, Vec<HeaderValue>> {
+  // Headers can be repeated, so the values from all the header lines are merged. Cookie headers
+  // are kept as they are, as the cookie values are not header parameters (see `cookies`)
   req.headers.iter()
-    .map(|(name, value)| (name.to_string(), parse_header_values(value.to_str().unwrap_or_default())))
-    .collect()
+    .fold(HashMap::new(), |mut headers, (name, value)| {
+      let value = value.to_str().unwrap_or_default();
+      let values = headers.entry(name.to_string()).or_insert_with(Vec::new);
+      if name == hyper::header::COOKIE {
+        values.push(HeaderValue::basic(value.trim()));
+      } else {
+        values.extend(parse_header_values(value));
+      }
+      headers
+    })
 }
 
 fn decode_query(query: &str) -> String {
-  let mut chars = query.chars();
-  let mut ch = chars.next();
-  let mut result = String::new();
-
-  while ch.is_some() {
-    let c = ch.unwrap();
-    if c == '%' {
-      let c1 = chars.next();
-      let c2 = chars.next();
-      match (c1, c2) {
-        (Some(v1), Some(v2)) => {
-          let mut s = String::new();
-          s.push(v1);
-          s.push(v2);
-          let decoded: Result<Vec<u8>, _> = hex::decode(s);
-          match decoded {
-            Ok(n) => result.push(n[0] as char),
-            Err(_) => {
-              result.push('%');
-              result.push(v1);
-              result.push(v2);
-            }
-          }
-        },
-        (Some(v1), None) => {
-          result.push('%');
-          result.push(v1);
+  percent_decode(query, true)
+}
+
+/// Decodes any percent-encoded characters in the value. If `plus_as_space` is true, `+` will be
+/// decoded as a space (which only applies to query strings). Invalid escape sequences are left
+/// as is.
+fn percent_decode(value: &str, plus_as_space: bool) -> String {
+  let bytes = value.as_bytes();
+  let mut result = Vec::with_capacity(bytes.len());
+  let mut index = 0;
+
+  while index < bytes.len() {
+    let b = bytes[index];
+    if b == b'%' && index + 2 < bytes.len() {
+      match hex::decode(&bytes[index + 1..index + 3]) {
+        Ok(decoded) => {
+          result.push(decoded[0]);
+          index += 3;
+          continue;
         },
-        _ => result.push('%')
+        Err(_) => result.push(b)
       }
-    } else if c == '+' {
-      result.push(' ');
+    } else if b == b'+' && plus_as_space {
+      result.push(b' ');
     } else {
-      result.push(c);
+      result.push(b);
     }
-
-    ch = chars.next();
+    index += 1;
   }
 
-  result
+  String::from_utf8_lossy(&result).to_string()
+}
+
+/// Splits the request path into its segments, decoding any percent-encoded characters in each
+/// segment. An encoded slash (`%2F`) is not treated as a separator.
+fn decoded_path_segments(path: &str) -> Vec<String> {
+  sanitise_path(path).iter()
+    .map(|segment| percent_decode(segment, false))
+    .collect()
 }
 
 fn parse_query(query: &str) -> HashMap<String, Vec<String>> {
@@ -958,64 +1602,270 @@ fn parse_query(query: &str) -> HashMap<String, Vec<String>> {
   }
 }
 
-async fn request_from_http_request(req: Request<hyper::Body>) -> WebmachineRequest {
+/// Reads the request body. If the body is larger than the maximum size, reading is stopped and
+/// an error is returned without buffering the rest of the body. This works with any
+/// `http_body::Body` implementation, not just `hyper::Body`.
+async fn read_body<B>(mut body: B, max_body_size: Option<usize>) -> Result<Vec<u8>, BodyError>
+  where B: HttpBody + Unpin,
+        B::Error: Display {
+  let mut data = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let mut chunk = chunk.map_err(|err| BodyError::Read(err.to_string()))?;
+    if max_body_size.map(|max| data.len() + chunk.remaining() > max).unwrap_or(false) {
+      return Err(BodyError::TooLarge);
+    }
+    while chunk.has_remaining() {
+      let bytes = chunk.chunk();
+      let len = bytes.len();
+      data.extend_from_slice(bytes);
+      chunk.advance(len);
+    }
+  }
+  Ok(data)
+}
+
+enum BodyError {
+  Read(String),
+  TooLarge
+}
+
+/// Creates a request from the parts of an HTTP request, without the body
+fn request_from_parts(parts: &Parts) -> WebmachineRequest {
+  let query = match parts.uri.query() {
+    Some(query) => parse_query(query),
+    None => HashMap::new()
+  };
+  WebmachineRequest {
+    request_path: parts.uri.path().to_string(),
+    base_path: "/".to_string(),
+    method: parts.method.as_str().into(),
+    headers: headers_from_http_request(parts),
+    body: None,
+    query,
+    http_version: format!("{:?}", parts.version)
+  }
+}
+
+async fn request_from_http_request<B>(
+  req: Request<B>,
+  max_body_size: Option<usize>
+) -> Result<WebmachineRequest, u16>
+  where B: HttpBody + Unpin,
+        B::Error: Display {
   let (parts, body) = req.into_parts();
-  let request_path = parts.uri.path().to_string();
 
-  let req_body = body.try_fold(Vec::new(), |mut data, chunk| async move {
-      data.extend_from_slice(&chunk);
-      Ok(data)
-    }).await;
-  let body = match req_body {
+  let content_length = parts.headers.get(http::header::CONTENT_LENGTH)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<usize>().ok());
+  if let (Some(max), Some(length)) = (max_body_size, content_length) {
+    if length > max {
+      warn!("Request body of {} bytes is larger than the maximum of {} bytes", length, max);
+      return Err(413);
+    }
+  }
+
+  let body = match read_body(body, max_body_size).await {
     Ok(body) => {
       if body.is_empty() {
         None
       } else {
-        Some(body.clone())
+        Some(body)
       }
     },
-    Err(err) => {
+    Err(BodyError::TooLarge) => {
+      warn!("Request body is larger than the maximum of {} bytes", max_body_size.unwrap_or_default());
+      return Err(413);
+    },
+    Err(BodyError::Read(err)) => {
       error!("Failed to read the request body: {}", err);
       None
     }
   };
 
-  let query = match parts.uri.query() {
-    Some(query) => parse_query(query),
-    None => HashMap::new()
-  };
-  WebmachineRequest {
-    request_path: request_path.clone(),
-    base_path: "/".to_string(),
-    method: parts.method.as_str().into(),
-    headers: headers_from_http_request(&parts),
+  Ok(WebmachineRequest {
     body,
-    query
+    .. request_from_parts(&parts)
+  })
+}
+
+/// Creates the context for executing a request, with the deadline and Accept headers parsed
+/// from the request headers
+fn context_from_request(request: WebmachineRequest) -> WebmachineContext {
+  WebmachineContext {
+    request_deadline: request.deadline_from_headers(Utc::now()),
+    accept_headers: Some(AcceptHeaders::parse(&request)),
+    request,
+    .. WebmachineContext::default()
+  }
+}
+
+/// Checks the `Expect` header of a request before the body is read. Hyper sends the interim
+/// '100 Continue' response when the body is first read, so a request that is rejected here is
+/// never asked to send its body. Any expectation other than `100-continue` results in a
+/// '417 Expectation Failed' response. For `100-continue`, a '413 Request Entity Too Large' response
+/// is returned if the declared length of the body is larger than `max_body_size` of the resource,
+/// or `valid_entity_length` returns false (which is called with a context without the body).
+fn expectation_failure(context: &mut WebmachineContext, resource: Option<&WebmachineResource>) -> Option<u16> {
+  let expect = context.request.header_value("Expect")?;
+  if !expect.trim().eq_ignore_ascii_case("100-continue") {
+    warn!("Rejecting request with an unsupported expectation '{}'", expect);
+    return Some(417);
+  }
+
+  let resource = resource?;
+  let too_large = match (resource.max_body_size, context.request.content_length()) {
+    (Some(max), Some(length)) => length > max as u64,
+    _ => false
+  };
+  if too_large || (context.request.has_body_method()
+    && !resource.valid_entity_length.lock().unwrap().deref()(context, resource)) {
+    warn!("Rejecting request with 'Expect: 100-continue' before reading the body, as the entity length is not valid");
+    Some(413)
+  } else {
+    None
   }
 }
 
-fn finalise_response(context: &mut WebmachineContext, resource: &WebmachineResource) {
+/// If the If-Range precondition of a range request (if there is one) matches the response. An
+/// entity tag must match the ETag of the response using the strong comparison (so a weak entity
+/// tag never matches), and a date must be the same as the Last-Modified date of the response.
+fn if_range_matches(context: &WebmachineContext) -> bool {
+  let values = context.request.find_header("If-Range");
+  if values.is_empty() {
+    return true;
+  }
+
+  let response_header = |name: &str| context.response.headers.iter()
+    .find(|(header, _)| header.eq_ignore_ascii_case(name))
+    .and_then(|(_, values)| values.first().cloned());
+  // HTTP dates contain a comma, so will have been split into multiple values
+  let value = values.iter().map(|value| value.to_string()).join(", ");
+  match parse_http_date(&value) {
+    Some(date) => response_header("Last-Modified")
+      .and_then(|last_modified| parse_http_date(&last_modified.value))
+      .map(|last_modified| last_modified == date)
+      .unwrap_or(false),
+    None => !values[0].value.starts_with("W/") && response_header("ETag")
+      .map(|etag| !etag.value.starts_with("W/") && etag.value == values[0].value)
+      .unwrap_or(false)
+  }
+}
+
+fn apply_range_request(context: &mut WebmachineContext) {
+  let range_header = context.request.find_header("Range").iter()
+    .map(|value| value.value.clone())
+    .join(",");
+  if let (Some(ranges), Some(body)) = (range::parse_range_header(&range_header), &context.response.body) {
+    let length = body.len() as u64;
+    let resolved = range::coalesce_ranges(&ranges.iter().filter_map(|range| range.resolve(length)).collect_vec());
+    if resolved.len() > range::MAX_RANGES {
+      debug!("Ignoring the Range header as it has more than {} ranges", range::MAX_RANGES);
+    } else if resolved.is_empty() {
+      context.response.status = 416;
+      context.response.headers.remove("Content-Length");
+      context.response.add_header("Content-Range",
+        vec![HeaderValue::basic(format!("bytes */{}", length))]);
+      context.response.body = None;
+    } else if resolved.len() == 1 {
+      let (first, last) = resolved[0];
+      let partial_body = body[first as usize..=last as usize].to_vec();
+      context.response.status = 206;
+      context.response.headers.remove("Content-Length");
+      context.response.add_header("Content-Range",
+        vec![HeaderValue::basic(format!("bytes {}-{}/{}", first, last, length))]);
+      context.response.body = Some(partial_body);
+    } else {
+      let boundary = multipart_boundary();
+      let content_type_key = context.response.headers.keys()
+        .find(|name| name.eq_ignore_ascii_case("Content-Type"))
+        .cloned();
+      let content_type = content_type_key.as_ref()
+        .and_then(|key| context.response.headers.remove(key))
+        .map(|values| values.iter().map(|value| value.to_string()).join(", "));
+      let mut multipart_body = Vec::new();
+      for (first, last) in resolved {
+        multipart_body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = &content_type {
+          multipart_body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        multipart_body.extend_from_slice(format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, length).as_bytes());
+        multipart_body.extend_from_slice(&body[first as usize..=last as usize]);
+        multipart_body.extend_from_slice(b"\r\n");
+      }
+      multipart_body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+      context.response.status = 206;
+      context.response.headers.remove("Content-Length");
+      context.response.add_header("Content-Type",
+        vec![HeaderValue::basic(format!("multipart/byteranges; boundary={}", boundary))]);
+      context.response.body = Some(multipart_body);
+    }
+  }
+}
+
+/// Encodes the rendered body with the selected charset. Only ISO-8859-1 and US-ASCII are
+/// transcoded (with any characters that can not be represented replaced with `?`, which is
+/// logged as a warning), as all other charsets are sent as the UTF-8 bytes of the body.
+fn encode_body(body: String, charset: Option<&str>) -> Vec<u8> {
+  let limit = match charset.map(|charset| charset.to_ascii_uppercase()).as_deref() {
+    Some("ISO-8859-1") | Some("LATIN1") | Some("ISO_8859-1") => 0xFF,
+    Some("US-ASCII") | Some("ASCII") => 0x7F,
+    _ => return body.into_bytes()
+  };
+  let replaced = body.chars().filter(|ch| (*ch as u32) > limit).count();
+  if replaced > 0 {
+    warn!("{} character(s) in the response body can not be represented in {} and have been replaced with '?'",
+      replaced, charset.unwrap_or_default());
+  }
+  body.chars()
+    .map(|ch| if (ch as u32) <= limit { ch as u32 as u8 } else { b'?' })
+    .collect()
+}
+
+/// Creates the Content-Type header for the media type, with the charset selected by content
+/// negotiation (or ISO-8859-1 if a charset was not selected)
+pub(crate) fn content_type_header(context: &WebmachineContext, media_type: &str) -> HeaderValue {
+  let charset = context.selected_charset.as_deref().unwrap_or("ISO-8859-1");
+  HeaderValue {
+    value: media_type.to_string(),
+    params: hashmap!{ "charset".to_string() => charset.to_string() },
+    quote: false
+  }
+}
+
+/// Generates a random boundary for a multipart response
+fn multipart_boundary() -> String {
+  let hasher = RandomState::new().build_hasher();
+  format!("webmachine-{:016x}", hasher.finish())
+}
+
+async fn finalise_response(context: &mut WebmachineContext, resource: &WebmachineResource<'_>) {
+  {
+    let callback = resource.finish_request.lock().unwrap();
+    callback.deref()(context, resource);
+  }
+
+  if resource.prevent_content_sniffing {
+    context.response.add_header("X-Content-Type-Options", vec![h!("nosniff")]);
+  }
+
+  let media_type_unknown = context.selected_media_type.as_deref()
+    .or_else(|| resource.produces.first().copied())
+    .map(|media_type| media_type.contains('*'))
+    .unwrap_or(true);
+  if resource.prevent_content_sniffing && media_type_unknown && !context.response.has_header("Content-Type") {
+    context.response.add_header("Content-Type", vec![h!("application/octet-stream")]);
+  }
+
   if !context.response.has_header("Content-Type") {
-    let media_type = match &context.selected_media_type {
-      &Some(ref media_type) => media_type.clone(),
-      &None => "application/json".to_string()
-    };
-    let charset = match &context.selected_charset {
-      &Some(ref charset) => charset.clone(),
-      &None => "ISO-8859-1".to_string()
-    };
-    let header = HeaderValue {
-      value: media_type,
-      params: hashmap!{ "charset".to_string() => charset },
-      quote: false
-    };
+    let media_type = context.selected_media_type.as_deref().unwrap_or("application/json");
+    let header = content_type_header(context, media_type);
     context.response.add_header("Content-Type", vec![header]);
   }
 
   let mut vary_header = if !context.response.has_header("Vary") {
     resource.variances
       .iter()
-      .map(|h| HeaderValue::parse_string(h.clone()))
+      .map(|h| HeaderValue::parse_string(h))
       .collect()
   } else {
     Vec::new()
@@ -1038,119 +1888,445 @@ fn finalise_response(context: &mut WebmachineContext, resource: &WebmachineResou
     context.response.add_header("Vary", vary_header.iter().cloned().unique().collect());
   }
 
-  if context.request.is_get_or_head() {
-    {
-      let callback = resource.generate_etag.lock().unwrap();
-      match callback.deref()(context, resource) {
-        Some(etag) => context.response.add_header("ETag", vec![HeaderValue::basic(&etag).quote()]),
-        None => ()
-      }
+  if !resource.cors_allow_null_origin {
+    remove_null_origin_cors_headers(context);
+  }
+
+  // A response that reflects a specific origin must vary on it, otherwise a cached response
+  // could be returned for requests from other origins
+  let reflects_origin = context.response.headers.iter()
+    .find(|(name, _)| name.eq_ignore_ascii_case("Access-Control-Allow-Origin"))
+    .map(|(_, values)| values.iter().any(|value| value.value != "*"))
+    .unwrap_or(false);
+  if reflects_origin {
+    let vary_key = context.response.headers.keys()
+      .find(|name| name.eq_ignore_ascii_case("Vary"))
+      .cloned()
+      .unwrap_or_else(|| "Vary".to_string());
+    let vary = context.response.headers.entry(vary_key).or_default();
+    if !vary.iter().any(|value| value.value.eq_ignore_ascii_case("Origin") || value.value == "*") {
+      vary.push(h!("Origin"));
     }
-    {
-      let callback = resource.expires.lock().unwrap();
-      match callback.deref()(context, resource) {
-        Some(datetime) => context.response.add_header("Expires", vec![HeaderValue::basic(datetime.to_rfc2822()).quote()]),
-        None => ()
-      }
+  }
+
+  // Validators are also returned for successful PUT or POST requests, so clients can perform
+  // conditional updates on the new or updated resource without having to do a GET first
+  let emit_validators = context.request.is_get_or_head() ||
+    (context.request.is_put_or_post() && [200, 201].contains(&context.response.status));
+  if emit_validators {
+    if let Some(etag) = invoke_callback(&resource.generate_etag, &resource.generate_etag_async, context, resource).await {
+      let value = HeaderValue::basic(&etag);
+      let value = if value.weak_etag().is_some() { value } else { value.quote() };
+      context.response.add_header("ETag", vec![value]);
     }
     {
       let callback = resource.last_modified.lock().unwrap();
-      match callback.deref()(context, resource) {
-        Some(datetime) => context.response.add_header("Last-Modified", vec![HeaderValue::basic(datetime.to_rfc2822()).quote()]),
-        None => ()
+      if let Some(datetime) = callback.deref()(context, resource) {
+        context.response.add_header("Last-Modified", vec![HeaderValue::basic(format_http_date(&datetime))]);
       }
     }
   }
 
+  if context.request.is_get_or_head() {
+    let callback = resource.expires.lock().unwrap();
+    if let Some(datetime) = callback.deref()(context, resource) {
+      context.response.add_header("Expires", vec![HeaderValue::basic(format_http_date(&datetime))]);
+    }
+  }
+
+  if context.response.body.is_none() && context.response.status == 200 && context.request.is_get_or_head() {
+    let callback = resource.body_length.lock().unwrap();
+    if let Some(length) = callback.deref()(context, resource) {
+      context.response.add_header("Content-Length", vec![HeaderValue::basic(length.to_string())]);
+    }
+  }
+
   if context.response.body.is_none() && context.response.status == 200 && context.request.is_get() {
-    let callback = resource.render_response.lock().unwrap();
-    match callback.deref()(context, resource) {
-      Some(body) => context.response.body = Some(body.into_bytes()),
-      None => ()
+    let callback = resource.render_response_stream.lock().unwrap();
+    if let Some(stream) = callback.deref()(context, resource) {
+      context.response.stream = Some(StreamingBody::new(stream));
+    }
+  }
+
+  if context.response.body.is_none() && context.response.stream.is_none() && context.response.status == 200 && context.request.is_get() {
+    let body = match &resource.render_response_lazy {
+      Some(callback) => {
+        let callback = callback.lock().unwrap();
+        callback.deref()(context, resource).map(|body| body())
+      },
+      None => invoke_callback(&resource.render_response, &resource.render_response_async, context, resource).await
+    };
+    if let Some(body) = body {
+      context.response.body = Some(encode_body(body, context.selected_charset.as_deref()));
     }
   }
 
-  match &resource.finalise_response {
-    Some(callback) => {
+  // A HEAD response must have the same headers as the GET response, so the body is rendered to
+  // determine the Content-Length (unless it was provided by `body_length`), and then discarded.
+  // A lazy body is not evaluated, so any headers set by the callback are still added.
+  if context.response.status == 200 && context.request.is_head() {
+    if let Some(callback) = &resource.render_response_lazy {
       let callback = callback.lock().unwrap();
-      callback.deref()(context, resource);
-    },
-    None => ()
+      let _ = callback.deref()(context, resource);
+    } else if !context.response.has_header("Content-Length") {
+      if let Some(body) = invoke_callback(&resource.render_response, &resource.render_response_async, context, resource).await {
+        let body = encode_body(body, context.selected_charset.as_deref());
+        context.response.add_header("Content-Length", vec![HeaderValue::basic(body.len().to_string())]);
+      }
+    }
+  }
+
+  if resource.accept_ranges && context.response.status == 200 && context.request.is_get_or_head() {
+    context.response.add_header("Accept-Ranges", vec![h!("bytes")]);
+    if context.request.is_get() && if_range_matches(context) {
+      apply_range_request(context);
+    }
+  }
+
+  if context.response.body.is_none() && context.response.status >= 400 {
+    let callback = resource.render_error.lock().unwrap();
+    if let Some(body) = callback.deref()(context, resource) {
+      context.response.body = Some(encode_body(body, context.selected_charset.as_deref()));
+    } else if no_acceptable_encoding(context) {
+      context.response.add_header("Content-Type", vec![h!("text/plain;charset=utf-8")]);
+      context.response.body = Some(format!("None of the encodings in the Accept-Encoding header are \
+        available. Available encodings: {}", resource.encodings_provided.join(", ")).into_bytes());
+    }
+  }
+
+  if let Some(body) = &context.response.body {
+    if ![204, 304].contains(&context.response.status) {
+      let length = body.len().to_string();
+      context.response.headers.retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+      context.response.add_header("Content-Length", vec![HeaderValue::basic(length)]);
+    }
+  }
+
+  if let Some(callback) = &resource.finalise_response {
+    let callback = callback.lock().unwrap();
+    callback.deref()(context, resource);
   }
 
   debug!("Final response: {:?}", context.response);
 }
 
+/// Marker stored in the context extensions when none of the encodings in the Accept-Encoding
+/// header are available (i.e. `identity;q=0` for a resource that only provides the identity
+/// encoding)
+struct NoAcceptableEncoding;
+
+/// If the request failed with a '406 Not Acceptable' response because none of the encodings in
+/// the Accept-Encoding header are available
+fn no_acceptable_encoding(context: &WebmachineContext) -> bool {
+  context.response.status == 406 && context.extensions.contains::<NoAcceptableEncoding>()
+}
+
+/// Removes the CORS headers from the response if the request has a `null` origin, and never lets
+/// a `null` origin be returned in the Access-Control-Allow-Origin header
+fn remove_null_origin_cors_headers(context: &mut WebmachineContext) {
+  let null_origin = context.request.header_value("Origin")
+    .map(|origin| origin.trim() == "null")
+    .unwrap_or(false);
+  if null_origin {
+    context.response.headers.retain(|name, _| !name.to_ascii_lowercase().starts_with("access-control-"));
+  } else {
+    context.response.headers.retain(|name, values| !(name.eq_ignore_ascii_case("Access-Control-Allow-Origin")
+      && values.iter().any(|value| value.value == "null")));
+  }
+}
+
+/// Runs the request through the resource the same way the dispatcher does (the state machine,
+/// then `finish_request`, the CORS headers, rendering the body and `finalise_response`), and
+/// returns the response. This is intended for testing resources without having to set up a
+/// dispatcher or HTTP request. Any async callbacks are awaited on the executor that polls the
+/// returned future, so resources with callbacks that need a Tokio runtime should be run from
+/// within one (i.e. a `#[tokio::test]`).
+///
+/// ```
+/// use webmachine_rust::{run, WebmachineResource};
+/// use webmachine_rust::context::WebmachineRequest;
+///
+/// let resource = WebmachineResource {
+///   render_response: webmachine_rust::callback(&|_, _| Some("{}".to_string())),
+///   .. WebmachineResource::default()
+/// };
+/// let response = futures::executor::block_on(run(&resource, WebmachineRequest::default()));
+/// assert_eq!(response.status, 200);
+/// ```
+pub async fn run(resource: &WebmachineResource<'_>, request: WebmachineRequest) -> WebmachineResponse {
+  let mut context = context_from_request(request);
+  execute_state_machine(&mut context, resource, MAX_STATE_MACHINE_TRANSITIONS).await;
+  finalise_response(&mut context, resource).await;
+  context.response
+}
+
+/// Headers that can not be combined into a single comma-separated header line, as their values
+/// can contain commas (i.e. the Expires attribute of a cookie, or the parameters of an
+/// authentication challenge). Each value of these headers is sent as a separate header line.
+const SEPARATE_LINE_HEADERS: [&str; 3] = ["Set-Cookie", "WWW-Authenticate", "Proxy-Authenticate"];
+
 fn generate_http_response(context: &WebmachineContext) -> http::Result<Response<hyper::Body>> {
   let mut response = Response::builder().status(context.response.status);
 
   for (header, values) in context.response.headers.clone() {
-    let header_values = values.iter().map(|h| h.to_string()).join(", ");
-    response = response.header(&header, &header_values);
+    if SEPARATE_LINE_HEADERS.iter().any(|name| header.eq_ignore_ascii_case(name)) {
+      for value in values {
+        response = response.header(&header, value.to_string());
+      }
+    } else {
+      let header_values = values.iter().map(|h| h.to_string()).join(", ");
+      response = response.header(&header, &header_values);
+    }
   }
-  match context.response.body.clone() {
-    Some(body) => response.body(body.into()),
-    None => response.body(Body::empty())
+  if let Some(reason) = &context.response.reason {
+    match ReasonPhrase::try_from(reason.as_bytes()) {
+      Ok(reason) => response = response.extension(reason),
+      Err(_) => warn!("Ignoring invalid reason phrase '{}'", reason)
+    }
   }
+  match context.response.stream.as_ref().and_then(|stream| stream.take()) {
+    Some(stream) => response.body(Body::wrap_stream(stream)),
+    None => match context.response.body.clone() {
+      Some(body) => response.body(body.into()),
+      None => response.body(Body::empty())
+    }
+  }
+}
+
+/// Summary of a route registered with the dispatcher
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSummary {
+  /// Route path (i.e. `/users/{id}`)
+  pub path: String,
+  /// Methods allowed on the resource for the route. HEAD is not included if the resource does
+  /// not allow it (see `WebmachineResource::allow_head`).
+  pub methods: Vec<String>,
+  /// If the resource determines the allowed methods for each request with `allowed_methods_fn`.
+  /// In that case, `methods` are only the default `allowed_methods` of the resource.
+  pub dynamic_methods: bool
 }
 
 /// The main hyper dispatcher
 #[derive(Clone)]
 pub struct WebmachineDispatcher<'a> {
   /// Map of routes to webmachine resources
-  pub routes: BTreeMap<&'a str, WebmachineResource<'a>>
+  pub routes: BTreeMap<&'a str, WebmachineResource<'a>>,
+  /// Map of routes to webmachine resources, where the routes are owned strings (i.e. routes that
+  /// are built at runtime from configuration). These are matched in the same way as `routes`.
+  pub owned_routes: BTreeMap<String, WebmachineResource<'a>>,
+  /// Maximum number of transitions the state machine can make for a request before a
+  /// '500 Internal Server Error' response is returned. Defaults to `MAX_STATE_MACHINE_TRANSITIONS`.
+  pub max_transitions: usize,
+  /// Counts of the response status codes produced by the dispatcher. These are shared between
+  /// clones of the dispatcher, so will include all requests handled by the server.
+  pub status_counts: Arc<Mutex<HashMap<u16, u64>>>,
+  /// If true, a `Content-Length: 0` header is added to '204 No Content' and '304 Not Modified'
+  /// responses, for legacy clients that require it. This is discouraged by RFC 9110, so defaults
+  /// to false.
+  pub empty_content_length: bool,
+  /// Interceptors that are run in order for every request before the route is matched (i.e. for
+  /// authentication, rate-limiting or logging). If an interceptor returns a response, it is used
+  /// as the response for the request, and no further interceptors or resources are executed.
+  /// Interceptors are also run for routes with a raw request handler, before the handler is
+  /// called, but the request body will not be in the context for those routes.
+  pub interceptors: Vec<Interceptor<'a>>
+}
+
+impl <'a> Default for WebmachineDispatcher<'a> {
+  fn default() -> Self {
+    WebmachineDispatcher {
+      routes: BTreeMap::new(),
+      owned_routes: BTreeMap::new(),
+      max_transitions: MAX_STATE_MACHINE_TRANSITIONS,
+      status_counts: Arc::new(Mutex::new(HashMap::new())),
+      empty_content_length: false,
+      interceptors: Vec::new()
+    }
+  }
 }
 
 impl <'a> WebmachineDispatcher<'a> {
+  /// Creates a dispatcher from a map of owned route strings to resources. This allows the routes
+  /// to be built at runtime without requiring `'static` strings.
+  pub fn new(routes: BTreeMap<String, WebmachineResource<'a>>) -> Self {
+    WebmachineDispatcher {
+      owned_routes: routes,
+      .. WebmachineDispatcher::default()
+    }
+  }
+
   /// Main dispatch function for the Webmachine. This will look for a matching resource
-  /// based on the request path. If one is not found, a 404 Not Found response is returned
+  /// based on the request path. If one is not found, a 404 Not Found response is returned.
+  /// The request body is read before the resource is executed, so for requests with an
+  /// `Expect: 100-continue` header, Hyper will send the interim '100 Continue' response on the
+  /// connection before the body is consumed. The expectation is checked before the body is read,
+  /// so requests that will be rejected because of the size of the body get a final response
+  /// instead.
   pub async fn dispatch(self, req: Request<hyper::Body>) -> http::Result<Response<hyper::Body>> {
-    let mut context = self.context_from_http_request(req).await;
-    self.dispatch_to_resource(&mut context);
-    generate_http_response(&context)
-  }
+    let request_path = WebmachineRequest {
+      request_path: req.uri().path().to_string(),
+      .. WebmachineRequest::default()
+    };
+    let matched = self.matching_resource(&request_path);
+
+    if let Some(handler) = matched.as_ref().and_then(|(_, resource)| resource.raw_request_handler.clone()) {
+      let (parts, body) = req.into_parts();
+      let mut context = context_from_request(request_from_parts(&parts));
+      let response = match self.run_interceptors(&mut context) {
+        Some(response) => {
+          context.response = response;
+          generate_http_response(&context)
+        },
+        None => handler(Request::from_parts(parts, body)).await
+      };
+      if let Ok(response) = &response {
+        self.count_status(response.status().as_u16());
+      }
+      return response;
+    }
 
-  async fn context_from_http_request(&self, req: Request<hyper::Body>) -> WebmachineContext {
-    let request = request_from_http_request(req).await;
-    WebmachineContext {
-      request,
-      response: WebmachineResponse::default(),
-      .. WebmachineContext::default()
+    let (parts, body) = req.into_parts();
+    let mut context = context_from_request(request_from_parts(&parts));
+    if let Some(status) = expectation_failure(&mut context, matched.as_ref().map(|(_, resource)| *resource)) {
+      self.count_status(status);
+      return Response::builder().status(status).body(Body::empty());
+    }
+    let req = Request::from_parts(parts, body);
+
+    let max_body_size = matched.as_ref().and_then(|(_, resource)| resource.max_body_size);
+    match request_from_http_request(req, max_body_size).await {
+      Ok(request) => {
+        let mut context = context_from_request(request);
+        self.dispatch_to_matched_resource(&mut context, matched).await;
+        generate_http_response(&context)
+      },
+      Err(status) => {
+        self.count_status(status);
+        Response::builder().status(status).body(Body::empty())
+      }
     }
   }
 
   fn match_paths(&self, request: &WebmachineRequest) -> Vec<String> {
-    let request_path = sanitise_path(&request.request_path);
-    self.routes
-      .keys()
-      .filter(|k| request_path.starts_with(&sanitise_path(k)))
+    let request_path = decoded_path_segments(&request.request_path);
+    self.route_paths().into_iter()
+      .filter(|k| match_route(&sanitise_path(k), &request_path).is_some())
       .map(|k| k.to_string())
       .collect()
   }
 
+  /// Returns all the registered route paths in order
+  fn route_paths(&self) -> Vec<&str> {
+    self.routes.keys().copied()
+      .merge(self.owned_routes.keys().map(|k| k.as_str()))
+      .dedup()
+      .collect()
+  }
+
+  /// Returns the route that best matches the request path. This will be the route that matches
+  /// the most path segments, with literal segments preferred over templated ones. A splat segment
+  /// matches all the remaining segments, but a route without one is preferred if they are equal.
+  fn matching_route(&self, request: &WebmachineRequest) -> Option<String> {
+    let request_segments = sanitise_path(&request.request_path).len();
+    self.match_paths(request).into_iter()
+      .sorted_by_cached_key(|path| {
+        let route = sanitise_path(path);
+        let (segments, splat) = split_splat(&route);
+        let matched = if splat.is_some() { request_segments } else { segments.len() };
+        let literals = segments.iter().filter(|segment| !is_path_param(segment)).count();
+        (std::cmp::Reverse(matched), std::cmp::Reverse(literals), splat.is_some(), std::cmp::Reverse(path.len()))
+      })
+      .next()
+  }
+
   fn lookup_resource(&self, path: &str) -> Option<&WebmachineResource<'a>> {
-    self.routes.get(path)
+    self.routes.get(path).or_else(|| self.owned_routes.get(path))
+  }
+
+  /// Returns the route that best matches the request path, along with its resource
+  fn matching_resource(&self, request: &WebmachineRequest) -> Option<(String, &WebmachineResource<'a>)> {
+    self.matching_route(request)
+      .and_then(|path| self.lookup_resource(&path).map(|resource| (path, resource)))
+  }
+
+  /// Returns a summary of all the registered routes, with the methods allowed on the resource
+  /// for each route
+  pub fn routes_summary(&self) -> Vec<RouteSummary> {
+    self.route_paths().into_iter()
+      .filter_map(|path| self.lookup_resource(path).map(|resource| (path, resource)))
+      .map(|(path, resource)| RouteSummary {
+        path: path.to_string(),
+        methods: resource.allowed_methods.iter()
+          .filter(|method| resource.allow_head || !method.eq_ignore_ascii_case("HEAD"))
+          .map(|method| method.to_string())
+          .collect(),
+        dynamic_methods: resource.allowed_methods_fn.is_some()
+      })
+      .collect()
   }
 
   /// Dispatches to the matching webmachine resource. If there is no matching resource, returns
-  /// 404 Not Found response
-  pub fn dispatch_to_resource(&self, context: &mut WebmachineContext) {
-    let matching_paths = self.match_paths(&context.request);
-    let ordered_by_length: Vec<String> = matching_paths.iter()
-      .cloned()
-      .sorted_by(|a, b| Ord::cmp(&b.len(), &a.len())).collect();
-    match ordered_by_length.first() {
-      Some(path) => {
-        update_paths_for_resource(&mut context.request, path);
-        if let Some(resource) = self.lookup_resource(path) {
-          execute_state_machine(context, &resource);
-          finalise_response(context, &resource);
-        } else {
-          context.response.status = 404;
+  /// 404 Not Found response. Any interceptors are run first, and if one returns a response, the
+  /// resource is not executed. Routes can contain `{name}` segments which will match any request
+  /// path segment, with the values stored in `context.path_params`. The route that matches the
+  /// most path segments is used, with literal segments preferred over templated ones. Request path
+  /// segments are percent-decoded before being matched (an encoded slash is not a separator).
+  ///
+  /// A route can end with a `*name` splat segment (i.e. `/static/*rest`), which matches the rest
+  /// of the request path. The remainder is stored in `context.path_params` under the name (without
+  /// a leading slash, and empty if there is no remainder), and `base_path` is set to the literal
+  /// prefix. When choosing between routes, a splat route counts as matching the whole request path,
+  /// so it is used in preference to a shorter prefix route (i.e. `/static`). Routes that match the
+  /// same number of segments with more literal segments are still preferred (i.e.
+  /// `/static/index.html`), and if there is no remainder, a route without the splat is used.
+  pub async fn dispatch_to_resource(&self, context: &mut WebmachineContext) {
+    let matched = self.matching_resource(&context.request);
+    self.dispatch_to_matched_resource(context, matched).await;
+  }
+
+  async fn dispatch_to_matched_resource(
+    &self,
+    context: &mut WebmachineContext,
+    matched: Option<(String, &WebmachineResource<'a>)>
+  ) {
+    if let Some(response) = self.run_interceptors(context) {
+      context.response = response;
+      self.count_status(context.response.status);
+      return;
+    }
+
+    match matched {
+      Some((path, resource)) => {
+        let route = sanitise_path(&path);
+        context.path_params = match_route(&route, &decoded_path_segments(&context.request.request_path))
+          .unwrap_or_default();
+        let prefix_len = split_splat(&route).0.len();
+        let base_path = format!("/{}", sanitise_path(&context.request.request_path)[..prefix_len].join("/"));
+        update_paths_for_resource(&mut context.request, &base_path);
+        context.matched_route = Some(path);
+        execute_state_machine(context, resource, self.max_transitions).await;
+        finalise_response(context, resource).await;
+        if self.empty_content_length && [204, 304].contains(&context.response.status)
+          && !context.response.has_header("Content-Length") {
+          context.response.add_header("Content-Length", vec![h!("0")]);
         }
       },
       None => context.response.status = 404
     };
+    self.count_status(context.response.status);
+  }
+
+  fn run_interceptors(&self, context: &mut WebmachineContext) -> Option<WebmachineResponse> {
+    self.interceptors.iter().find_map(|interceptor| interceptor(context))
+  }
+
+  fn count_status(&self, status: u16) {
+    *self.status_counts.lock().unwrap().entry(status).or_insert(0) += 1;
+  }
+
+  /// Returns a snapshot of the number of responses produced for each status code
+  pub fn status_counters(&self) -> HashMap<u16, u64> {
+    self.status_counts.lock().unwrap().clone()
   }
 }
 
@@ -0,0 +1,180 @@
+//! The `range` module deals with parsing Range and Content-Range headers and applying byte
+//! ranges to response bodies as per https://tools.ietf.org/html/rfc7233.
+
+/// Enum to represent a single byte range from a Range header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+  /// Range from the first byte position to the optional last byte position (inclusive), i.e. `0-9` or `10-`
+  Range(u64, Option<u64>),
+  /// The last N bytes of the body, i.e. `-10`
+  Suffix(u64)
+}
+
+impl ByteRange {
+  /// Resolves this range against a body of the given length, returning the first and last byte
+  /// positions (inclusive). Returns None if the range is not satisfiable.
+  pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+    match *self {
+      ByteRange::Range(first, last) => if first < length {
+        let last = last.map(|last| last.min(length - 1)).unwrap_or(length - 1);
+        Some((first, last))
+      } else {
+        None
+      },
+      ByteRange::Suffix(suffix) => if suffix > 0 && length > 0 {
+        Some((length.saturating_sub(suffix), length - 1))
+      } else {
+        None
+      }
+    }
+  }
+}
+
+/// The maximum number of ranges that will be returned in a multipart/byteranges response (after
+/// overlapping and adjacent ranges are coalesced). A request with more ranges than this is
+/// served the full body, as allowed by RFC 7233 section 6.1.
+pub const MAX_RANGES: usize = 16;
+
+/// Coalesces resolved ranges (first and last byte positions, inclusive) that overlap or are
+/// adjacent, so that no part of the body is sent more than once. The returned ranges are in
+/// ascending order.
+pub fn coalesce_ranges(ranges: &[(u64, u64)]) -> Vec<(u64, u64)> {
+  let mut sorted = ranges.to_vec();
+  sorted.sort_unstable();
+  let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+  for (first, last) in sorted {
+    match coalesced.last_mut() {
+      Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = previous.1.max(last),
+      _ => coalesced.push((first, last))
+    }
+  }
+  coalesced
+}
+
+fn parse_byte_range(value: &str) -> Option<ByteRange> {
+  let (first, last) = value.trim().split_once('-')?;
+  let first = first.trim();
+  let last = last.trim();
+  if first.is_empty() {
+    last.parse().ok().map(ByteRange::Suffix)
+  } else {
+    let first = first.parse().ok()?;
+    if last.is_empty() {
+      Some(ByteRange::Range(first, None))
+    } else {
+      let last = last.parse().ok()?;
+      if last >= first {
+        Some(ByteRange::Range(first, Some(last)))
+      } else {
+        None
+      }
+    }
+  }
+}
+
+/// Parses the value of a Range header (i.e. `bytes=0-9,20-29`). Returns None if the header is
+/// not a valid byte range set.
+pub fn parse_range_header(value: &str) -> Option<Vec<ByteRange>> {
+  let (unit, ranges) = value.trim().split_once('=')?;
+  if unit.trim().eq_ignore_ascii_case("bytes") {
+    let ranges: Option<Vec<ByteRange>> = ranges.split(',')
+      .filter(|range| !range.trim().is_empty())
+      .map(parse_byte_range)
+      .collect();
+    ranges.filter(|ranges| !ranges.is_empty())
+  } else {
+    None
+  }
+}
+
+/// Struct to represent the value of a Content-Range header (i.e. `bytes 0-9/100`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+  /// First byte position of the range
+  pub first: u64,
+  /// Last byte position of the range (inclusive)
+  pub last: u64,
+  /// Complete length of the representation, if known (`*` is used when it is not)
+  pub complete_length: Option<u64>
+}
+
+/// Parses the value of a Content-Range header with a byte range (i.e. `bytes 0-9/100` or
+/// `bytes 0-9/*`). Returns None if the header is not a valid byte range, including when the last
+/// byte position is not less than the complete length.
+pub fn parse_content_range_header(value: &str) -> Option<ContentRange> {
+  let (unit, range) = value.trim().split_once(' ')?;
+  if !unit.eq_ignore_ascii_case("bytes") {
+    return None;
+  }
+  let (range, complete_length) = range.trim().split_once('/')?;
+  let (first, last) = range.split_once('-')?;
+  let first = first.trim().parse().ok()?;
+  let last = last.trim().parse().ok()?;
+  let complete_length = match complete_length.trim() {
+    "*" => None,
+    length => Some(length.parse().ok()?)
+  };
+  if last < first || complete_length.map(|length| last >= length).unwrap_or(false) {
+    None
+  } else {
+    Some(ContentRange { first, last, complete_length })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn coalesce_ranges_test() {
+    expect!(coalesce_ranges(&[])).to(be_equal_to(vec![]));
+    expect!(coalesce_ranges(&[(0, 9)])).to(be_equal_to(vec![(0, 9)]));
+    expect!(coalesce_ranges(&[(0, 99), (0, 99), (0, 99)])).to(be_equal_to(vec![(0, 99)]));
+    expect!(coalesce_ranges(&[(20, 29), (0, 9), (10, 14)])).to(be_equal_to(vec![(0, 14), (20, 29)]));
+    expect!(coalesce_ranges(&[(0, 50), (10, 20), (40, 60)])).to(be_equal_to(vec![(0, 60)]));
+  }
+
+  #[test]
+  fn parse_range_header_test() {
+    expect!(parse_range_header("bytes=0-0")).to(be_some().value(vec![ByteRange::Range(0, Some(0))]));
+    expect!(parse_range_header("bytes=10-")).to(be_some().value(vec![ByteRange::Range(10, None)]));
+    expect!(parse_range_header("bytes=-10")).to(be_some().value(vec![ByteRange::Suffix(10)]));
+    expect!(parse_range_header("bytes=0-9, 20-29")).to(be_some().value(vec![
+      ByteRange::Range(0, Some(9)), ByteRange::Range(20, Some(29))
+    ]));
+    expect!(parse_range_header("bytes=9-0")).to(be_none());
+    expect!(parse_range_header("bytes=")).to(be_none());
+    expect!(parse_range_header("bytes=a-b")).to(be_none());
+    expect!(parse_range_header("items=0-9")).to(be_none());
+    expect!(parse_range_header("0-9")).to(be_none());
+  }
+
+  #[test]
+  fn parse_content_range_header_test() {
+    expect!(parse_content_range_header("bytes 0-9/100")).to(be_some().value(ContentRange {
+      first: 0, last: 9, complete_length: Some(100)
+    }));
+    expect!(parse_content_range_header("bytes 10-19/*")).to(be_some().value(ContentRange {
+      first: 10, last: 19, complete_length: None
+    }));
+    expect!(parse_content_range_header("bytes 9-0/100")).to(be_none());
+    expect!(parse_content_range_header("bytes 0-100/100")).to(be_none());
+    expect!(parse_content_range_header("bytes */100")).to(be_none());
+    expect!(parse_content_range_header("bytes 0-9")).to(be_none());
+    expect!(parse_content_range_header("items 0-9/100")).to(be_none());
+    expect!(parse_content_range_header("bytes a-9/100")).to(be_none());
+  }
+
+  #[test]
+  fn resolve_byte_range_test() {
+    expect!(ByteRange::Range(0, Some(0)).resolve(10)).to(be_some().value((0, 0)));
+    expect!(ByteRange::Range(5, Some(100)).resolve(10)).to(be_some().value((5, 9)));
+    expect!(ByteRange::Range(5, None).resolve(10)).to(be_some().value((5, 9)));
+    expect!(ByteRange::Range(10, None).resolve(10)).to(be_none());
+    expect!(ByteRange::Suffix(3).resolve(10)).to(be_some().value((7, 9)));
+    expect!(ByteRange::Suffix(30).resolve(10)).to(be_some().value((0, 9)));
+    expect!(ByteRange::Suffix(0).resolve(10)).to(be_none());
+  }
+}
@@ -0,0 +1,200 @@
+//! The `serializers` module provides a registry of body serializers for media types, so that a
+//! resource can render a typed value and have it serialized for the media type selected by content
+//! negotiation (i.e. JSON or CSV).
+
+use std::sync::Arc;
+
+use tracing::error;
+
+use crate::context::WebmachineContext;
+use crate::{content_type_header, owned_callback, WebmachineResource};
+
+/// Type of a serializer, which converts a value into the body for a media type
+pub type Serializer<'a, T> = Arc<dyn Fn(&T) -> Result<String, String> + Send + Sync + 'a>;
+
+/// Registry of serializers for the media types a resource produces. The media types are kept in
+/// the order they are registered, so the first one is the default.
+pub struct SerializerRegistry<'a, T> {
+  serializers: Vec<(&'a str, Serializer<'a, T>)>
+}
+
+impl <'a, T> Clone for SerializerRegistry<'a, T> {
+  fn clone(&self) -> Self {
+    SerializerRegistry {
+      serializers: self.serializers.clone()
+    }
+  }
+}
+
+impl <'a, T> Default for SerializerRegistry<'a, T> {
+  fn default() -> Self {
+    SerializerRegistry {
+      serializers: Vec::new()
+    }
+  }
+}
+
+impl <'a, T> SerializerRegistry<'a, T> {
+  /// Creates an empty registry
+  pub fn new() -> Self {
+    SerializerRegistry::default()
+  }
+
+  /// Registers the serializer for the media type, replacing any existing one
+  pub fn register<F>(mut self, media_type: &'a str, serializer: F) -> Self
+    where F: Fn(&T) -> Result<String, String> + Send + Sync + 'a {
+    self.serializers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(media_type));
+    self.serializers.push((media_type, Arc::new(serializer)));
+    self
+  }
+
+  /// Returns the media types that have a registered serializer
+  pub fn media_types(&self) -> Vec<&'a str> {
+    self.serializers.iter().map(|(media_type, _)| *media_type).collect()
+  }
+
+  /// Serializes the value for the media type. Returns None if there is no serializer registered
+  /// for the media type.
+  pub fn serialize(&self, media_type: &str, value: &T) -> Option<Result<String, String>> {
+    self.serializers.iter()
+      .find(|(registered, _)| registered.eq_ignore_ascii_case(media_type))
+      .map(|(_, serializer)| serializer(value))
+  }
+}
+
+#[cfg(feature = "serde")]
+impl <'a, T: serde::Serialize> SerializerRegistry<'a, T> {
+  /// Registers a serializer for `application/json` using serde_json
+  pub fn with_json(self) -> Self {
+    self.register("application/json", |value: &T| serde_json::to_string(value).map_err(|err| err.to_string()))
+  }
+}
+
+impl <'a> WebmachineResource<'a> {
+  /// Sets the media types the resource produces from the serializers in the registry, along with
+  /// the callback to render the value for the response. The `render_response` callback is replaced
+  /// with one that invokes the callback and then serializes the value for the media type selected
+  /// by content negotiation (or the first one if the request did not have an Accept header). If
+  /// the value can not be serialized, a '500 Internal Server Error' response is returned.
+  ///
+  /// ```
+  /// use webmachine_rust::WebmachineResource;
+  /// use webmachine_rust::serializers::SerializerRegistry;
+  ///
+  /// let registry = SerializerRegistry::new()
+  ///   .register("text/csv", |values: &Vec<u32>| {
+  ///     Ok(values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(","))
+  ///   });
+  /// let resource = WebmachineResource::default()
+  ///   .render_with(registry, |_, _| Some(vec![1, 2, 3]));
+  /// ```
+  pub fn render_with<T, F>(mut self, registry: SerializerRegistry<'a, T>, render: F) -> Self
+    where T: 'a,
+          F: Fn(&mut WebmachineContext, &WebmachineResource) -> Option<T> + Send + Sync + 'a {
+    self.produces = registry.media_types();
+    self.render_response = owned_callback(move |context: &mut WebmachineContext, resource: &WebmachineResource| {
+      let media_type = context.selected_media_type.clone()
+        .or_else(|| registry.media_types().first().map(|media_type| media_type.to_string()))?;
+      let value = render(context, resource)?;
+      match registry.serialize(&media_type, &value)? {
+        Ok(body) => Some(body),
+        Err(err) => {
+          error!("Failed to serialize the response body as '{}': {}", media_type, err);
+          context.response.status = 500;
+          None
+        }
+      }
+    });
+    self
+  }
+
+  /// Sets the `render_error` callback to one that invokes the callback to render the error value
+  /// for the response, and then serializes it for the media type selected by content negotiation.
+  /// If the error occurred before a media type was selected (or there is no serializer for it), the
+  /// first media type in the registry is used. The Content-Type header of the response is set to
+  /// the media type used, with the selected charset (the same as for successful responses).
+  pub fn render_errors_with<T, F>(mut self, registry: SerializerRegistry<'a, T>, render: F) -> Self
+    where T: 'a,
+          F: Fn(&mut WebmachineContext, &WebmachineResource) -> Option<T> + Send + Sync + 'a {
+    self.render_error = owned_callback(move |context: &mut WebmachineContext, resource: &WebmachineResource| {
+      let media_types = registry.media_types();
+      let media_type = context.selected_media_type.as_ref()
+        .and_then(|selected| media_types.iter().find(|media_type| media_type.eq_ignore_ascii_case(selected)))
+        .or_else(|| media_types.first())
+        .map(|media_type| media_type.to_string())?;
+      let value = render(context, resource)?;
+      match registry.serialize(&media_type, &value)? {
+        Ok(body) => {
+          context.response.add_header("Content-Type", vec![content_type_header(context, &media_type)]);
+          Some(body)
+        },
+        Err(err) => {
+          error!("Failed to serialize the error body as '{}': {}", media_type, err);
+          None
+        }
+      }
+    });
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use crate::context::WebmachineContext;
+
+  use super::*;
+
+  #[cfg(feature = "serde")]
+  fn registry<'a>() -> SerializerRegistry<'a, Vec<(String, u32)>> {
+    SerializerRegistry::new()
+      .with_json()
+      .register("text/csv", |rows: &Vec<(String, u32)>| {
+        Ok(rows.iter().map(|(name, count)| format!("{},{}", name, count)).collect::<Vec<_>>().join("\n"))
+      })
+  }
+
+  #[test]
+  #[cfg(feature = "serde")]
+  fn serialize_test() {
+    let registry = registry();
+    let rows = vec![("a".to_string(), 1)];
+    expect!(registry.media_types()).to(be_equal_to(vec!["application/json", "text/csv"]));
+    expect!(registry.serialize("text/csv", &rows)).to(be_some().value(Ok("a,1".to_string())));
+    expect!(registry.serialize("application/xml", &rows)).to(be_none());
+  }
+
+  #[test]
+  #[cfg(feature = "serde")]
+  fn render_with_serializes_for_the_selected_media_type() {
+    let resource = WebmachineResource::default()
+      .render_with(registry(), |_, _| Some(vec![("a".to_string(), 1), ("b".to_string(), 2)]));
+    expect!(resource.produces.clone()).to(be_equal_to(vec!["application/json", "text/csv"]));
+
+    let mut context = WebmachineContext {
+      selected_media_type: Some("application/json".to_string()),
+      .. WebmachineContext::default()
+    };
+    let body = resource.render_response.lock().unwrap()(&mut context, &resource);
+    expect!(body).to(be_some().value("[[\"a\",1],[\"b\",2]]"));
+
+    let mut context = WebmachineContext {
+      selected_media_type: Some("text/csv".to_string()),
+      .. WebmachineContext::default()
+    };
+    let body = resource.render_response.lock().unwrap()(&mut context, &resource);
+    expect!(body).to(be_some().value("a,1\nb,2"));
+  }
+
+  #[test]
+  fn render_with_returns_500_if_the_value_can_not_be_serialized() {
+    let registry = SerializerRegistry::new()
+      .register("text/plain", |_: &u32| Err("not supported".to_string()));
+    let resource = WebmachineResource::default().render_with(registry, |_, _| Some(1));
+    let mut context = WebmachineContext::default();
+    let body = resource.render_response.lock().unwrap()(&mut context, &resource);
+    expect!(body).to(be_none());
+    expect!(context.response.status).to(be_equal_to(500));
+  }
+}
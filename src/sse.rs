@@ -0,0 +1,183 @@
+//! The `sse` module provides support for Server-Sent Events (https://html.spec.whatwg.org/multipage/server-sent-events.html),
+//! streaming events to the client over a `text/event-stream` response.
+
+use std::error::Error;
+use std::pin::Pin;
+
+use futures::{Stream, StreamExt};
+use hyper::body::Bytes;
+use itertools::Itertools;
+
+use crate::context::{BodyStream, WebmachineContext};
+use crate::headers::HeaderValue;
+use crate::{owned_callback, WebmachineResource};
+
+/// Stream of events to send to the client
+pub type EventStream = Pin<Box<dyn Stream<Item = SseEvent> + Send>>;
+
+/// A single Server-Sent Event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SseEvent {
+  /// Event ID. The client will send the last ID it received in the `Last-Event-ID` header when it
+  /// reconnects.
+  pub id: Option<String>,
+  /// Event type
+  pub event: Option<String>,
+  /// Event data. Data with multiple lines will be sent as multiple `data` fields.
+  pub data: String,
+  /// Reconnection time in milliseconds
+  pub retry: Option<u64>
+}
+
+impl SseEvent {
+  /// Creates an event with the given data
+  pub fn new(data: &str) -> SseEvent {
+    SseEvent {
+      data: data.to_string(),
+      .. SseEvent::default()
+    }
+  }
+
+  /// Sets the event ID
+  pub fn with_id(mut self, id: &str) -> SseEvent {
+    self.id = Some(id.to_string());
+    self
+  }
+
+  /// Sets the event type
+  pub fn with_event(mut self, event: &str) -> SseEvent {
+    self.event = Some(event.to_string());
+    self
+  }
+
+  /// Formats the event as it is sent over the wire, terminated by a blank line. Any CR or LF
+  /// characters in the ID or event type are removed, as they would terminate the field. Data is
+  /// split into lines on CRLF, LF or CR, with each line sent as a separate `data` field.
+  pub fn to_bytes(&self) -> Bytes {
+    let mut buffer = String::new();
+    if let Some(id) = &self.id {
+      buffer.push_str(&format!("id: {}\n", strip_line_breaks(id)));
+    }
+    if let Some(event) = &self.event {
+      buffer.push_str(&format!("event: {}\n", strip_line_breaks(event)));
+    }
+    if let Some(retry) = self.retry {
+      buffer.push_str(&format!("retry: {}\n", retry));
+    }
+    for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+      buffer.push_str(&format!("data: {}\n", line));
+    }
+    buffer.push('\n');
+    Bytes::from(buffer)
+  }
+}
+
+fn strip_line_breaks(value: &str) -> String {
+  value.chars().filter(|ch| *ch != '\r' && *ch != '\n').collect()
+}
+
+/// Creates a resource that streams Server-Sent Events to the client. The factory function is
+/// called for each request with the value of the `Last-Event-ID` header (if the client is
+/// reconnecting), and must return the stream of events to send.
+///
+/// The response is sent as `text/event-stream` with caching and proxy buffering disabled, and is
+/// never encoded (compressed), as that would delay the events being delivered to the client.
+pub fn sse_resource<'a, F>(factory: F) -> WebmachineResource<'a>
+  where F: Fn(Option<String>) -> EventStream + Send + Sync + 'a {
+  WebmachineResource {
+    allowed_methods: vec!["OPTIONS", "GET", "HEAD"],
+    produces: vec!["text/event-stream"],
+    encodings_provided: vec!["identity"],
+    render_response_stream: owned_callback(move |context: &mut WebmachineContext, _: &WebmachineResource| {
+      let last_event_id = last_event_id(context);
+      context.response.add_header("Content-Type", vec![h!("text/event-stream")]);
+      context.response.add_header("Cache-Control", vec![h!("no-cache")]);
+      context.response.add_header("X-Accel-Buffering", vec![h!("no")]);
+      let stream: BodyStream = Box::pin(factory(last_event_id)
+        .map(|event| Ok::<_, Box<dyn Error + Send + Sync>>(event.to_bytes())));
+      Some(stream)
+    }),
+    .. WebmachineResource::default()
+  }
+}
+
+fn last_event_id(context: &WebmachineContext) -> Option<String> {
+  let values = context.request.find_header("Last-Event-ID");
+  if values.is_empty() {
+    None
+  } else {
+    Some(values.iter().map(HeaderValue::to_string).join(", "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+
+  use crate::context::{WebmachineContext, WebmachineRequest};
+
+  use super::*;
+
+  #[test]
+  fn event_to_bytes_test() {
+    expect!(SseEvent::new("hello").to_bytes()).to(be_equal_to(Bytes::from("data: hello\n\n")));
+    expect!(SseEvent::new("line 1\nline 2").with_id("1").with_event("update").to_bytes())
+      .to(be_equal_to(Bytes::from("id: 1\nevent: update\ndata: line 1\ndata: line 2\n\n")));
+    expect!(SseEvent { retry: Some(1000), .. SseEvent::default() }.to_bytes())
+      .to(be_equal_to(Bytes::from("retry: 1000\ndata: \n\n")));
+  }
+
+  #[test]
+  fn event_to_bytes_removes_line_breaks_from_the_id_and_event_type() {
+    expect!(SseEvent::new("hello").with_id("1\r\ndata: injected").to_bytes())
+      .to(be_equal_to(Bytes::from("id: 1data: injected\ndata: hello\n\n")));
+    expect!(SseEvent::new("hello").with_event("update\nretry: 1").to_bytes())
+      .to(be_equal_to(Bytes::from("event: updateretry: 1\ndata: hello\n\n")));
+    expect!(SseEvent::new("hello").with_event("update\r").to_bytes())
+      .to(be_equal_to(Bytes::from("event: update\ndata: hello\n\n")));
+  }
+
+  #[test]
+  fn event_to_bytes_splits_data_on_all_line_endings() {
+    expect!(SseEvent::new("line 1\r\nline 2").to_bytes())
+      .to(be_equal_to(Bytes::from("data: line 1\ndata: line 2\n\n")));
+    expect!(SseEvent::new("line 1\nline 2").to_bytes())
+      .to(be_equal_to(Bytes::from("data: line 1\ndata: line 2\n\n")));
+    expect!(SseEvent::new("line 1\rline 2").to_bytes())
+      .to(be_equal_to(Bytes::from("data: line 1\ndata: line 2\n\n")));
+    expect!(SseEvent::new("line 1\r\n").to_bytes())
+      .to(be_equal_to(Bytes::from("data: line 1\ndata: \n\n")));
+  }
+
+  #[test]
+  fn sse_resource_emits_events() {
+    let resource = sse_resource(|last_event_id| {
+      let start = last_event_id.and_then(|id| id.parse::<u32>().ok()).unwrap_or(0);
+      let events = (start + 1..=start + 2)
+        .map(|id| SseEvent::new(&format!("event {}", id)).with_id(&id.to_string()))
+        .collect::<Vec<_>>();
+      Box::pin(futures::stream::iter(events))
+    });
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        headers: hashmap! {
+          "Accept".to_string() => vec![h!("text/event-stream")],
+          "Last-Event-ID".to_string() => vec![h!("4")]
+        },
+        .. WebmachineRequest::default()
+      },
+      .. WebmachineContext::default()
+    };
+
+    let stream = resource.render_response_stream.lock().unwrap()(&mut context, &resource).unwrap();
+    expect!(context.response.headers.get("Content-Type").cloned()).to(be_some().value(vec![h!("text/event-stream")]));
+    expect!(context.response.headers.get("Cache-Control").cloned()).to(be_some().value(vec![h!("no-cache")]));
+
+    let chunks = futures::executor::block_on(stream.map(|chunk| chunk.unwrap()).collect::<Vec<_>>());
+    expect!(chunks).to(be_equal_to(vec![
+      Bytes::from("id: 5\ndata: event 5\n\n"),
+      Bytes::from("id: 6\ndata: event 6\n\n")
+    ]));
+  }
+}
@@ -1,14 +1,21 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use chrono::*;
 use expectest::prelude::*;
+use hyper::body::Bytes;
 use maplit::btreemap;
 
 use super::*;
 use super::{
-  execute_state_machine,
-  finalise_response,
+  execute_state_machine as execute_state_machine_with_limit,
+  finalise_response as finalise_response_async,
+  run as run_async,
   join_paths,
+  headers_from_http_request,
+  normalise_path,
+  percent_decode,
   parse_header_values,
   update_paths_for_resource,
 };
@@ -24,9 +31,22 @@ fn resource(path: &str) -> WebmachineRequest {
     headers: HashMap::new(),
     body: None,
     query: HashMap::new(),
+    http_version: "HTTP/1.1".to_string(),
   }
 }
 
+fn execute_state_machine(context: &mut WebmachineContext, resource: &WebmachineResource) {
+  futures::executor::block_on(execute_state_machine_with_limit(context, resource, MAX_STATE_MACHINE_TRANSITIONS))
+}
+
+fn finalise_response(context: &mut WebmachineContext, resource: &WebmachineResource) {
+  futures::executor::block_on(finalise_response_async(context, resource))
+}
+
+fn run(resource: &WebmachineResource, request: WebmachineRequest) -> WebmachineResponse {
+  futures::executor::block_on(run_async(resource, request))
+}
+
 #[test]
 fn path_matcher_test() {
   let dispatcher = WebmachineDispatcher {
@@ -35,7 +55,8 @@ fn path_matcher_test() {
       "/path1" => WebmachineResource::default(),
       "/path2" => WebmachineResource::default(),
       "/path1/path3" => WebmachineResource::default()
-    }
+    },
+    ..WebmachineDispatcher::default()
   };
   expect!(dispatcher.match_paths(&resource("/path1"))).to(be_equal_to(vec!["/", "/path1"]));
   expect!(dispatcher.match_paths(&resource("/path1/"))).to(be_equal_to(vec!["/", "/path1"]));
@@ -46,25 +67,267 @@ fn path_matcher_test() {
   expect!(dispatcher.match_paths(&resource("/"))).to(be_equal_to(vec!["/"]));
 }
 
+#[test]
+fn path_matcher_with_templated_routes_test() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/users" => WebmachineResource::default(),
+      "/users/{id}" => WebmachineResource::default(),
+      "/users/{id}/posts/{post_id}" => WebmachineResource::default(),
+      "/users/me" => WebmachineResource::default()
+    },
+    ..WebmachineDispatcher::default()
+  };
+  expect!(dispatcher.match_paths(&resource("/users/100"))).to(be_equal_to(vec!["/users", "/users/{id}"]));
+  expect!(dispatcher.match_paths(&resource("/users/me"))).to(be_equal_to(vec!["/users", "/users/me", "/users/{id}"]));
+  expect!(dispatcher.match_paths(&resource("/users/100/posts/200"))).to(be_equal_to(vec![
+    "/users", "/users/{id}", "/users/{id}/posts/{post_id}"
+  ]));
+  expect!(dispatcher.match_paths(&resource("/users/100/posts"))).to(be_equal_to(vec!["/users", "/users/{id}"]));
+}
+
+#[test]
+fn dispatcher_populates_path_params_from_templated_routes() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/users/{id}" => WebmachineResource::default(),
+      "/users/{id}/posts/{post_id}" => WebmachineResource::default(),
+      "/users/me" => WebmachineResource::default()
+    },
+    ..WebmachineDispatcher::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: resource("/users/100/posts/200/comments"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.path_params).to(be_equal_to(hashmap!{
+    "id".to_string() => "100".to_string(),
+    "post_id".to_string() => "200".to_string()
+  }));
+  expect!(context.request.base_path).to(be_equal_to("/users/100/posts/200".to_string()));
+  expect!(context.request.request_path).to(be_equal_to("/comments".to_string()));
+  expect!(context.matched_route).to(be_some().value("/users/{id}/posts/{post_id}"));
+
+  let mut context = WebmachineContext {
+    request: resource("/users/me"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.path_params.is_empty()).to(be_true());
+  expect!(context.request.base_path).to(be_equal_to("/users/me".to_string()));
+}
+
+#[test]
+fn dispatcher_captures_the_rest_of_the_path_with_splat_routes() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/static" => WebmachineResource::default(),
+      "/static/*rest" => WebmachineResource::default(),
+      "/static/index.html" => WebmachineResource::default()
+    },
+    ..WebmachineDispatcher::default()
+  };
+
+  expect!(dispatcher.matching_route(&resource("/static/css/site.css"))).to(be_some().value("/static/*rest"));
+  expect!(dispatcher.matching_route(&resource("/static/index.html"))).to(be_some().value("/static/index.html"));
+  expect!(dispatcher.matching_route(&resource("/static"))).to(be_some().value("/static"));
+
+  let mut context = WebmachineContext {
+    request: resource("/static/css/site.css"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.path_params).to(be_equal_to(hashmap!{
+    "rest".to_string() => "css/site.css".to_string()
+  }));
+  expect!(context.request.base_path).to(be_equal_to("/static".to_string()));
+  expect!(context.request.request_path).to(be_equal_to("/css/site.css".to_string()));
+}
+
+#[test]
+fn dispatcher_matches_routes_against_percent_decoded_path_segments() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/users/John Doe" => WebmachineResource::default(),
+      "/files/{name}" => WebmachineResource::default(),
+      "/files/a/b" => WebmachineResource::default()
+    },
+    ..WebmachineDispatcher::default()
+  };
+
+  expect!(dispatcher.match_paths(&resource("/users/John%20Doe"))).to(be_equal_to(vec!["/users/John Doe"]));
+
+  let mut context = WebmachineContext {
+    request: resource("/users/John%20Doe/avatar"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.request.base_path).to(be_equal_to("/users/John%20Doe".to_string()));
+  expect!(context.request.request_path).to(be_equal_to("/avatar".to_string()));
+
+  expect!(dispatcher.match_paths(&resource("/files/a%2Fb"))).to(be_equal_to(vec!["/files/{name}"]));
+  let mut context = WebmachineContext {
+    request: resource("/files/a%2Fb"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.path_params).to(be_equal_to(hashmap!{
+    "name".to_string() => "a/b".to_string()
+  }));
+}
+
+#[test]
+fn percent_decode_test() {
+  expect!(percent_decode("John%20Doe", false)).to(be_equal_to("John Doe".to_string()));
+  expect!(percent_decode("a+b", false)).to(be_equal_to("a+b".to_string()));
+  expect!(percent_decode("a+b", true)).to(be_equal_to("a b".to_string()));
+  expect!(percent_decode("caf%C3%A9", false)).to(be_equal_to("café".to_string()));
+  expect!(percent_decode("100%", false)).to(be_equal_to("100%".to_string()));
+  expect!(percent_decode("%zz%2", false)).to(be_equal_to("%zz%2".to_string()));
+}
+
+#[test]
+fn path_segments_decodes_each_segment() {
+  let request = resource("/files/a%2Fb/John%20Doe/");
+  expect!(request.path_segments()).to(be_equal_to(vec!["files".to_string(), "a/b".to_string(), "John Doe".to_string()]));
+}
+
+#[test]
+fn routes_summary_test() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path1" => WebmachineResource::default(),
+      "/path2" => WebmachineResource {
+        allowed_methods: vec!["POST", "PUT"],
+        ..WebmachineResource::default()
+      },
+      "/path3" => WebmachineResource {
+        allow_head: false,
+        ..WebmachineResource::default()
+      },
+      "/path4" => WebmachineResource {
+        allowed_methods_fn: Some(callback(&|_, _| vec!["GET".to_string()])),
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+  expect!(dispatcher.routes_summary()).to(be_equal_to(vec![
+    RouteSummary {
+      path: "/path1".to_string(),
+      methods: vec!["OPTIONS".to_string(), "GET".to_string(), "HEAD".to_string()],
+      dynamic_methods: false
+    },
+    RouteSummary {
+      path: "/path2".to_string(),
+      methods: vec!["POST".to_string(), "PUT".to_string()],
+      dynamic_methods: false
+    },
+    RouteSummary {
+      path: "/path3".to_string(),
+      methods: vec!["OPTIONS".to_string(), "GET".to_string()],
+      dynamic_methods: false
+    },
+    RouteSummary {
+      path: "/path4".to_string(),
+      methods: vec!["OPTIONS".to_string(), "GET".to_string(), "HEAD".to_string()],
+      dynamic_methods: true
+    }
+  ]));
+}
+
+#[test]
+fn dispatcher_with_owned_routes_test() {
+  let routes = ["users", "orders"].iter()
+    .map(|name| (format!("/api/{}", name), WebmachineResource::default()))
+    .collect::<BTreeMap<_, _>>();
+  let dispatcher = WebmachineDispatcher::new(routes);
+  expect!(dispatcher.routes_summary().into_iter().map(|route| route.path).collect::<Vec<_>>())
+    .to(be_equal_to(vec!["/api/orders".to_string(), "/api/users".to_string()]));
+
+  let mut context = WebmachineContext {
+    request: resource("/api/users/100"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.response.status).to(be_equal_to(200));
+  expect!(context.request.base_path).to(be_equal_to("/api/users".to_string()));
+
+  let mut context = WebmachineContext {
+    request: resource("/api/other"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.response.status).to(be_equal_to(404));
+}
+
 #[test]
 fn sanitise_path_test() {
-  expect!(sanitise_path(&"/".to_string()).iter()).to(be_empty());
-  expect!(sanitise_path(&"//".to_string()).iter()).to(be_empty());
-  expect!(sanitise_path(&"/a/b/c".to_string())).to(be_equal_to(vec!["a", "b", "c"]));
-  expect!(sanitise_path(&"/a/b/c/".to_string())).to(be_equal_to(vec!["a", "b", "c"]));
-  expect!(sanitise_path(&"/a//b/c".to_string())).to(be_equal_to(vec!["a", "b", "c"]));
+  expect!(sanitise_path("/").iter()).to(be_empty());
+  expect!(sanitise_path("//").iter()).to(be_empty());
+  expect!(sanitise_path("/a/b/c")).to(be_equal_to(vec!["a", "b", "c"]));
+  expect!(sanitise_path("/a/b/c/")).to(be_equal_to(vec!["a", "b", "c"]));
+  expect!(sanitise_path("/a//b/c")).to(be_equal_to(vec!["a", "b", "c"]));
 }
 
 #[test]
 fn dispatcher_returns_404_if_there_is_no_matching_resource() {
   let mut context = WebmachineContext::default();
   let displatcher = WebmachineDispatcher {
-    routes: btreemap! { "/some/path" => WebmachineResource::default() }
+    routes: btreemap! { "/some/path" => WebmachineResource::default() },
+    ..WebmachineDispatcher::default()
   };
-  displatcher.dispatch_to_resource(&mut context);
+  futures::executor::block_on(displatcher.dispatch_to_resource(&mut context));
   expect(context.response.status).to(be_equal_to(404));
 }
 
+#[test]
+fn execute_state_machine_returns_500_if_the_maximum_transitions_are_exceeded() {
+  let mut context = WebmachineContext::default();
+  futures::executor::block_on(execute_state_machine_with_limit(&mut context, &WebmachineResource::default(), 5));
+  expect(context.response.status).to(be_equal_to(500));
+  expect(context.decisions.last().unwrap().2.as_str()).to(be_equal_to("End(500)"));
+
+  let mut context = WebmachineContext {
+    request: resource("/path"),
+    ..WebmachineContext::default()
+  };
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! { "/path" => WebmachineResource::default() },
+    max_transitions: 5,
+    ..WebmachineDispatcher::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect(context.response.status).to(be_equal_to(500));
+}
+
+#[test]
+fn dispatcher_counts_the_response_status_codes() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path" => WebmachineResource::default(),
+      "/missing" => WebmachineResource {
+        resource_exists: callback(&|_, _| false),
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+  for path in ["/path", "/missing", "/path", "/other"] {
+    let mut context = WebmachineContext {
+      request: resource(path),
+      ..WebmachineContext::default()
+    };
+    futures::executor::block_on(dispatcher.clone().dispatch_to_resource(&mut context));
+  }
+  expect!(dispatcher.status_counters()).to(be_equal_to(hashmap!{
+    200 => 2,
+    404 => 2
+  }));
+}
+
 #[test]
 fn execute_state_machine_returns_503_if_resource_indicates_not_available() {
   let mut context = WebmachineContext::default();
@@ -106,6 +369,33 @@ fn update_paths_for_resource_on_path() {
   expect(request.base_path).to(be_equal_to("/path".to_string()));
 }
 
+#[test]
+fn normalise_path_test() {
+  expect!(normalise_path("/")).to(be_equal_to("/".to_string()));
+  expect!(normalise_path("//")).to(be_equal_to("/".to_string()));
+  expect!(normalise_path("/a//b")).to(be_equal_to("/a/b".to_string()));
+  expect!(normalise_path("//a///b/")).to(be_equal_to("/a/b/".to_string()));
+}
+
+#[test]
+fn update_paths_for_resource_removes_duplicate_slashes() {
+  let mut request = WebmachineRequest {
+    request_path: "/a//b".to_string(),
+    ..WebmachineRequest::default()
+  };
+  update_paths_for_resource(&mut request, "/a");
+  expect(request.request_path).to(be_equal_to("/b".to_string()));
+  expect(request.base_path).to(be_equal_to("/a".to_string()));
+
+  let mut request = WebmachineRequest {
+    request_path: "//a/b".to_string(),
+    ..WebmachineRequest::default()
+  };
+  update_paths_for_resource(&mut request, "/a");
+  expect(request.request_path).to(be_equal_to("/b".to_string()));
+  expect(request.base_path).to(be_equal_to("/a".to_string()));
+}
+
 #[test]
 fn update_paths_for_resource_on_path_with_subpath() {
   let mut request = WebmachineRequest {
@@ -132,411 +422,458 @@ fn execute_state_machine_returns_501_if_method_is_not_in_known_list() {
 }
 
 #[test]
-fn execute_state_machine_returns_414_if_uri_is_too_long() {
-  let mut context = WebmachineContext::default();
-  let resource = WebmachineResource {
-    uri_too_long: callback(&|_, _| true),
-    ..WebmachineResource::default()
-  };
-  execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(414));
-}
-
-#[test]
-fn execute_state_machine_returns_405_if_method_is_not_allowed() {
+fn execute_state_machine_awaits_async_callbacks() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "TRACE".to_string(),
+      method: "POST".to_string(),
+      body: Some("body".as_bytes().to_vec()),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource::default();
-  execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(405));
-  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
-    HeaderValue::basic("OPTIONS"),
-    HeaderValue::basic("GET"),
-    HeaderValue::basic("HEAD")
-  ]));
-}
-
-#[test]
-fn execute_state_machine_returns_400_if_malformed_request() {
-  let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    malformed_request: callback(&|_, _| true),
+    allowed_methods: vec!["POST"],
+    resource_exists: callback(&|_, _| false),
+    resource_exists_async: Some(async_callback(|_, _| futures::future::ready(true))),
+    process_post_async: Some(async_callback(|context, _| {
+      let body = context.request.body.clone();
+      async move { if body.is_some() { Ok(true) } else { Err(400.into()) } }
+    })),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(400));
-}
+  expect(context.response.status).to(be_equal_to(204));
 
-#[test]
-fn execute_state_machine_returns_401_if_not_authorized() {
   let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    not_authorized: callback(&|_, _| Some("Basic realm=\"User Visible Realm\"".to_string())),
+    render_response_async: Some(async_callback(|_, _| async { Some("rendered".to_string()) })),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(401));
-  expect(context.response.headers.get("WWW-Authenticate").unwrap().clone()).to(be_equal_to(vec![
-    HeaderValue::basic(&"Basic realm=\"User Visible Realm\"".to_string())
-  ]));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.body).to(be_some().value("rendered".as_bytes().to_vec()));
 }
 
 #[test]
-fn execute_state_machine_returns_403_if_forbidden() {
+fn execute_state_machine_awaits_the_async_versions_of_the_decision_callbacks() {
   let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    forbidden: callback(&|_, _| true),
+    not_authorized_async: Some(async_callback(|_, _| async { Some("Basic realm=\"test\"".to_string()) })),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(403));
-}
+  expect(context.response.status).to(be_equal_to(401));
 
-#[test]
-fn execute_state_machine_returns_501_if_there_is_an_unsupported_content_header() {
   let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    unsupported_content_headers: callback(&|_, _| true),
+    forbidden_async: Some(async_callback(|_, _| futures::future::ready(true))),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(501));
-}
+  expect(context.response.status).to(be_equal_to(403));
 
-#[test]
-fn execute_state_machine_returns_415_if_the_content_type_is_unknown() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
-      headers: hashmap! {
-        "Content-type".to_string() => vec![HeaderValue::basic(&"application/xml".to_string())]
-      },
+      headers: hashmap!{ "If-None-Match".to_string() => vec![h!("\"1234\"")] },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    acceptable_content_types: vec!["application/json"],
-    allowed_methods: vec!["POST"],
+    generate_etag_async: Some(async_callback(|_, _| async { Some("1234".to_string()) })),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(415));
-}
+  expect(context.response.status).to(be_equal_to(304));
 
-#[test]
-fn execute_state_machine_returns_does_not_return_415_if_not_a_put_or_post() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Content-type".to_string() => vec![HeaderValue::basic(&"application/xml".to_string())]
-      },
+      method: "PUT".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    is_conflict_async: Some(async_callback(|_, _| futures::future::ready(true))),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to_not(be_equal_to(415));
+  expect(context.response.status).to(be_equal_to(409));
 }
 
 #[test]
-fn parse_header_test() {
-  expect(parse_header_values("").iter()).to(be_empty());
-  expect(parse_header_values("HEADER A")).to(be_equal_to(vec!["HEADER A".to_string()]));
-  expect(parse_header_values("HEADER A, header B"))
-    .to(be_equal_to(vec!["HEADER A".to_string(), "header B".to_string()]));
-  expect(parse_header_values("text/plain;  q=0.5,   text/html,text/x-dvi; q=0.8, text/x-c"))
-    .to(be_equal_to(vec![
-      HeaderValue { value: "text/plain".to_string(), params: hashmap! {"q".to_string() => "0.5".to_string()}, quote: false },
-      HeaderValue { value: "text/html".to_string(), params: hashmap! {}, quote: false },
-      HeaderValue { value: "text/x-dvi".to_string(), params: hashmap! {"q".to_string() => "0.8".to_string()}, quote: false },
-      HeaderValue { value: "text/x-c".to_string(), params: hashmap! {}, quote: false }
-    ]));
-}
+fn cacheable_returns_304_for_a_matching_etag_without_rendering_the_response() {
+  let renders = std::sync::atomic::AtomicUsize::new(0);
+  let render = |_: &mut WebmachineContext, _: &WebmachineResource| {
+    renders.fetch_add(1, Ordering::SeqCst);
+    Some("{\"version\": 1}".to_string())
+  };
+  let resource = WebmachineResource::default()
+    .cacheable(callback(&|_, _| Some("v1".to_string())), callback(&render));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  let etag = context.response.headers.get("ETag").unwrap()[0].to_string();
+  expect(etag.as_str()).to(be_equal_to("\"v1\""));
+  expect(renders.load(Ordering::SeqCst)).to(be_equal_to(1));
 
-#[test]
-fn execute_state_machine_returns_413_if_the_request_entity_is_too_large() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! { "If-None-Match".to_string() => parse_header_values(&etag) },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    valid_entity_length: callback(&|_, _| false),
-    allowed_methods: vec!["POST"],
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(413));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+  expect(context.response.body).to(be_none());
+  expect(renders.load(Ordering::SeqCst)).to(be_equal_to(1));
 }
 
 #[test]
-fn execute_state_machine_returns_does_not_return_413_if_not_a_put_or_post() {
+fn produces_map_renders_the_response_for_the_negotiated_media_type() {
+  let resource = WebmachineResource::default().produces_map(vec![
+    ("application/json", callback(&|_, _| Some("{\"id\": 1}".to_string()))),
+    ("application/xml", callback(&|_, _| Some("<item id=\"1\"/>".to_string())))
+  ]);
+  expect!(resource.produces.clone()).to(be_equal_to(vec!["application/json", "application/xml"]));
+
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      headers: hashmap! { "Accept".to_string() => vec![h!("application/xml")] },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    valid_entity_length: callback(&|_, _| false),
-    ..WebmachineResource::default()
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Type").unwrap()[0].value.as_str()).to(be_equal_to("application/xml"));
+  expect(context.response.body).to(be_some().value("<item id=\"1\"/>".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! { "Accept".to_string() => vec![h!("application/json")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to_not(be_equal_to(413));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Type").unwrap()[0].value.as_str()).to(be_equal_to("application/json"));
+  expect(context.response.body).to(be_some().value("{\"id\": 1}".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.body).to(be_some().value("{\"id\": 1}".as_bytes().to_vec()));
 }
 
 #[test]
-fn execute_state_machine_returns_headers_for_option_request() {
+fn execute_state_machine_treats_additional_methods_as_known_and_allowed() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "OPTIONS".to_string(),
+      method: "PURGE".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    allowed_methods: vec!["OPTIONS"],
-    options: callback(&|_, _| Some(hashmap! {
-      "A".to_string() => vec!["B".to_string()],
-      "C".to_string() => vec!["D;E=F".to_string()],
-    })),
-    ..WebmachineResource::default()
-  };
+  let resource = WebmachineResource::default()
+    .with_additional_known_methods(&["PURGE", "GET"]);
+  expect!(resource.known_methods.iter().filter(|m| **m == "GET").count()).to(be_equal_to(1));
+  expect!(resource.known_methods.last()).to(be_some().value(&"PURGE"));
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(204));
-  expect(context.response.headers.get("A").unwrap().clone()).to(be_equal_to(vec!["B".to_string()]));
-  expect(context.response.headers.get("C").unwrap().clone()).to(be_equal_to(vec!["D;E=F".to_string()]));
-}
+  expect(context.response.status).to(be_equal_to(405));
 
-#[test]
-fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_content_type() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept".to_string() => vec![HeaderValue::basic(&"application/xml".to_string())]
-      },
+      method: "PURGE".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
+  let resource = resource.with_additional_allowed_methods(&["PURGE"]);
+  expect!(resource.allowed_methods.clone()).to(be_equal_to(vec!["OPTIONS", "GET", "HEAD", "PURGE"]));
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn execute_state_machine_returns_414_if_uri_is_too_long() {
+  let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    produces: vec!["application/javascript"],
+    uri_too_long: callback(&|_, _| true),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(406));
+  expect(context.response.status).to(be_equal_to(414));
 }
 
 #[test]
-fn execute_state_machine_sets_content_type_header_if_the_request_does_have_an_acceptable_content_type() {
+fn execute_state_machine_returns_405_if_method_is_not_allowed() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept".to_string() => vec![HeaderValue::basic(&"application/xml".to_string())]
-      },
+      method: "TRACE".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET"),
+    HeaderValue::basic("HEAD")
+  ]));
+}
+
+#[test]
+fn execute_state_machine_returns_406_if_an_accept_header_accepts_nothing() {
   let resource = WebmachineResource {
-    produces: vec!["application/xml"],
+    produces: vec!["application/json"],
+    languages_provided: vec!["en"],
+    charsets_provided: vec!["UTF-8"],
+    encodings_provided: vec!["identity", "gzip"],
     ..WebmachineResource::default()
   };
-  execute_state_machine(&mut context, &resource);
-  finalise_response(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(200));
-  expect(context.response.headers.get("Content-Type").unwrap()).to(be_equal_to(&vec![h!("application/xml;charset=ISO-8859-1")]));
+  let headers = vec![
+    ("Accept", "*/*;q=0"),
+    ("Accept", "application/json;q=0, text/plain;q=0"),
+    ("Accept-Language", "*;q=0"),
+    ("Accept-Language", "en;q=0"),
+    ("Accept-Charset", "*;q=0"),
+    ("Accept-Charset", "UTF-8;q=0, ISO-8859-1;q=0"),
+    ("Accept-Encoding", "*;q=0"),
+    ("Accept-Encoding", "gzip;q=0, identity;q=0")
+  ];
+  for (header, value) in headers {
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        headers: hashmap! { header.to_string() => parse_header_values(value) },
+        ..WebmachineRequest::default()
+      },
+      ..WebmachineContext::default()
+    };
+    execute_state_machine(&mut context, &resource);
+    expect!(context.response.status).to(be_equal_to(406));
+  }
+
+  let resource = WebmachineResource::default();
+  for (header, value) in [("Accept-Language", "*;q=0"), ("Accept-Charset", "*;q=0"), ("Accept-Encoding", "*;q=0")] {
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        headers: hashmap! { header.to_string() => parse_header_values(value) },
+        ..WebmachineRequest::default()
+      },
+      ..WebmachineContext::default()
+    };
+    execute_state_machine(&mut context, &resource);
+    expect!(context.response.status).to(be_equal_to(406));
+  }
 }
 
 #[test]
-fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_language() {
-  let mut context = WebmachineContext {
-    request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept-Language".to_string() => vec![HeaderValue::basic(&"da".to_string())]
-      },
-      ..WebmachineRequest::default()
+fn execute_state_machine_parses_the_accept_headers_once() {
+  let request = WebmachineRequest {
+    headers: hashmap! {
+      "Accept".to_string() => parse_header_values("text/html;q=0.5, application/json"),
+      "Accept-Language".to_string() => vec![h!("en-AU")],
+      "Accept-Encoding".to_string() => vec![h!("gzip")]
     },
-    ..WebmachineContext::default()
+    ..WebmachineRequest::default()
   };
   let resource = WebmachineResource {
+    produces: vec!["text/html", "application/json"],
     languages_provided: vec!["en"],
+    encodings_provided: vec!["identity", "gzip"],
     ..WebmachineResource::default()
   };
+  let mut context = WebmachineContext {
+    request: request.clone(),
+    ..WebmachineContext::default()
+  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(406));
+  expect!(context.response.status).to(be_equal_to(200));
+  expect!(context.selected_media_type.clone()).to(be_some().value(content_negotiation::matching_content_type(&resource, &request).unwrap()));
+  expect!(context.selected_language.clone()).to(be_some().value("en"));
+  expect!(context.selected_encoding.clone()).to(be_some().value("gzip"));
+  expect!(context.accept_headers.clone()).to(be_some().value(content_negotiation::AcceptHeaders::parse(&request)));
+
+  // Headers that have already been parsed are not parsed again
+  let mut context = WebmachineContext {
+    request,
+    accept_headers: Some(content_negotiation::AcceptHeaders {
+      media_types: Some(vec![h!("text/html")]),
+      .. content_negotiation::AcceptHeaders::default()
+    }),
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect!(context.selected_media_type).to(be_some().value("text/html"));
 }
 
 #[test]
-fn execute_state_machine_sets_the_language_header_if_the_request_does_have_an_acceptable_language() {
+fn execute_state_machine_returns_405_for_head_if_head_is_not_allowed() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept-Language".to_string() => vec![HeaderValue::basic(&"en-gb".to_string())]
-      },
+      method: "HEAD".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    languages_provided: vec!["en"],
+    allow_head: false,
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET")
+  ]));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
   expect(context.response.status).to(be_equal_to(200));
-  expect(context.response.headers).to(be_equal_to(btreemap! { "Content-Language".to_string() => vec![h!("en")] }));
 }
 
 #[test]
-fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_charset() {
+fn execute_state_machine_returns_allowed_methods_for_options_request_by_default() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept-Charset".to_string() => vec![h!("iso-8859-5"), h!("iso-8859-1;q=0")]
-      },
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    charsets_provided: vec!["UTF-8", "US-ASCII"],
-    ..WebmachineResource::default()
-  };
+  let resource = WebmachineResource::default();
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(406));
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET"),
+    HeaderValue::basic("HEAD")
+  ]));
 }
 
 #[test]
-fn execute_state_machine_sets_the_charset_if_the_request_does_have_an_acceptable_charset() {
+fn execute_state_machine_uses_the_allowed_methods_callback_if_set() {
+  let resource = WebmachineResource {
+    allowed_methods_fn: Some(callback(&|context, _| if context.request.has_header("Authorization") {
+      vec!["OPTIONS".to_string(), "GET".to_string(), "DELETE".to_string()]
+    } else {
+      vec!["OPTIONS".to_string(), "GET".to_string()]
+    })),
+    ..WebmachineResource::default()
+  };
+
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept-Charset".to_string() => vec![h!("UTF-8"), h!("iso-8859-1;q=0")]
-      },
+      method: "DELETE".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    charsets_provided: vec!["UTF-8", "US-ASCII"],
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  finalise_response(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(200));
-  expect(context.response.headers.get("Content-Type").unwrap()).to(be_equal_to(&vec![h!("application/json;charset=UTF-8")]));
-}
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET")
+  ]));
 
-#[test]
-fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_encoding() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "Accept-Encoding".to_string() => vec![h!("compress"), h!("*;q=0")]
-      },
+      method: "DELETE".to_string(),
+      headers: hashmap!{ "Authorization".to_string() => vec![h!("Bearer token")] },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    encodings_provided: vec!["identity"],
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(406));
+  expect(context.response.status).to(be_equal_to(204));
 }
 
 #[test]
-fn execute_state_machine_sets_the_vary_header_if_the_resource_has_variances() {
+fn execute_state_machine_returns_204_for_options_request_on_a_nonexistent_resource_by_default() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    variances: vec!["HEADER-A", "HEADER-B"],
+    resource_exists: callback(&|_, _| false),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  finalise_response(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(200));
-  expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Content-Type".to_string() => vec![h!("application/json;charset=ISO-8859-1")],
-    "Vary".to_string() => vec![h!("HEADER-A"), h!("HEADER-B")]
-  }));
+  expect(context.response.status).to(be_equal_to(204));
 }
 
 #[test]
-fn execute_state_machine_returns_404_if_the_resource_does_not_exist() {
+fn execute_state_machine_returns_404_for_options_request_on_a_nonexistent_resource_if_required() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
     resource_exists: callback(&|_, _| false),
+    options_requires_existing_resource: true,
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
   expect(context.response.status).to(be_equal_to(404));
-}
+  expect(context.response.headers.get("Allow")).to(be_none());
 
-#[test]
-fn execute_state_machine_returns_412_if_the_resource_does_not_exist_and_there_is_an_if_match_header() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "If-Match".to_string() => vec![h!("*")]
-      },
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| false),
+    options_requires_existing_resource: true,
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(412));
+  expect(context.response.status).to(be_equal_to(204));
 }
 
 #[test]
-fn execute_state_machine_returns_301_and_sets_location_header_if_the_resource_has_moved_permanently() {
+fn execute_state_machine_allow_header_lists_known_methods_for_options_and_allowed_methods_for_405() {
+  let resource = WebmachineResource {
+    known_methods: vec!["OPTIONS", "GET", "HEAD", "PUT"],
+    options_allow_known_methods: true,
+    ..WebmachineResource::default()
+  };
+
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "PUT".to_string(),
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    allowed_methods: vec!["PUT"],
-    resource_exists: callback(&|_, _| false),
-    moved_permanently: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(301));
-  expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
-  }));
-}
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET"),
+    HeaderValue::basic("HEAD"),
+    HeaderValue::basic("PUT")
+  ]));
 
-#[test]
-fn execute_state_machine_returns_409_if_the_put_request_is_a_conflict() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       method: "PUT".to_string(),
@@ -544,542 +881,577 @@ fn execute_state_machine_returns_409_if_the_put_request_is_a_conflict() {
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    allowed_methods: vec!["PUT"],
-    resource_exists: callback(&|_, _| false),
-    is_conflict: callback(&|_, _| true),
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(409));
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("OPTIONS"),
+    HeaderValue::basic("GET"),
+    HeaderValue::basic("HEAD")
+  ]));
 }
 
 #[test]
-fn execute_state_machine_returns_404_if_the_resource_does_not_exist_and_does_not_except_posts_to_nonexistant_resources() {
+fn execute_state_machine_options_reports_the_actual_allowed_methods() {
+  let resource = WebmachineResource {
+    allowed_methods_fn: Some(callback(&|_, _| vec!["OPTIONS".to_string(), "GET".to_string(), "DELETE".to_string()])),
+    ..WebmachineResource::default()
+  };
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    h!("OPTIONS"), h!("GET"), h!("DELETE")
+  ]));
+  expect(context.response.headers.get("Access-Control-Allow-Methods").unwrap().clone()).to(be_equal_to(vec![
+    h!("OPTIONS"), h!("GET"), h!("DELETE")
+  ]));
+
   let resource = WebmachineResource {
-    allowed_methods: vec!["POST"],
-    resource_exists: callback(&|_, _| false),
-    allow_missing_post: callback(&|_, _| false),
+    options: callback(&|_, _| Some(hashmap! { "allow".to_string() => vec!["GET".to_string()] })),
     ..WebmachineResource::default()
   };
-  execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(404));
-}
-
-#[test]
-fn execute_state_machine_returns_301_and_sets_location_header_if_the_resource_has_moved_permanently_and_prev_existed_and_not_a_put() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    allowed_methods: vec!["POST"],
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| true),
-    moved_permanently: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
-    ..WebmachineResource::default()
-  };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(301));
+  expect(context.response.status).to(be_equal_to(204));
   expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
+    "allow".to_string() => vec![h!("GET")]
   }));
 }
 
 #[test]
-fn execute_state_machine_returns_307_and_sets_location_header_if_the_resource_has_moved_temporarily_and_not_a_put() {
-  let mut context = WebmachineContext {
-    request: WebmachineRequest {
-      ..WebmachineRequest::default()
-    },
-    ..WebmachineContext::default()
-  };
+fn execute_state_machine_returns_400_if_malformed_request() {
+  let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| true),
-    moved_temporarily: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
+    malformed_request: callback(&|_, _| true),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(307));
-  expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
-  }));
+  expect(context.response.status).to(be_equal_to(400));
 }
 
 #[test]
-fn execute_state_machine_returns_410_if_the_resource_has_prev_existed_and_not_a_post() {
-  let mut context = WebmachineContext {
-    request: WebmachineRequest {
-      ..WebmachineRequest::default()
+fn execute_state_machine_returns_400_for_unknown_query_parameters_only_in_strict_mode() {
+  let request = WebmachineRequest {
+    query: hashmap! {
+      "page".to_string() => vec!["1".to_string()],
+      "foo".to_string() => vec!["1".to_string()]
     },
-    ..WebmachineContext::default()
+    ..WebmachineRequest::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| true),
+    allowed_query_params: vec!["page"],
     ..WebmachineResource::default()
   };
-  execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(410));
-}
+  let mut context = WebmachineContext {
+    request: request.clone(),
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+
+  let resource = WebmachineResource {
+    allowed_query_params: vec!["page"],
+    reject_unknown_query_params: true,
+    ..WebmachineResource::default()
+  };
+  let mut context = WebmachineContext {
+    request,
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(400));
 
-#[test]
-fn execute_state_machine_returns_410_if_the_resource_has_prev_existed_and_a_post_and_posts_to_missing_resource_not_allowed() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      query: hashmap! { "page".to_string() => vec!["1".to_string()] },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn execute_state_machine_returns_401_if_not_authorized() {
+  let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    allowed_methods: vec!["POST"],
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| true),
-    allow_missing_post: callback(&|_, _| false),
+    not_authorized: callback(&|_, _| Some("Basic realm=\"User Visible Realm\"".to_string())),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(410));
+  expect(context.response.status).to(be_equal_to(401));
+  expect(context.response.headers.get("WWW-Authenticate").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("Basic realm=\"User Visible Realm\"".to_string())
+  ]));
 }
 
 #[test]
-fn execute_state_machine_returns_404_if_the_resource_has_not_prev_existed_and_a_post_and_posts_to_missing_resource_not_allowed() {
-  let mut context = WebmachineContext {
-    request: WebmachineRequest {
-      method: "POST".to_string(),
-      ..WebmachineRequest::default()
-    },
-    ..WebmachineContext::default()
+fn execute_state_machine_returns_403_if_forbidden() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    forbidden: callback(&|_, _| true),
+    ..WebmachineResource::default()
   };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(403));
+}
+
+#[test]
+fn execute_state_machine_returns_501_if_there_is_an_unsupported_content_header() {
+  let mut context = WebmachineContext::default();
   let resource = WebmachineResource {
-    allowed_methods: vec!["POST"],
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| false),
-    allow_missing_post: callback(&|_, _| false),
+    unsupported_content_headers: callback(&|_, _| true),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(404));
+  expect(context.response.status).to(be_equal_to(501));
 }
 
 #[test]
-fn execute_state_machine_returns_412_if_the_resource_etag_does_not_match_if_match_header() {
+fn execute_state_machine_returns_415_if_the_content_type_is_unknown() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      method: "POST".to_string(),
       headers: hashmap! {
-        "If-Match".to_string() => vec![h!("\"1234567891\"")]
+        "Content-type".to_string() => vec![HeaderValue::basic("application/xml".to_string())]
       },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    acceptable_content_types: vec!["application/json"],
+    allowed_methods: vec!["POST"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(412));
+  expect(context.response.status).to(be_equal_to(415));
 }
 
 #[test]
-fn execute_state_machine_returns_412_if_the_resource_etag_does_not_match_if_match_header_weak_etag() {
+fn execute_state_machine_uses_the_content_types_accepted_callback_if_set() {
+  let resource = WebmachineResource {
+    allowed_methods: vec!["POST"],
+    process_post: callback(&|_, _| Ok(true)),
+    content_types_accepted_fn: Some(callback(&|context, _| {
+      let content_type = context.request.content_type().to_lowercase();
+      content_type == "application/json" || (content_type.starts_with("application/") && content_type.ends_with("+json"))
+    })),
+    ..WebmachineResource::default()
+  };
+  for (content_type, status) in [("application/vnd.api+json", 204), ("application/json", 204), ("application/xml", 415)] {
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        method: "POST".to_string(),
+        headers: hashmap! {
+          "Content-Type".to_string() => vec![HeaderValue::basic(content_type)]
+        },
+        ..WebmachineRequest::default()
+      },
+      ..WebmachineContext::default()
+    };
+    execute_state_machine(&mut context, &resource);
+    expect!(context.response.status).to(be_equal_to(status));
+  }
+}
+
+#[test]
+fn execute_state_machine_returns_415_if_the_content_type_of_a_patch_is_unknown() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      method: "PATCH".to_string(),
       headers: hashmap! {
-        "If-Match".to_string() => vec![h!("W/\"1234567891\"")]
+        "Content-type".to_string() => vec![HeaderValue::basic("application/xml".to_string())]
       },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    acceptable_content_types: vec!["application/json"],
+    allowed_methods: vec!["PATCH"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(412));
+  expect(context.response.status).to(be_equal_to(415));
 }
 
 #[test]
-fn execute_state_machine_returns_412_if_the_resource_last_modified_gt_unmodified_since() {
-  let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
-  let datetime = Local::now().with_timezone(&offset);
-  let header_datetime = datetime.clone() - Duration::minutes(5);
+fn execute_state_machine_returns_does_not_return_415_if_not_a_put_or_post() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       headers: hashmap! {
-        "If-Unmodified-Since".to_string() => vec![h!(&*format!("\"{}\"", header_datetime.to_rfc2822()))]
+        "Content-type".to_string() => vec![HeaderValue::basic("application/xml".to_string())]
       },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    last_modified: callback(&|_, _| {
-      let fixed_offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
-      Some(Local::now().with_timezone(&fixed_offset))
-    }),
     ..WebmachineResource::default()
   };
-
   execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to_not(be_equal_to(415));
+}
 
-  expect(context.response.status).to(be_equal_to(412));
+#[test]
+fn parse_header_test() {
+  expect(parse_header_values("").iter()).to(be_empty());
+  expect(parse_header_values("HEADER A")).to(be_equal_to(vec!["HEADER A".to_string()]));
+  expect(parse_header_values("HEADER A, header B"))
+    .to(be_equal_to(vec!["HEADER A".to_string(), "header B".to_string()]));
+  expect(parse_header_values("text/plain;  q=0.5,   text/html,text/x-dvi; q=0.8, text/x-c"))
+    .to(be_equal_to(vec![
+      HeaderValue { value: "text/plain".to_string(), params: hashmap! {"q".to_string() => "0.5".to_string()}, quote: false },
+      HeaderValue { value: "text/html".to_string(), params: hashmap! {}, quote: false },
+      HeaderValue { value: "text/x-dvi".to_string(), params: hashmap! {"q".to_string() => "0.8".to_string()}, quote: false },
+      HeaderValue { value: "text/x-c".to_string(), params: hashmap! {}, quote: false }
+    ]));
 }
 
 #[test]
-fn execute_state_machine_returns_304_if_non_match_star_exists_and_is_not_a_head_or_get() {
+fn execute_state_machine_returns_413_if_the_request_entity_is_too_large() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       method: "POST".to_string(),
-      headers: hashmap! {
-        "If-None-Match".to_string() => vec![h!("*")]
-      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
+    valid_entity_length: callback(&|_, _| false),
     allowed_methods: vec!["POST"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(412));
+  expect(context.response.status).to(be_equal_to(413));
 }
 
 #[test]
-fn execute_state_machine_returns_304_if_non_match_star_exists_and_is_a_head_or_get() {
+fn execute_state_machine_returns_413_if_the_declared_content_length_is_too_large() {
+  let resource = WebmachineResource {
+    valid_entity_length: callback(&|context, _| context.request.content_length().unwrap_or(0) <= 1024),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "HEAD".to_string(),
-      headers: hashmap! {
-        "If-None-Match".to_string() => vec![h!("*")]
-      },
+      method: "POST".to_string(),
+      headers: hashmap! { "Content-Length".to_string() => vec![h!("4096")] },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
-  let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    allowed_methods: vec!["HEAD"],
-    ..WebmachineResource::default()
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(413));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      headers: hashmap! { "Content-Length".to_string() => vec![h!("512")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(304));
+  expect(context.response.status).to_not(be_equal_to(413));
 }
 
 #[test]
-fn execute_state_machine_returns_412_if_resource_etag_in_if_non_match_and_is_not_a_head_or_get() {
+fn execute_state_machine_returns_does_not_return_413_if_not_a_put_or_post() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
-      headers: hashmap! {
-        "If-None-Match".to_string() => vec![h!("W/\"1234567890\""), h!("W/\"1234567891\"")]
-      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    allowed_methods: vec!["POST"],
-    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    valid_entity_length: callback(&|_, _| false),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(412));
+  expect(context.response.status).to_not(be_equal_to(413));
 }
 
 #[test]
-fn execute_state_machine_returns_304_if_resource_etag_in_if_non_match_and_is_a_head_or_get() {
+fn execute_state_machine_returns_headers_for_option_request() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      headers: hashmap! {
-        "If-None-Match".to_string() => vec![h!("\"1234567890\""), h!("\"1234567891\"")]
-      },
+      method: "OPTIONS".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    allowed_methods: vec!["OPTIONS"],
+    options: callback(&|_, _| Some(hashmap! {
+      "A".to_string() => vec!["B".to_string()],
+      "C".to_string() => vec!["D;E=F".to_string()],
+    })),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(304));
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("A").unwrap().clone()).to(be_equal_to(vec!["B".to_string()]));
+  expect(context.response.headers.get("C").unwrap().clone()).to(be_equal_to(vec!["D;E=F".to_string()]));
 }
 
 #[test]
-fn execute_state_machine_returns_304_if_the_resource_last_modified_gt_modified_since() {
-  let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
-  let datetime = Local::now().with_timezone(&offset) - Duration::minutes(15);
-  let header_datetime = datetime + Duration::minutes(5);
+fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_content_type() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       headers: hashmap! {
-        "If-Modified-Since".to_string() => vec![h!(&*format!("\"{}\"", header_datetime.to_rfc2822()))]
+        "Accept".to_string() => vec![HeaderValue::basic("application/xml".to_string())]
       },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    last_modified: callback(&|_, _| {
-      let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
-      Some(Local::now().with_timezone(&offset) - Duration::minutes(15))
-    }),
+    produces: vec!["application/javascript"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(304));
+  expect(context.response.status).to(be_equal_to(406));
 }
 
 #[test]
-fn execute_state_machine_returns_202_if_delete_was_not_enacted() {
+fn execute_state_machine_returns_406_if_the_only_produced_content_type_is_excluded_with_zero_quality() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "DELETE".to_string(),
+      headers: hashmap! {
+        "Accept".to_string() => parse_header_values("application/json;q=0, text/plain")
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    delete_resource: callback(&|_, _| Ok(false)),
-    allowed_methods: vec!["DELETE"],
+    produces: vec!["application/json"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(202));
+  expect(context.response.status).to(be_equal_to(406));
 }
 
 #[test]
-fn execute_state_machine_returns_a_resource_status_code_if_delete_fails() {
+fn execute_state_machine_sets_content_type_header_if_the_request_does_have_an_acceptable_content_type() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "DELETE".to_string(),
+      headers: hashmap! {
+        "Accept".to_string() => vec![HeaderValue::basic("application/xml".to_string())]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    delete_resource: callback(&|_, _| Err(500)),
-    allowed_methods: vec!["DELETE"],
+    produces: vec!["application/xml"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(500));
-}
-
-#[test]
-fn join_paths_test() {
-  expect!(join_paths(&Vec::new(), &Vec::new())).to(be_equal_to("/".to_string()));
-  expect!(join_paths(&vec!["".to_string()], &Vec::new())).to(be_equal_to("/".to_string()));
-  expect!(join_paths(&Vec::new(), &vec!["".to_string()])).to(be_equal_to("/".to_string()));
-  expect!(join_paths(&vec!["a".to_string(), "b".to_string(), "c".to_string()], &Vec::new())).to(be_equal_to("/a/b/c".to_string()));
-  expect!(join_paths(&vec!["a".to_string(), "b".to_string(), "".to_string()], &Vec::new())).to(be_equal_to("/a/b".to_string()));
-  expect!(join_paths(&Vec::new(), &vec!["a".to_string(), "b".to_string(), "c".to_string()])).to(be_equal_to("/a/b/c".to_string()));
-  expect!(join_paths(&vec!["a".to_string(), "b".to_string(), "c".to_string()], &vec!["d".to_string(), "e".to_string(), "f".to_string()])).to(be_equal_to("/a/b/c/d/e/f".to_string()));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Type").unwrap()).to(be_equal_to(&vec![h!("application/xml;charset=ISO-8859-1")]));
 }
 
 #[test]
-fn execute_state_machine_returns_a_resource_status_code_if_post_fails_and_post_is_create() {
+fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_language() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! {
+        "Accept-Language".to_string() => vec![HeaderValue::basic("da".to_string())]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| true),
-    create_path: callback(&|_, _| Err(500)),
-    allowed_methods: vec!["POST"],
+    languages_provided: vec!["en"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(500));
+  expect(context.response.status).to(be_equal_to(406));
 }
 
 #[test]
-fn execute_state_machine_returns_a_resource_status_code_if_post_fails_and_post_is_not_create() {
+fn execute_state_machine_sets_the_language_header_if_the_request_does_have_an_acceptable_language() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! {
+        "Accept-Language".to_string() => vec![HeaderValue::basic("en-gb".to_string())]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| false),
-    process_post: callback(&|_, _| Err(500)),
-    allowed_methods: vec!["POST"],
+    languages_provided: vec!["en"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(500));
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers).to(be_equal_to(btreemap! { "Content-Language".to_string() => vec![h!("en")] }));
 }
 
 #[test]
-fn execute_state_machine_returns_303_and_post_is_create_and_redirect_is_set() {
+fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_charset() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
-      base_path: "/base/path".to_string(),
+      headers: hashmap! {
+        "Accept-Charset".to_string() => vec![h!("iso-8859-5"), h!("iso-8859-1;q=0")]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| true),
-    create_path: callback(&|context, _| {
-      context.redirect = true;
-      Ok("/new/path".to_string())
-    }),
-    allowed_methods: vec!["POST"],
+    charsets_provided: vec!["UTF-8", "US-ASCII"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(303));
-  expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Location".to_string() => vec![h!("/base/path/new/path")]
-  }));
+  expect(context.response.status).to(be_equal_to(406));
 }
 
 #[test]
-fn execute_state_machine_returns_303_if_post_is_not_create_and_redirect_is_set() {
+fn execute_state_machine_sets_the_charset_if_the_request_does_have_an_acceptable_charset() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! {
+        "Accept-Charset".to_string() => vec![h!("UTF-8"), h!("iso-8859-1;q=0")]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| false),
-    process_post: callback(&|context, _| {
-      context.redirect = true;
-      Ok(true)
-    }),
-    allowed_methods: vec!["POST"],
+    charsets_provided: vec!["UTF-8", "US-ASCII"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(303));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Type").unwrap()).to(be_equal_to(&vec![h!("application/json;charset=UTF-8")]));
 }
 
 #[test]
-fn execute_state_machine_returns_303_if_post_to_missing_resource_and_redirect_is_set() {
+fn execute_state_machine_returns_406_if_the_request_does_not_have_an_acceptable_encoding() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! {
+        "Accept-Encoding".to_string() => vec![h!("compress"), h!("*;q=0")]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| false),
-    allow_missing_post: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| false),
-    process_post: callback(&|context, _| {
-      context.redirect = true;
-      Ok(true)
-    }),
-    allowed_methods: vec!["POST"],
+    encodings_provided: vec!["identity"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(303));
+  expect(context.response.status).to(be_equal_to(406));
 }
 
 #[test]
-fn execute_state_machine_returns_201_if_post_creates_new_resource() {
+fn finalise_response_explains_a_406_response_if_identity_encoding_is_rejected() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "POST".to_string(),
+      headers: hashmap! {
+        "Accept-Encoding".to_string() => vec![h!("identity;q=0")]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| false),
-    previously_existed: callback(&|_, _| false),
-    allow_missing_post: callback(&|_, _| true),
-    post_is_create: callback(&|_, _| true),
-    create_path: callback(&|_, _| { Ok("/new/path".to_string()) }),
-    allowed_methods: vec!["POST"],
+    encodings_provided: vec!["identity"],
+    render_response: callback(&|_, _| Some("body".to_string())),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(201));
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(406));
+  expect(context.response.headers.get("Content-Type").cloned()).to(be_some().value(vec![h!("text/plain;charset=utf-8")]));
+  expect(context.response.body).to(be_some().value(
+    "None of the encodings in the Accept-Encoding header are available. Available encodings: identity".as_bytes().to_vec()));
+}
+
+#[test]
+fn execute_state_machine_sets_the_vary_header_if_the_resource_has_variances() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    variances: vec!["HEADER-A", "HEADER-B"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
   expect(context.response.headers).to(be_equal_to(btreemap! {
-    "Location".to_string() => vec![h!("/new/path")]
+    "Access-Control-Allow-Headers".to_string() => vec![h!("Content-Type")],
+    "Access-Control-Allow-Methods".to_string() => vec![h!("OPTIONS"), h!("GET"), h!("HEAD")],
+    "Access-Control-Allow-Origin".to_string() => vec![h!("*")],
+    "Content-Type".to_string() => vec![h!("application/json;charset=ISO-8859-1")],
+    "Vary".to_string() => vec![h!("HEADER-A"), h!("HEADER-B")]
   }));
 }
 
 #[test]
-fn execute_state_machine_returns_201_if_put_to_new_resource() {
+fn execute_state_machine_returns_404_if_the_resource_does_not_exist() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "PUT".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
     resource_exists: callback(&|_, _| false),
-    allowed_methods: vec!["PUT"],
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(201));
+  expect(context.response.status).to(be_equal_to(404));
 }
 
 #[test]
-fn execute_state_machine_returns_409_for_existing_resource_if_the_put_request_is_a_conflict() {
+fn execute_state_machine_returns_412_if_the_resource_does_not_exist_and_there_is_an_if_match_header() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "PUT".to_string(),
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("*")]
+      },
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    allowed_methods: vec!["PUT"],
-    resource_exists: callback(&|_, _| true),
-    is_conflict: callback(&|_, _| true),
+    resource_exists: callback(&|_, _| false),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(409));
+  expect(context.response.status).to(be_equal_to(412));
 }
 
 #[test]
-fn execute_state_machine_returns_200_if_put_request_to_existing_resource() {
+fn execute_state_machine_returns_301_and_sets_location_header_if_the_resource_has_moved_permanently() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       method: "PUT".to_string(),
@@ -1089,19 +1461,19 @@ fn execute_state_machine_returns_200_if_put_request_to_existing_resource() {
   };
   let resource = WebmachineResource {
     allowed_methods: vec!["PUT"],
-    resource_exists: callback(&|_, _| true),
-    process_put: callback(&|context, _| {
-      context.response.body = Some("body".as_bytes().to_vec());
-      Ok(true)
-    }),
+    resource_exists: callback(&|_, _| false),
+    moved_permanently: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.status).to(be_equal_to(301));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
+  }));
 }
 
 #[test]
-fn execute_state_machine_returns_204_if_put_request_to_existing_resource_with_no_response_body() {
+fn execute_state_machine_returns_409_if_the_put_request_is_a_conflict() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
       method: "PUT".to_string(),
@@ -1111,112 +1483,2909 @@ fn execute_state_machine_returns_204_if_put_request_to_existing_resource_with_no
   };
   let resource = WebmachineResource {
     allowed_methods: vec!["PUT"],
-    resource_exists: callback(&|_, _| true),
+    resource_exists: callback(&|_, _| false),
+    is_conflict: callback(&|_, _| true),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.status).to(be_equal_to(409));
 }
 
 #[test]
-fn execute_state_machine_returns_300_if_multiple_choices_is_true() {
+fn execute_state_machine_returns_404_if_the_resource_does_not_exist_and_does_not_except_posts_to_nonexistant_resources() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
+      method: "POST".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    multiple_choices: callback(&|_, _| true),
+    allowed_methods: vec!["POST"],
+    resource_exists: callback(&|_, _| false),
+    allow_missing_post: callback(&|_, _| false),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(300));
+  expect(context.response.status).to(be_equal_to(404));
 }
 
 #[test]
-fn execute_state_machine_returns_204_if_delete_was_enacted_and_response_has_no_body() {
+fn execute_state_machine_returns_301_and_sets_location_header_if_the_resource_has_moved_permanently_and_prev_existed_and_not_a_put() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "DELETE".to_string(),
+      method: "POST".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    delete_resource: callback(&|_, _| Ok(true)),
-    allowed_methods: vec!["DELETE"],
+    allowed_methods: vec!["POST"],
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| true),
+    moved_permanently: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.status).to(be_equal_to(301));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
+  }));
 }
 
 #[test]
-fn execute_state_machine_returns_200_if_delete_was_enacted_and_response_has_a_body() {
+fn execute_state_machine_returns_307_and_sets_location_header_if_the_resource_has_moved_temporarily_and_not_a_put() {
   let mut context = WebmachineContext {
     request: WebmachineRequest {
-      method: "DELETE".to_string(),
       ..WebmachineRequest::default()
     },
     ..WebmachineContext::default()
   };
   let resource = WebmachineResource {
-    resource_exists: callback(&|_, _| true),
-    delete_resource: callback(&|context, _| {
-      context.response.body = Some("body".as_bytes().to_vec());
-      Ok(true)
-    }),
-    allowed_methods: vec!["DELETE"],
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| true),
+    moved_temporarily: callback(&|_, _| Some("http://go.away.com/to/here".to_string())),
     ..WebmachineResource::default()
   };
   execute_state_machine(&mut context, &resource);
-  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.status).to(be_equal_to(307));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![h!("http://go.away.com/to/here")]
+  }));
 }
 
 #[test]
-fn parse_query_string_test() {
-  let query = "a=b&c=d".to_string();
-  let expected = hashmap! {
-    "a".to_string() => vec!["b".to_string()],
-    "c".to_string() => vec!["d".to_string()]
+fn execute_state_machine_returns_410_if_the_resource_has_prev_existed_and_not_a_post() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
   };
-  expect!(parse_query(&query)).to(be_equal_to(expected));
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(410));
 }
 
 #[test]
-fn parse_query_string_handles_empty_string() {
-  let query = "".to_string();
-  expect!(parse_query(&query)).to(be_equal_to(hashmap! {}));
+fn execute_state_machine_returns_410_for_a_get_after_the_resource_was_deleted() {
+  let deleted = AtomicBool::new(false);
+  let resource_exists = |_: &mut WebmachineContext, _: &WebmachineResource| !deleted.load(Ordering::SeqCst);
+  let delete_resource = |_: &mut WebmachineContext, _: &WebmachineResource| {
+    deleted.store(true, Ordering::SeqCst);
+    Ok(true)
+  };
+  let previously_existed = |_: &mut WebmachineContext, _: &WebmachineResource| deleted.load(Ordering::SeqCst);
+  let resource = WebmachineResource {
+    allowed_methods: vec!["GET", "DELETE"],
+    resource_exists: callback(&resource_exists),
+    delete_resource: callback(&delete_resource),
+    previously_existed: callback(&previously_existed),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(410));
 }
 
 #[test]
-fn parse_query_string_handles_missing_values() {
-  let query = "a=&c=d".to_string();
-  let expected = hashmap! {
-    "a".to_string() => vec!["".to_string()],
-    "c".to_string() => vec!["d".to_string()]
+fn execute_state_machine_returns_410_if_the_resource_has_prev_existed_and_a_post_and_posts_to_missing_resource_not_allowed() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
   };
-  expect!(parse_query(&query)).to(be_equal_to(expected));
+  let resource = WebmachineResource {
+    allowed_methods: vec!["POST"],
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| true),
+    allow_missing_post: callback(&|_, _| false),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(410));
 }
 
 #[test]
-fn parse_query_string_handles_equals_in_values() {
-  let query = "a=b&c=d=e=f".to_string();
-  let expected = hashmap! {
-    "a".to_string() => vec!["b".to_string()],
-    "c".to_string() => vec!["d=e=f".to_string()]
+fn execute_state_machine_returns_404_if_the_resource_has_not_prev_existed_and_a_post_and_posts_to_missing_resource_not_allowed() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
   };
-  expect!(parse_query(&query)).to(be_equal_to(expected));
+  let resource = WebmachineResource {
+    allowed_methods: vec!["POST"],
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| false),
+    allow_missing_post: callback(&|_, _| false),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
 }
 
 #[test]
-fn parse_query_string_decodes_values() {
-  let query = "a=a%20b%20c".to_string();
-  let expected = hashmap! {
-    "a".to_string() => vec!["a b c".to_string()]
-  };
-  expect!(parse_query(&query)).to(be_equal_to(expected));
+fn execute_state_machine_returns_412_if_the_resource_etag_does_not_match_if_match_header() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("\"1234567891\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+}
+
+#[test]
+fn execute_state_machine_returns_412_if_the_resource_etag_does_not_match_if_match_header_weak_etag() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("W/\"1234567891\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+}
+
+#[test]
+fn weak_etags_match_if_none_match_but_not_if_match() {
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("W/\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("W/\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn a_weak_resource_etag_never_matches_if_match() {
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("W/\"1234567890\"".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.headers.get("ETag").cloned())
+    .to(be_some().value(vec![HeaderValue::basic("W/\"1234567890\"")]));
+}
+
+#[test]
+fn execute_state_machine_returns_412_if_the_resource_last_modified_gt_unmodified_since() {
+  let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+  let datetime = Local::now().with_timezone(&offset);
+  let header_datetime = datetime - Duration::minutes(5);
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Unmodified-Since".to_string() => vec![h!(&*format!("\"{}\"", header_datetime.to_rfc2822()))]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    last_modified: callback(&|_, _| {
+      let fixed_offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+      Some(Local::now().with_timezone(&fixed_offset))
+    }),
+    ..WebmachineResource::default()
+  };
+
+  execute_state_machine(&mut context, &resource);
+
+  expect(context.response.status).to(be_equal_to(412));
+}
+
+#[test]
+fn execute_state_machine_returns_304_if_non_match_star_exists_and_is_not_a_head_or_get() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("*")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+}
+
+#[test]
+fn execute_state_machine_returns_304_if_non_match_star_exists_and_is_a_head_or_get() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "HEAD".to_string(),
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("*")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    allowed_methods: vec!["HEAD"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+}
+
+#[test]
+fn execute_state_machine_returns_412_if_resource_etag_in_if_non_match_and_is_not_a_head_or_get() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("W/\"1234567890\""), h!("W/\"1234567891\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    allowed_methods: vec!["POST"],
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(412));
+}
+
+#[test]
+fn execute_state_machine_returns_304_if_resource_etag_in_if_non_match_and_is_a_head_or_get() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("\"1234567890\""), h!("\"1234567891\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+}
+
+#[test]
+fn execute_state_machine_returns_304_if_the_resource_last_modified_gt_modified_since() {
+  let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+  let datetime = Local::now().with_timezone(&offset) - Duration::minutes(15);
+  let header_datetime = datetime + Duration::minutes(5);
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Modified-Since".to_string() => vec![h!(&*format!("\"{}\"", header_datetime.to_rfc2822()))]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    last_modified: callback(&|_, _| {
+      let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+      Some(Local::now().with_timezone(&offset) - Duration::minutes(15))
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+}
+
+#[test]
+fn execute_state_machine_ignores_sub_second_differences_in_last_modified() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Modified-Since".to_string() => parse_header_values("Sun, 06 Nov 1994 08:49:37 GMT")
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    last_modified: callback(&|_, _| DateTime::parse_from_rfc3339("1994-11-06T08:49:37.750Z").ok()),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      headers: hashmap! {
+        "If-Unmodified-Since".to_string() => parse_header_values("Sun, 06 Nov 1994 08:49:37 GMT")
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    last_modified: callback(&|_, _| DateTime::parse_from_rfc3339("1994-11-06T08:49:37.750Z").ok()),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+}
+
+#[test]
+fn execute_state_machine_returns_202_if_delete_was_not_enacted() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    delete_resource: callback(&|_, _| Ok(false)),
+    allowed_methods: vec!["DELETE"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(202));
+}
+
+#[test]
+fn execute_state_machine_returns_a_resource_status_code_if_delete_fails() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    delete_resource: callback(&|_, _| Err(500.into())),
+    allowed_methods: vec!["DELETE"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(500));
+}
+
+#[test]
+fn join_paths_test() {
+  expect!(join_paths(&Vec::new(), &Vec::new())).to(be_equal_to("/".to_string()));
+  expect!(join_paths(&["".to_string()], &Vec::new())).to(be_equal_to("/".to_string()));
+  expect!(join_paths(&Vec::new(), &["".to_string()])).to(be_equal_to("/".to_string()));
+  expect!(join_paths(&["a".to_string(), "b".to_string(), "c".to_string()], &Vec::new())).to(be_equal_to("/a/b/c".to_string()));
+  expect!(join_paths(&["a".to_string(), "b".to_string(), "".to_string()], &Vec::new())).to(be_equal_to("/a/b".to_string()));
+  expect!(join_paths(&Vec::new(), &["a".to_string(), "b".to_string(), "c".to_string()])).to(be_equal_to("/a/b/c".to_string()));
+  expect!(join_paths(&["a".to_string(), "b".to_string(), "c".to_string()], &["d".to_string(), "e".to_string(), "f".to_string()])).to(be_equal_to("/a/b/c/d/e/f".to_string()));
+}
+
+#[test]
+fn execute_state_machine_returns_a_resource_status_code_if_post_fails_and_post_is_create() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| true),
+    create_path: callback(&|_, _| Err(500.into())),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(500));
+}
+
+#[test]
+fn execute_state_machine_location_for_a_created_resource_does_not_contain_duplicate_slashes() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      base_path: "/orders/".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| true),
+    create_path: callback(&|_, _| Ok("//1234/".to_string())),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.headers.get("Location").unwrap()[0].value.as_str()).to(be_equal_to("/orders/1234"));
+}
+
+#[test]
+fn execute_state_machine_returns_a_resource_status_code_if_post_fails_and_post_is_not_create() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| false),
+    process_post: callback(&|_, _| Err(500.into())),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(500));
+}
+
+#[test]
+fn execute_state_machine_returns_the_configured_status_if_post_is_not_processed() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    process_post: callback(&|_, _| Ok(false)),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+
+  let resource = WebmachineResource {
+    post_not_processed_status: Some(422),
+    ..resource
+  };
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(422));
+
+  let resource = WebmachineResource {
+    process_post: callback(&|_, _| Ok(true)),
+    ..resource
+  };
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+}
+
+#[test]
+fn execute_state_machine_returns_303_and_post_is_create_and_redirect_is_set() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      base_path: "/base/path".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| true),
+    create_path: callback(&|context, _| {
+      context.redirect = true;
+      Ok("/new/path".to_string())
+    }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(303));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![h!("/base/path/new/path")]
+  }));
+}
+
+#[test]
+fn execute_state_machine_returns_303_if_post_is_not_create_and_redirect_is_set() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| false),
+    process_post: callback(&|context, _| {
+      context.redirect = true;
+      Ok(true)
+    }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(303));
+}
+
+#[test]
+fn execute_state_machine_returns_303_with_the_location_from_redirect_to() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    process_post: callback(&|context, _| {
+      context.redirect_to("/orders/1234");
+      Ok(true)
+    }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(303));
+  expect(context.response.headers.get("Location").unwrap().clone()).to(be_equal_to(vec![h!("/orders/1234")]));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    post_is_create: callback(&|_, _| true),
+    create_path: callback(&|context, _| {
+      context.redirect_to("https://example.com/orders/1234");
+      Ok("/orders/1234".to_string())
+    }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(303));
+  expect(context.response.headers.get("Location").unwrap()[0].value.as_str()).to(be_equal_to("https://example.com/orders/1234"));
+}
+
+#[test]
+fn execute_state_machine_returns_303_if_post_to_missing_resource_and_redirect_is_set() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| false),
+    allow_missing_post: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| false),
+    process_post: callback(&|context, _| {
+      context.redirect = true;
+      Ok(true)
+    }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(303));
+}
+
+#[test]
+fn execute_state_machine_returns_201_if_post_creates_new_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "POST".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    previously_existed: callback(&|_, _| false),
+    allow_missing_post: callback(&|_, _| true),
+    post_is_create: callback(&|_, _| true),
+    create_path: callback(&|_, _| { Ok("/new/path".to_string()) }),
+    allowed_methods: vec!["POST"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(201));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![h!("/new/path")]
+  }));
+}
+
+#[test]
+fn execute_state_machine_returns_201_if_put_to_new_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    allowed_methods: vec!["PUT"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(201));
+}
+
+#[test]
+fn execute_state_machine_returns_201_with_location_and_body_if_put_to_new_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      base_path: "/orders".to_string(),
+      request_path: "/1234".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    allowed_methods: vec!["PUT"],
+    process_put: callback(&|context, _| {
+      context.response.body = Some("{\"id\": 1234}".as_bytes().to_vec());
+      Ok(true)
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(201));
+  expect(context.response.headers.get("Location").unwrap().clone()).to(be_equal_to(vec![h!("/orders/1234")]));
+  expect(context.response.body).to(be_some().value("{\"id\": 1234}".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Location")).to(be_none());
+}
+
+#[test]
+fn execute_state_machine_returns_409_for_existing_resource_if_the_put_request_is_a_conflict() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    resource_exists: callback(&|_, _| true),
+    is_conflict: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(409));
+}
+
+#[test]
+fn execute_state_machine_returns_200_if_put_request_to_existing_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    resource_exists: callback(&|_, _| true),
+    process_put: callback(&|context, _| {
+      context.response.body = Some("body".as_bytes().to_vec());
+      Ok(true)
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn execute_state_machine_returns_204_if_put_request_to_existing_resource_with_no_response_body() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    resource_exists: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+}
+
+#[test]
+fn execute_state_machine_calls_process_patch_for_a_patch_request_to_an_existing_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PATCH".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PATCH"],
+    process_patch: callback(&|context, _| {
+      context.response.body = Some("patched".as_bytes().to_vec());
+      Ok(true)
+    }),
+    process_put: callback(&|_, _| Err(500.into())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.body).to(be_some().value("patched".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PATCH".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PATCH"],
+    process_patch: callback(&|_, _| Err(422.into())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(422));
+}
+
+#[test]
+fn execute_state_machine_returns_409_if_the_patch_request_is_a_conflict() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PATCH".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PATCH"],
+    is_conflict: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(409));
+}
+
+#[test]
+fn execute_state_machine_returns_404_for_a_patch_request_to_a_missing_resource() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PATCH".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PATCH"],
+    resource_exists: callback(&|_, _| false),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
+}
+
+#[test]
+fn execute_state_machine_returns_300_if_multiple_choices_is_true() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    multiple_choices: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(300));
+}
+
+#[test]
+fn execute_state_machine_returns_204_if_delete_was_enacted_and_response_has_no_body() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    delete_resource: callback(&|_, _| Ok(true)),
+    allowed_methods: vec!["DELETE"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+}
+
+#[test]
+fn execute_state_machine_returns_200_if_delete_was_enacted_and_response_has_a_body() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    delete_resource: callback(&|context, _| {
+      context.response.body = Some("body".as_bytes().to_vec());
+      Ok(true)
+    }),
+    allowed_methods: vec!["DELETE"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn execute_state_machine_returns_204_if_delete_was_enacted_and_delete_returns_no_content_is_set() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    delete_resource: callback(&|context, _| {
+      context.response.body = Some("body".as_bytes().to_vec());
+      Ok(true)
+    }),
+    delete_returns_no_content: true,
+    allowed_methods: vec!["DELETE"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.body).to(be_none());
+}
+
+#[test]
+fn parse_query_string_test() {
+  let query = "a=b&c=d".to_string();
+  let expected = hashmap! {
+    "a".to_string() => vec!["b".to_string()],
+    "c".to_string() => vec!["d".to_string()]
+  };
+  expect!(parse_query(&query)).to(be_equal_to(expected));
+}
+
+#[test]
+fn parse_query_string_handles_empty_string() {
+  let query = "".to_string();
+  expect!(parse_query(&query)).to(be_equal_to(hashmap! {}));
+}
+
+#[test]
+fn parse_query_string_handles_missing_values() {
+  let query = "a=&c=d".to_string();
+  let expected = hashmap! {
+    "a".to_string() => vec!["".to_string()],
+    "c".to_string() => vec!["d".to_string()]
+  };
+  expect!(parse_query(&query)).to(be_equal_to(expected));
+}
+
+#[test]
+fn parse_query_string_handles_equals_in_values() {
+  let query = "a=b&c=d=e=f".to_string();
+  let expected = hashmap! {
+    "a".to_string() => vec!["b".to_string()],
+    "c".to_string() => vec!["d=e=f".to_string()]
+  };
+  expect!(parse_query(&query)).to(be_equal_to(expected));
+}
+
+#[test]
+fn parse_query_string_decodes_values() {
+  let query = "a=a%20b%20c".to_string();
+  let expected = hashmap! {
+    "a".to_string() => vec!["a b c".to_string()]
+  };
+  expect!(parse_query(&query)).to(be_equal_to(expected));
+}
+
+#[test]
+fn finalise_response_sets_content_length_from_body_length_callback_without_rendering_for_head() {
+  let rendered = AtomicBool::new(false);
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "HEAD".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let render = |_: &mut WebmachineContext, _: &WebmachineResource| {
+    rendered.store(true, Ordering::SeqCst);
+    Some("body".to_string())
+  };
+  let resource = WebmachineResource {
+    body_length: callback(&|_, _| Some(1024)),
+    render_response: callback(&render),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Length").unwrap().clone()).to(be_equal_to(vec![h!("1024")]));
+  expect(context.response.body).to(be_none());
+  expect(rendered.load(Ordering::SeqCst)).to(be_false());
+}
+
+#[test]
+fn finalise_response_sets_the_etag_header_for_a_successful_put() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    resource_exists: callback(&|_, _| false),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(201));
+  expect(context.response.headers.get("ETag").unwrap().clone()).to(be_equal_to(vec![h!("1234567890").quote()]));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct User {
+  name: String
+}
+
+#[test]
+fn callbacks_can_pass_typed_values_using_the_context_extensions() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    not_authorized: callback(&|context, _| {
+      context.insert(User { name: "Fred".to_string() });
+      None
+    }),
+    forbidden: callback(&|context, _| {
+      context.get::<User>().map(|user| user.name != "Fred").unwrap_or(true)
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.get::<User>().cloned()).to(be_some().value(User { name: "Fred".to_string() }));
+}
+
+#[test]
+fn request_from_http_request_captures_the_connection_header_and_version() {
+  let req = Request::builder()
+    .uri("/path")
+    .version(http::Version::HTTP_10)
+    .header("Connection", "close")
+    .body(Body::empty())
+    .unwrap();
+  let request = futures::executor::block_on(request_from_http_request(req, None)).unwrap();
+  expect!(request.http_version.as_str()).to(be_equal_to("HTTP/1.0"));
+  expect!(request.connection()).to(be_equal_to(vec![h!("close")]));
+  expect!(request.is_keep_alive()).to(be_false());
+}
+
+#[test]
+fn request_from_http_request_enforces_the_maximum_body_size() {
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .body(Body::from("0123456789"))
+    .unwrap();
+  let request = futures::executor::block_on(request_from_http_request(req, Some(10))).unwrap();
+  expect!(request.body).to(be_some().value("0123456789".as_bytes().to_vec()));
+
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .header("Content-Length", "11")
+    .body(Body::from("01234567890"))
+    .unwrap();
+  expect!(futures::executor::block_on(request_from_http_request(req, Some(10)))).to(be_err().value(413));
+
+  // A streamed body without a Content-Length is stopped once the limit is exceeded
+  let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("01234"), Ok("56789"), Ok("0")];
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .body(Body::wrap_stream(futures::stream::iter(chunks)))
+    .unwrap();
+  expect!(futures::executor::block_on(request_from_http_request(req, Some(10)))).to(be_err().value(413));
+}
+
+#[test]
+fn request_from_http_request_reads_bodies_other_than_hyper_body() {
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .body("{\"a\": 1}".to_string())
+    .unwrap();
+  let request = futures::executor::block_on(request_from_http_request(req, None)).unwrap();
+  expect!(request.body).to(be_some().value("{\"a\": 1}".as_bytes().to_vec()));
+}
+
+#[test]
+fn dispatcher_returns_413_if_the_request_body_is_larger_than_the_resource_maximum() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path" => WebmachineResource {
+        allowed_methods: vec!["POST"],
+        max_body_size: Some(4),
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .header("Content-Type", "application/json")
+    .body(Body::from("[1, 2, 3]"))
+    .unwrap();
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(req)).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(413));
+  expect!(dispatcher.status_counters()).to(be_equal_to(hashmap!{ 413 => 1 }));
+
+  let req = Request::builder()
+    .uri("/path")
+    .method("POST")
+    .header("Content-Type", "application/json")
+    .body(Body::from("[1]"))
+    .unwrap();
+  let response = futures::executor::block_on(dispatcher.dispatch(req)).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(204));
+}
+
+#[test]
+fn dispatcher_interceptor_can_short_circuit_the_request() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path" => WebmachineResource {
+        resource_exists: callback(&|_, _| panic!("resource should not be executed")),
+        ..WebmachineResource::default()
+      }
+    },
+    interceptors: vec![
+      Arc::new(|context: &mut WebmachineContext| {
+        context.metadata.insert("intercepted".to_string(), "true".to_string());
+        None
+      }),
+      Arc::new(|context: &mut WebmachineContext| if context.request.has_header("Authorization") {
+        None
+      } else {
+        let mut response = WebmachineResponse { status: 401, ..WebmachineResponse::default() };
+        response.add_header("WWW-Authenticate", vec![h!("Bearer")]);
+        Some(response)
+      })
+    ],
+    ..WebmachineDispatcher::default()
+  };
+  let mut context = WebmachineContext {
+    request: resource("/path"),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.response.status).to(be_equal_to(401));
+  expect!(context.response.headers.get("WWW-Authenticate").cloned()).to(be_some().value(vec![h!("Bearer")]));
+  expect!(context.metadata.get("intercepted").cloned()).to(be_some().value("true"));
+  expect!(dispatcher.status_counters()).to(be_equal_to(hashmap!{ 401 => 1 }));
+}
+
+#[test]
+fn generate_http_response_sends_each_cookie_as_a_separate_header() {
+  let mut context = WebmachineContext::default();
+  context.response.add_cookie("session", "abc123", &["Expires=Wed, 21 Oct 2015 07:28:00 GMT"]);
+  context.response.add_cookie("theme", "dark", &[]);
+  let response = generate_http_response(&context).unwrap();
+  let cookies = response.headers().get_all("Set-Cookie").iter()
+    .map(|value| value.to_str().unwrap().to_string())
+    .collect::<Vec<_>>();
+  expect!(cookies).to(be_equal_to(vec![
+    "session=abc123; Expires=Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+    "theme=dark".to_string()
+  ]));
+}
+
+#[test]
+fn generate_http_response_does_not_combine_authentication_challenges() {
+  let mut context = WebmachineContext::default();
+  context.response.status = 401;
+  context.response.add_header("WWW-Authenticate", vec![
+    HeaderValue::basic("Basic realm=\"api\", charset=\"UTF-8\""),
+    HeaderValue::basic("Bearer realm=\"api\"")
+  ]);
+  context.response.add_header("Vary", vec![h!("Accept"), h!("Origin")]);
+  let response = generate_http_response(&context).unwrap();
+  let challenges = response.headers().get_all("WWW-Authenticate").iter()
+    .map(|value| value.to_str().unwrap().to_string())
+    .collect::<Vec<_>>();
+  expect!(challenges).to(be_equal_to(vec![
+    "Basic realm=\"api\", charset=\"UTF-8\"".to_string(),
+    "Bearer realm=\"api\"".to_string()
+  ]));
+  expect!(response.headers().get_all("Vary").iter().count()).to(be_equal_to(1));
+}
+
+#[test]
+fn dispatcher_adds_a_zero_content_length_to_no_content_responses_if_enabled() {
+  let mut dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path" => WebmachineResource {
+        allowed_methods: vec!["DELETE"],
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+  let request = WebmachineRequest {
+    request_path: "/path".to_string(),
+    method: "DELETE".to_string(),
+    ..WebmachineRequest::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: request.clone(),
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.response.status).to(be_equal_to(204));
+  expect!(context.response.has_header("Content-Length")).to(be_false());
+
+  dispatcher.empty_content_length = true;
+  let mut context = WebmachineContext {
+    request,
+    ..WebmachineContext::default()
+  };
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect!(context.response.status).to(be_equal_to(204));
+  expect!(context.response.headers.get("Content-Length").cloned()).to(be_some().value(vec![h!("0")]));
+}
+
+#[test]
+fn put_to_a_read_only_resource_returns_405_with_allow_header_and_error_body() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    render_error: callback(&|context, _| Some(format!("{{\"error\": {}}}", context.response.status))),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.headers.get("Allow").unwrap().clone()).to(be_equal_to(vec![
+    h!("OPTIONS"),
+    h!("GET"),
+    h!("HEAD")
+  ]));
+  expect(context.response.body).to(be_some().value("{\"error\": 405}".as_bytes().to_vec()));
+}
+
+#[test]
+fn finalise_response_returns_206_for_a_single_byte_range_probe() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Range".to_string() => vec![h!("bytes=0-0")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    accept_ranges: true,
+    render_response: callback(&|_, _| Some("0123456789".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(206));
+  expect(context.response.headers.get("Content-Range").unwrap().clone()).to(be_equal_to(vec![h!("bytes 0-0/10")]));
+  expect(context.response.headers.get("Accept-Ranges").unwrap().clone()).to(be_equal_to(vec![h!("bytes")]));
+  expect(context.response.body).to(be_some().value("0".as_bytes().to_vec()));
+}
+
+#[test]
+fn finalise_response_renders_the_body_after_finish_request() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    finish_request: callback(&|context, _| {
+      context.metadata.insert("nonce".to_string(), "abc123".to_string());
+      context.response.add_header("X-Nonce", vec![h!("abc123")]);
+    }),
+    render_response: callback(&|context, _| {
+      context.metadata.get("nonce").map(|nonce| format!("{{\"nonce\": \"{}\"}}", nonce))
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("X-Nonce").cloned()).to(be_some().value(vec![h!("abc123")]));
+  expect(context.response.body).to(be_some().value("{\"nonce\": \"abc123\"}".as_bytes().to_vec()));
+}
+
+#[test]
+fn finalise_response_transcodes_error_bodies_to_the_selected_charset() {
+  let resource = WebmachineResource {
+    produces: vec!["text/plain"],
+    charsets_provided: vec!["UTF-8", "ISO-8859-1"],
+    resource_exists: callback(&|_, _| false),
+    render_error: callback(&|_, _| Some("Café introuvable".to_string())),
+    ..WebmachineResource::default()
+  };
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("text/plain")],
+        "Accept-Charset".to_string() => vec![h!("ISO-8859-1")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
+  expect(context.response.headers.get("Content-Type").cloned())
+    .to(be_some().value(vec![h!("text/plain;charset=ISO-8859-1")]));
+  let body = context.response.body.clone().unwrap();
+  expect!(body.len()).to(be_equal_to(16));
+  expect!(body[3]).to(be_equal_to(0xE9));
+}
+
+#[test]
+fn finalise_response_transcodes_the_body_to_the_selected_charset() {
+  let resource = WebmachineResource {
+    produces: vec!["text/plain"],
+    charsets_provided: vec!["UTF-8", "ISO-8859-1"],
+    render_response: callback(&|_, _| Some("Café €5".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("text/plain")],
+        "Accept-Charset".to_string() => vec![h!("ISO-8859-1")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  let body = context.response.body.clone().unwrap();
+  expect!(body.clone()).to(be_equal_to(vec![b'C', b'a', b'f', 0xE9, b' ', b'?', b'5']));
+  let decoded: String = body.iter().map(|b| *b as char).collect();
+  expect!(decoded).to(be_equal_to("Café ?5".to_string()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("text/plain")],
+        "Accept-Charset".to_string() => vec![h!("UTF-8")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.body).to(be_some().value("Café €5".as_bytes().to_vec()));
+}
+
+#[test]
+fn finalise_response_keeps_headers_set_by_render_response() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    render_response: callback(&|context, _| {
+      let body = "0123456789".to_string();
+      let checksum = body.bytes().map(|b| b as u32).sum::<u32>();
+      context.response.add_header("X-Checksum", vec![HeaderValue::basic(checksum.to_string())]);
+      context.response.add_header("Content-Type", vec![h!("application/vnd.test+json")]);
+      Some(body)
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("X-Checksum").cloned()).to(be_some().value(vec![h!("525")]));
+  expect(context.response.headers.get("Content-Type").cloned()).to(be_some().value(vec![h!("application/vnd.test+json")]));
+
+  let response = generate_http_response(&context).unwrap();
+  expect!(response.headers().get("X-Checksum").map(|v| v.to_str().unwrap().to_string())).to(be_some().value("525"));
+}
+
+#[test]
+fn finalise_response_only_applies_the_range_if_the_if_range_header_matches() {
+  let resource = WebmachineResource {
+    accept_ranges: true,
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    last_modified: callback(&|_, _| Some(DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z").unwrap())),
+    render_response: callback(&|_, _| Some("0123456789".to_string())),
+    ..WebmachineResource::default()
+  };
+  let cases = vec![
+    ("\"1234567890\"", 206),
+    ("W/\"1234567890\"", 200),
+    ("\"other\"", 200),
+    ("Tue, 02 Jan 2024 03:04:05 GMT", 206),
+    ("Tue, 02 Jan 2024 03:04:06 GMT", 200)
+  ];
+  for (if_range, status) in cases {
+    let mut context = WebmachineContext {
+      request: WebmachineRequest {
+        headers: hashmap! {
+          "Range".to_string() => vec![h!("bytes=0-4")],
+          "If-Range".to_string() => parse_header_values(if_range)
+        },
+        ..WebmachineRequest::default()
+      },
+      ..WebmachineContext::default()
+    };
+    execute_state_machine(&mut context, &resource);
+    finalise_response(&mut context, &resource);
+    expect!(context.response.status).to(be_equal_to(status));
+    if status == 206 {
+      expect!(context.response.body).to(be_some().value("01234".as_bytes().to_vec()));
+    } else {
+      expect!(context.response.body).to(be_some().value("0123456789".as_bytes().to_vec()));
+    }
+  }
+}
+
+#[test]
+fn finalise_response_returns_a_multipart_body_for_multiple_byte_ranges() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("text/plain")],
+        "Range".to_string() => parse_header_values("bytes=0-9,20-29")
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    accept_ranges: true,
+    produces: vec!["text/plain"],
+    render_response: callback(&|_, _| Some("0123456789abcdefghijABCDEFGHIJ".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(206));
+  expect(context.response.has_header("Content-Range")).to(be_false());
+
+  let content_type = context.response.headers.get("Content-Type").unwrap()[0].value.clone();
+  let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+  let expected = format!("--{b}\r\nContent-Type: text/plain; charset=ISO-8859-1\r\nContent-Range: bytes 0-9/30\r\n\r\n0123456789\r\n\
+    --{b}\r\nContent-Type: text/plain; charset=ISO-8859-1\r\nContent-Range: bytes 20-29/30\r\n\r\nABCDEFGHIJ\r\n\
+    --{b}--\r\n", b = boundary);
+  expect!(String::from_utf8(context.response.body.unwrap()).unwrap()).to(be_equal_to(expected));
+}
+
+#[test]
+fn finalise_response_coalesces_overlapping_byte_ranges_and_limits_the_number_of_ranges() {
+  let resource = WebmachineResource {
+    accept_ranges: true,
+    produces: vec!["text/plain"],
+    render_response: callback(&|_, _| Some("0123456789abcdefghijABCDEFGHIJ".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Range".to_string() => parse_header_values(&format!("bytes={}", vec!["0-"; 50].join(",")))
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(206));
+  expect(context.response.headers.get("Content-Range").cloned())
+    .to(be_some().value(vec![HeaderValue::basic("bytes 0-29/30")]));
+  expect(context.response.body.map(|body| body.len())).to(be_some().value(30));
+
+  let resource = WebmachineResource {
+    render_response: callback(&|_, _| Some("x".repeat(64))),
+    ..resource
+  };
+  let ranges = (0..64).step_by(2).map(|i| format!("{}-{}", i, i)).join(",");
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Range".to_string() => parse_header_values(&format!("bytes={}", ranges))
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.has_header("Content-Range")).to(be_false());
+  expect(context.response.body.map(|body| body.len())).to(be_some().value(64));
+}
+
+#[test]
+fn finalise_response_ignores_range_header_if_the_resource_does_not_accept_ranges() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Range".to_string() => vec![h!("bytes=0-0")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    render_response: callback(&|_, _| Some("0123456789".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.has_header("Content-Range")).to(be_false());
+  expect(context.response.body).to(be_some().value("0123456789".as_bytes().to_vec()));
+}
+
+#[test]
+fn retry_after_can_be_set_for_unavailable_and_rate_limited_responses() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    available: callback(&|context, _| {
+      context.response.set_retry_after(120);
+      false
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(503));
+  expect(context.response.headers.get("Retry-After").cloned()).to(be_some().value(vec![h!("120")]));
+
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! { "/" => WebmachineResource::default() },
+    interceptors: vec![Arc::new(|_: &mut WebmachineContext| {
+      let mut response = WebmachineResponse { status: 429, ..WebmachineResponse::default() };
+      response.set_retry_after(30);
+      Some(response)
+    })],
+    ..WebmachineDispatcher::default()
+  };
+  let mut context = WebmachineContext::default();
+  futures::executor::block_on(dispatcher.dispatch_to_resource(&mut context));
+  expect(context.response.status).to(be_equal_to(429));
+  expect(context.response.headers.get("Retry-After").cloned()).to(be_some().value(vec![h!("30")]));
+}
+
+#[test]
+fn error_bodies_are_rendered_with_the_negotiated_media_type() {
+  let registry = serializers::SerializerRegistry::new()
+    .register("application/json", |(status, message): &(u16, String)| {
+      Ok(format!("{{\"status\":{},\"message\":\"{}\"}}", status, message))
+    })
+    .register("application/xml", |(status, message): &(u16, String)| {
+      Ok(format!("<error status=\"{}\">{}</error>", status, message))
+    });
+  let resource = WebmachineResource {
+    produces: vec!["application/json", "application/xml"],
+    resource_exists: callback(&|_, _| false),
+    ..WebmachineResource::default()
+  }.render_errors_with(registry, |context, _| Some((context.response.status, "Not Found".to_string())));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! { "Accept".to_string() => vec![h!("application/xml")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
+  expect(context.response.headers.get("Content-Type").cloned())
+    .to(be_some().value(vec![h!("application/xml;charset=ISO-8859-1")]));
+  expect(context.response.body).to(be_some().value("<error status=\"404\">Not Found</error>".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
+  expect(context.response.headers.get("Content-Type").cloned())
+    .to(be_some().value(vec![h!("application/json;charset=ISO-8859-1")]));
+
+  let resource = WebmachineResource {
+    charsets_provided: vec!["UTF-8"],
+    ..resource
+  };
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! { "Accept-Charset".to_string() => vec![h!("UTF-8")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(404));
+  expect(context.response.headers.get("Content-Type").cloned())
+    .to(be_some().value(vec![h!("application/json;charset=UTF-8")]));
+  expect(context.response.body).to(be_some().value("{\"status\":404,\"message\":\"Not Found\"}".as_bytes().to_vec()));
+}
+
+#[test]
+fn unavailable_resource_returns_503_with_maintenance_body() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    available: callback(&|context, _| {
+      context.response.add_header("Retry-After", vec![h!("120")]);
+      false
+    }),
+    render_error: callback(&|context, _| if context.response.status == 503 {
+      Some("{\"message\": \"Down for maintenance\"}".to_string())
+    } else {
+      None
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(503));
+  expect(context.response.headers.get("Retry-After").unwrap().clone()).to(be_equal_to(vec![h!("120")]));
+  expect(context.response.body).to(be_some().value("{\"message\": \"Down for maintenance\"}".as_bytes().to_vec()));
+}
+
+#[test]
+fn execute_state_machine_returns_428_for_an_unconditional_put_if_conditional_writes_are_required() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    require_conditional_for_writes: true,
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(428));
+}
+
+#[test]
+fn execute_state_machine_allows_a_conditional_put_if_conditional_writes_are_required() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      headers: hashmap! {
+        "If-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    require_conditional_for_writes: true,
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+}
+
+#[test]
+fn execute_state_machine_returns_405_with_a_discovery_link() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      base_path: "/path".to_string(),
+      request_path: "/sub".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.has_header("Allow")).to(be_true());
+  expect(context.response.headers.get("Link").unwrap().clone()).to(be_equal_to(vec![
+    HeaderValue::basic("</path/sub>; rel=\"describedby\"")
+  ]));
+}
+
+#[test]
+fn execute_state_machine_returns_405_without_a_discovery_link_if_options_is_not_allowed() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "DELETE".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["GET"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(405));
+  expect(context.response.has_header("Link")).to(be_false());
+}
+
+#[test]
+fn execute_state_machine_returns_501_if_there_is_a_webdav_if_header() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If".to_string() => vec![h!("(<urn:uuid:181d4fae-7d8c-11d0-a765-00a0c91e6bf2>)")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(501));
+
+  let resource = WebmachineResource {
+    supports_webdav_if: true,
+    ..WebmachineResource::default()
+  };
+  let mut context = WebmachineContext {
+    request: context.request.clone(),
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn execute_state_machine_returns_304_if_etag_in_if_none_match_even_if_modified_since_the_if_modified_since() {
+  let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+  let header_datetime = Local::now().with_timezone(&offset) - Duration::minutes(30);
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("\"1234567890\"")],
+        "If-Modified-Since".to_string() => vec![h!(&*format!("\"{}\"", header_datetime.to_rfc2822()))]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    last_modified: callback(&|_, _| {
+      let offset = FixedOffset::east_opt(10 * 3600).expect("FixedOffset::east out of bounds");
+      Some(Local::now().with_timezone(&offset) - Duration::minutes(15))
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+}
+
+#[test]
+fn finalise_response_does_not_render_the_body_for_head_or_not_modified_responses() {
+  let rendered = AtomicBool::new(false);
+  let render = |_: &mut WebmachineContext, _: &WebmachineResource| {
+    rendered.store(true, Ordering::SeqCst);
+    Some("body".to_string())
+  };
+  let resource = WebmachineResource {
+    render_response: callback(&render),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  // A HEAD request only renders the body to determine the Content-Length, so it is not rendered
+  // if the length is provided
+  let head_resource = WebmachineResource {
+    render_response: callback(&render),
+    body_length: callback(&|_, _| Some(4)),
+    ..WebmachineResource::default()
+  };
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "HEAD".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &head_resource);
+  finalise_response(&mut context, &head_resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(rendered.load(Ordering::SeqCst)).to(be_false());
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+  expect(rendered.load(Ordering::SeqCst)).to(be_false());
+}
+
+#[test]
+fn finalise_response_only_evaluates_a_lazy_body_for_get_requests() {
+  let evaluated = Arc::new(AtomicBool::new(false));
+  let render_evaluated = evaluated.clone();
+  let render = move |_: &mut WebmachineContext, _: &WebmachineResource| {
+    let evaluated = render_evaluated.clone();
+    Some(Box::new(move || {
+      evaluated.store(true, Ordering::SeqCst);
+      "body".to_string()
+    }) as LazyBody)
+  };
+  let resource = WebmachineResource {
+    render_response_lazy: Some(callback(&render)),
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "HEAD".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.has_header("Content-Length")).to(be_false());
+  expect(evaluated.load(Ordering::SeqCst)).to(be_false());
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-None-Match".to_string() => vec![h!("\"1234567890\"")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(304));
+  expect(evaluated.load(Ordering::SeqCst)).to(be_false());
+
+  let mut context = WebmachineContext::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.body).to(be_some().value("body".as_bytes().to_vec()));
+  expect(evaluated.load(Ordering::SeqCst)).to(be_true());
+}
+
+#[test]
+fn head_response_has_the_same_headers_as_the_get_response() {
+  let resource = WebmachineResource {
+    produces: vec!["text/plain"],
+    accept_ranges: true,
+    generate_etag: callback(&|_, _| Some("1234567890".to_string())),
+    render_response: callback(&|_, _| Some("Hello World".to_string())),
+    ..WebmachineResource::default()
+  };
+
+  let mut get_context = WebmachineContext::default();
+  execute_state_machine(&mut get_context, &resource);
+  finalise_response(&mut get_context, &resource);
+  let get_response = generate_http_response(&get_context).unwrap();
+
+  let mut head_context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "HEAD".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  execute_state_machine(&mut head_context, &resource);
+  finalise_response(&mut head_context, &resource);
+  expect!(head_context.request.is_head()).to(be_true());
+  expect!(head_context.response.body.is_none()).to(be_true());
+  let head_response = generate_http_response(&head_context).unwrap();
+
+  expect!(head_response.status()).to(be_equal_to(get_response.status()));
+  for header in ["Content-Type", "ETag", "Accept-Ranges"] {
+    expect!(head_response.headers().get(header)).to(be_equal_to(get_response.headers().get(header)));
+  }
+  expect!(head_response.headers().get("Content-Length").map(|v| v.to_str().unwrap().to_string()))
+    .to(be_some().value("11"));
+}
+
+#[test]
+fn execute_state_machine_records_the_decisions_on_the_context() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    forbidden: callback(&|_, _| true),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(403));
+  expect(context.decisions).to(be_equal_to(vec![
+    ("B13Available".to_string(), true, "B12KnownMethod".to_string()),
+    ("B12KnownMethod".to_string(), true, "B11UriTooLong".to_string()),
+    ("B11UriTooLong".to_string(), false, "B10MethodAllowed".to_string()),
+    ("B10MethodAllowed".to_string(), true, "B9MalformedRequest".to_string()),
+    ("B9MalformedRequest".to_string(), false, "B8Authorized".to_string()),
+    ("B8Authorized".to_string(), true, "B7Forbidden".to_string()),
+    ("B7Forbidden".to_string(), true, "End(403)".to_string())
+  ]));
+}
+
+#[test]
+fn execute_state_machine_returns_302_to_the_timegate_for_an_accept_datetime_request() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      base_path: "/archive".to_string(),
+      request_path: "/page".to_string(),
+      headers: hashmap! {
+        "Accept-Datetime".to_string() => vec![h!("Thu, 31 May 2007 20:35:00 GMT")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    memento_timegate: callback(&|_, _| Some("http://timegate.example.com/archive/page".to_string())),
+    last_modified: callback(&|_, _| Some(DateTime::parse_from_rfc3339("2007-05-31T20:35:00Z").unwrap())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(302));
+  expect(context.response.headers).to(be_equal_to(btreemap! {
+    "Location".to_string() => vec![HeaderValue::basic("http://timegate.example.com/archive/page")],
+    "Link".to_string() => vec![
+      HeaderValue::basic("</archive/page>; rel=\"original\""),
+      HeaderValue::basic("<http://timegate.example.com/archive/page>; rel=\"timegate\"")
+    ],
+    "Vary".to_string() => vec![h!("Accept-Datetime")],
+    "Memento-Datetime".to_string() => vec![HeaderValue::basic("Thu, 31 May 2007 20:35:00 GMT")]
+  }));
+}
+
+#[test]
+fn execute_state_machine_ignores_accept_datetime_if_the_resource_has_no_timegate() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept-Datetime".to_string() => vec![h!("Thu, 31 May 2007 20:35:00 GMT")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+}
+
+#[test]
+fn context_returns_the_negotiated_representation() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("application/xml")],
+        "Accept-Language".to_string() => vec![h!("da")],
+        "Accept-Charset".to_string() => vec![h!("UTF-8")],
+        "Accept-Encoding".to_string() => vec![h!("gzip")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    produces: vec!["application/json", "application/xml"],
+    languages_provided: vec!["en", "da"],
+    charsets_provided: vec!["ISO-8859-1", "UTF-8"],
+    encodings_provided: vec!["identity", "gzip"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.negotiated()).to(be_equal_to(Negotiated {
+    media_type: Some("application/xml".to_string()),
+    language: Some("da".to_string()),
+    charset: Some("UTF-8".to_string()),
+    encoding: Some("gzip".to_string())
+  }));
+}
+
+#[test]
+fn generate_http_response_streams_the_body_from_render_response_stream() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    render_response_stream: callback(&|_, _| {
+      let chunks: Vec<Result<Bytes, Box<dyn Error + Send + Sync>>> = vec![
+        Ok(Bytes::from("chunk 1, ")),
+        Ok(Bytes::from("chunk 2"))
+      ];
+      let stream: BodyStream = Box::pin(futures::stream::iter(chunks));
+      Some(stream)
+    }),
+    render_response: callback(&|_, _| Some("not streamed".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.body.clone()).to(be_none());
+
+  let response = generate_http_response(&context).unwrap();
+  let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+  expect!(body).to(be_equal_to(Bytes::from("chunk 1, chunk 2")));
+}
+
+#[test]
+fn execute_state_machine_falls_back_to_the_default_content_type_if_configured() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "Accept".to_string() => vec![h!("application/xml")]
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    produces: vec!["application/json", "text/plain"],
+    negotiation_fallback_to_default: true,
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("Content-Type").unwrap()).to(be_equal_to(&vec![h!("application/json;charset=ISO-8859-1")]));
+}
+
+#[test]
+fn execute_state_machine_returns_the_status_and_body_of_a_webmachine_error() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    process_put: callback(&|_, _| Err(WebmachineError::StatusWithBody(422, "{\"error\": \"invalid\"}".to_string()))),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(422));
+  expect(context.response.body).to(be_some().value("{\"error\": \"invalid\"}".as_bytes().to_vec()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    process_put: callback(&|_, _| Err(500u16.into())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(500));
+  expect(context.response.body).to(be_none());
+}
+
+#[test]
+fn execute_state_machine_makes_the_content_range_available_to_process_put() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      headers: hashmap!{ "Content-Range".to_string() => vec![h!("bytes 0-9/100")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    process_put: callback(&|context, _| {
+      let range = context.request.content_range();
+      context.response.body = range.map(|range| format!("{}-{}/{:?}", range.first, range.last,
+        range.complete_length).into_bytes());
+      Ok(true)
+    }),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.body).to(be_some().value("0-9/Some(100)".as_bytes().to_vec()));
+}
+
+#[test]
+fn execute_state_machine_returns_400_for_a_put_with_a_malformed_content_range() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "PUT".to_string(),
+      headers: hashmap!{ "Content-Range".to_string() => vec![h!("bytes 10-0/100")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    allowed_methods: vec!["PUT"],
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(400));
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn generate_http_response_for_a_json_error_response() {
+  let context = WebmachineContext {
+    response: WebmachineResponse::json(401, &serde_json::json!({ "error": "not authorised" })),
+    ..WebmachineContext::default()
+  };
+  let response = generate_http_response(&context).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(401));
+  expect!(response.headers().get("Content-Type").unwrap().to_str().unwrap()).to(be_equal_to("application/json"));
+  let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+  expect!(body).to(be_equal_to(Bytes::from("{\"error\":\"not authorised\"}")));
+}
+
+#[test]
+fn execute_state_machine_handles_rfc_1123_dates_sent_by_browsers() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! {
+        "If-Modified-Since".to_string() => parse_header_values("Sun, 06 Nov 1994 08:49:37 GMT")
+      },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| true),
+    last_modified: callback(&|_, _| DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z").ok()),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  expect(context.if_modified_since).to(be_some().value(DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z").unwrap()));
+  expect(context.response.status).to(be_equal_to(304));
+}
+
+#[test]
+fn finalise_response_formats_last_modified_and_expires_as_rfc_1123_dates() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    last_modified: callback(&|_, _| DateTime::parse_from_rfc3339("1994-11-06T18:49:37+10:00").ok()),
+    expires: callback(&|_, _| DateTime::parse_from_rfc3339("1994-11-07T08:49:37Z").ok()),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.headers.get("Last-Modified").unwrap()[0].to_string())
+    .to(be_equal_to("Sun, 06 Nov 1994 08:49:37 GMT".to_string()));
+  expect(context.response.headers.get("Expires").unwrap()[0].to_string())
+    .to(be_equal_to("Mon, 07 Nov 1994 08:49:37 GMT".to_string()));
+}
+
+#[test]
+fn finalise_response_adds_vary_origin_for_a_reflected_cors_origin() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
+      headers: hashmap! { "Origin".to_string() => vec![h!("https://example.com")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    options: callback(&|context, _| Some(hashmap! {
+      "Access-Control-Allow-Origin".to_string() => context.request.find_header("Origin").iter()
+        .map(|origin| origin.value.clone()).collect()
+    })),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Vary").unwrap().clone()).to(be_equal_to(vec![h!("Origin")]));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.headers.get("Access-Control-Allow-Origin").unwrap().clone()).to(be_equal_to(vec![h!("*")]));
+  expect(context.response.headers.get("Vary")).to(be_none());
+}
+
+#[test]
+fn finalise_response_removes_cors_headers_for_a_null_origin_by_default() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! { "Origin".to_string() => vec![h!("null")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource::default();
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.has_header("Access-Control-Allow-Origin")).to(be_false());
+  expect(context.response.has_header("Access-Control-Allow-Methods")).to(be_false());
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
+      headers: hashmap! { "Origin".to_string() => vec![h!("https://example.com")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    options: callback(&|_, _| Some(hashmap! {
+      "Access-Control-Allow-Origin".to_string() => vec!["null".to_string()]
+    })),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.has_header("Access-Control-Allow-Origin")).to(be_false());
+}
+
+#[test]
+fn finalise_response_keeps_cors_headers_for_a_null_origin_if_allowed() {
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      method: "OPTIONS".to_string(),
+      headers: hashmap! { "Origin".to_string() => vec![h!("null")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    cors_allow_null_origin: true,
+    options: callback(&|context, _| Some(hashmap! {
+      "Access-Control-Allow-Origin".to_string() => context.request.header_value("Origin").into_iter().collect()
+    })),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(204));
+  expect(context.response.headers.get("Access-Control-Allow-Origin").unwrap().clone()).to(be_equal_to(vec![h!("null")]));
+  expect(context.response.has_header("Access-Control-Allow-Methods")).to(be_true());
+}
+
+#[test]
+fn headers_from_http_request_does_not_parse_cookie_headers() {
+  let request = Request::builder()
+    .header("Cookie", "a=1; b=2; a=3; token=YWJjZA==")
+    .header("Cookie", "list=x,y")
+    .body(())
+    .unwrap();
+  let (parts, _) = request.into_parts();
+  let request = WebmachineRequest {
+    headers: headers_from_http_request(&parts),
+    ..WebmachineRequest::default()
+  };
+  expect!(request.cookies()).to(be_equal_to(hashmap! {
+    "a".to_string() => "1".to_string(),
+    "b".to_string() => "2".to_string(),
+    "token".to_string() => "YWJjZA==".to_string(),
+    "list".to_string() => "x,y".to_string()
+  }));
+}
+
+#[test]
+fn headers_from_http_request_merges_repeated_headers() {
+  let request = Request::builder()
+    .header("Accept", "application/xml")
+    .header("Accept", "text/plain;q=0.5, application/json")
+    .body(())
+    .unwrap();
+  let (parts, _) = request.into_parts();
+  let headers = headers_from_http_request(&parts);
+  expect!(headers.get("accept").unwrap().clone()).to(be_equal_to(vec![
+    h!("application/xml"), h!("text/plain;q=0.5"), h!("application/json")
+  ]));
+
+  let request = WebmachineRequest {
+    headers,
+    ..WebmachineRequest::default()
+  };
+  let resource = WebmachineResource {
+    produces: vec!["application/xml"],
+    ..WebmachineResource::default()
+  };
+  expect!(content_negotiation::matching_content_type(&resource, &request)).to(be_some().value("application/xml"));
+}
+
+/// All the decisions except `End`. `decision_index` maps each decision to its position in this
+/// array with an exhaustive match, so adding a decision fails to compile until it has an index
+/// there, and `transition_map_has_a_transition_for_every_decision` fails until it is also in the
+/// array at that index.
+const ALL_DECISIONS: [Decision; 59] = [
+  Decision::Start,
+  Decision::A3Options,
+  Decision::B3Options,
+  Decision::B4RequestEntityTooLarge,
+  Decision::B5UnknownContentType,
+  Decision::B6UnsupportedContentHeader,
+  Decision::B7Forbidden,
+  Decision::B8Authorized,
+  Decision::B9MalformedRequest,
+  Decision::B10MethodAllowed,
+  Decision::B11UriTooLong,
+  Decision::B12KnownMethod,
+  Decision::B13Available,
+  Decision::C3AcceptExists,
+  Decision::C4AcceptableMediaTypeAvailable,
+  Decision::D4AcceptLanguageExists,
+  Decision::D5AcceptableLanguageAvailable,
+  Decision::E5AcceptCharsetExists,
+  Decision::E6AcceptableCharsetAvailable,
+  Decision::F6AcceptEncodingExists,
+  Decision::F7AcceptableEncodingAvailable,
+  Decision::G4MementoTimegate,
+  Decision::G6PreconditionRequired,
+  Decision::G7ResourceExists,
+  Decision::G8IfMatchExists,
+  Decision::G9IfMatchStarExists,
+  Decision::G11EtagInIfMatch,
+  Decision::H7IfMatchStarExists,
+  Decision::H10IfUnmodifiedSinceExists,
+  Decision::H11IfUnmodifiedSinceValid,
+  Decision::H12LastModifiedGreaterThanUMS,
+  Decision::I4HasMovedPermanently,
+  Decision::I12IfNoneMatchExists,
+  Decision::I13IfNoneMatchStarExists,
+  Decision::I7Put,
+  Decision::J18GetHead,
+  Decision::K5HasMovedPermanently,
+  Decision::K7ResourcePreviouslyExisted,
+  Decision::K13ETagInIfNoneMatch,
+  Decision::L5HasMovedTemporarily,
+  Decision::L7Post,
+  Decision::L13IfModifiedSinceExists,
+  Decision::L14IfModifiedSinceValid,
+  Decision::L15IfModifiedSinceGreaterThanNow,
+  Decision::L17IfLastModifiedGreaterThanMS,
+  Decision::M5Post,
+  Decision::M7PostToMissingResource,
+  Decision::M16Delete,
+  Decision::M20DeleteEnacted,
+  Decision::N5PostToMissingResource,
+  Decision::N11Redirect,
+  Decision::N16Post,
+  Decision::O14Conflict,
+  Decision::O16Put,
+  Decision::O17Patch,
+  Decision::O18MultipleRepresentations,
+  Decision::O20ResponseHasBody,
+  Decision::P3Conflict,
+  Decision::P11NewResource
+];
+
+fn decision_index(decision: &Decision) -> Option<usize> {
+  match decision {
+    Decision::End(_) => None,
+    Decision::Start => Some(0),
+    Decision::A3Options => Some(1),
+    Decision::B3Options => Some(2),
+    Decision::B4RequestEntityTooLarge => Some(3),
+    Decision::B5UnknownContentType => Some(4),
+    Decision::B6UnsupportedContentHeader => Some(5),
+    Decision::B7Forbidden => Some(6),
+    Decision::B8Authorized => Some(7),
+    Decision::B9MalformedRequest => Some(8),
+    Decision::B10MethodAllowed => Some(9),
+    Decision::B11UriTooLong => Some(10),
+    Decision::B12KnownMethod => Some(11),
+    Decision::B13Available => Some(12),
+    Decision::C3AcceptExists => Some(13),
+    Decision::C4AcceptableMediaTypeAvailable => Some(14),
+    Decision::D4AcceptLanguageExists => Some(15),
+    Decision::D5AcceptableLanguageAvailable => Some(16),
+    Decision::E5AcceptCharsetExists => Some(17),
+    Decision::E6AcceptableCharsetAvailable => Some(18),
+    Decision::F6AcceptEncodingExists => Some(19),
+    Decision::F7AcceptableEncodingAvailable => Some(20),
+    Decision::G4MementoTimegate => Some(21),
+    Decision::G6PreconditionRequired => Some(22),
+    Decision::G7ResourceExists => Some(23),
+    Decision::G8IfMatchExists => Some(24),
+    Decision::G9IfMatchStarExists => Some(25),
+    Decision::G11EtagInIfMatch => Some(26),
+    Decision::H7IfMatchStarExists => Some(27),
+    Decision::H10IfUnmodifiedSinceExists => Some(28),
+    Decision::H11IfUnmodifiedSinceValid => Some(29),
+    Decision::H12LastModifiedGreaterThanUMS => Some(30),
+    Decision::I4HasMovedPermanently => Some(31),
+    Decision::I12IfNoneMatchExists => Some(32),
+    Decision::I13IfNoneMatchStarExists => Some(33),
+    Decision::I7Put => Some(34),
+    Decision::J18GetHead => Some(35),
+    Decision::K5HasMovedPermanently => Some(36),
+    Decision::K7ResourcePreviouslyExisted => Some(37),
+    Decision::K13ETagInIfNoneMatch => Some(38),
+    Decision::L5HasMovedTemporarily => Some(39),
+    Decision::L7Post => Some(40),
+    Decision::L13IfModifiedSinceExists => Some(41),
+    Decision::L14IfModifiedSinceValid => Some(42),
+    Decision::L15IfModifiedSinceGreaterThanNow => Some(43),
+    Decision::L17IfLastModifiedGreaterThanMS => Some(44),
+    Decision::M5Post => Some(45),
+    Decision::M7PostToMissingResource => Some(46),
+    Decision::M16Delete => Some(47),
+    Decision::M20DeleteEnacted => Some(48),
+    Decision::N5PostToMissingResource => Some(49),
+    Decision::N11Redirect => Some(50),
+    Decision::N16Post => Some(51),
+    Decision::O14Conflict => Some(52),
+    Decision::O16Put => Some(53),
+    Decision::O17Patch => Some(54),
+    Decision::O18MultipleRepresentations => Some(55),
+    Decision::O20ResponseHasBody => Some(56),
+    Decision::P3Conflict => Some(57),
+    Decision::P11NewResource => Some(58),
+  }
+}
+
+#[test]
+fn transition_map_has_a_transition_for_every_decision() {
+  for (index, decision) in ALL_DECISIONS.iter().enumerate() {
+    expect!(decision_index(decision)).to(be_some().value(index));
+    if !decision.is_terminal() {
+      assert!(TRANSITION_MAP.contains_key(decision), "{:?} has no transition", decision);
+    }
+  }
+
+  for (from, transition) in TRANSITION_MAP.iter() {
+    let targets = match transition {
+      Transition::To(decision) => vec![decision],
+      Transition::Branch(decision_true, decision_false) => vec![decision_true, decision_false]
+    };
+    for decision in targets.iter() {
+      assert!(decision.is_terminal() || TRANSITION_MAP.contains_key(decision), "{:?} has no transition", decision);
+    }
+    for decision in targets.into_iter().chain(std::iter::once(from)) {
+      if let Some(index) = decision_index(decision) {
+        assert!(ALL_DECISIONS.get(index) == Some(decision), "{:?} is not at index {} of ALL_DECISIONS", decision, index);
+      }
+    }
+  }
+}
+
+#[test]
+fn dispatcher_sends_a_100_continue_interim_response_before_the_body_is_read() {
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  async fn read_response_head(stream: &mut tokio::net::TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+      head.push(stream.read_u8().await.unwrap());
+    }
+    String::from_utf8(head).unwrap()
+  }
+
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  runtime.block_on(async {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let dispatcher: WebmachineDispatcher<'static> = WebmachineDispatcher {
+      routes: btreemap! {
+        "/path" => WebmachineResource {
+          allowed_methods: vec!["POST"],
+          process_post: callback(&|context, _| {
+            context.response.body = context.request.body.clone();
+            Ok(true)
+          }),
+          ..WebmachineResource::default()
+        }
+      },
+      ..WebmachineDispatcher::default()
+    };
+    let server = async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      hyper::server::conn::Http::new().serve_connection(stream, dispatcher).await.unwrap();
+    };
+
+    let client = async move {
+      let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+      client.write_all(b"POST /path HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+        Content-Length: 9\r\nExpect: 100-continue\r\n\r\n").await.unwrap();
+      let interim = read_response_head(&mut client).await;
+      expect!(interim.starts_with("HTTP/1.1 100 Continue")).to(be_true());
+
+      client.write_all(b"{\"a\": 1}\n").await.unwrap();
+      let response = read_response_head(&mut client).await;
+      expect!(response.starts_with("HTTP/1.1 200 OK")).to(be_true());
+      let mut body = [0; 9];
+      client.read_exact(&mut body).await.unwrap();
+      expect!(&body).to(be_equal_to(b"{\"a\": 1}\n"));
+    };
+
+    futures::join!(server, client);
+  });
+}
+
+#[test]
+fn dispatcher_checks_the_expectation_before_the_body_is_read() {
+  use std::sync::atomic::{AtomicBool, Ordering};
+
+  fn request(expect: &str, content_length: usize, body_read: Arc<AtomicBool>) -> Request<Body> {
+    let body = futures::stream::once(async move {
+      body_read.store(true, Ordering::SeqCst);
+      Ok::<_, std::io::Error>("{\"a\": 1}\n")
+    });
+    Request::builder()
+      .method("POST")
+      .uri("/path")
+      .header("Content-Type", "application/json")
+      .header("Content-Length", content_length)
+      .header("Expect", expect)
+      .body(Body::wrap_stream(body))
+      .unwrap()
+  }
+
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/path" => WebmachineResource {
+        allowed_methods: vec!["POST"],
+        max_body_size: Some(100),
+        valid_entity_length: callback(&|context, _| context.request.content_length() != Some(50)),
+        process_post: callback(&|_, _| Ok(true)),
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+
+  let body_read = Arc::new(AtomicBool::new(false));
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(request("100-continue", 1000, body_read.clone()))).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(413));
+  expect!(body_read.load(Ordering::SeqCst)).to(be_false());
+
+  let body_read = Arc::new(AtomicBool::new(false));
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(request("100-continue", 50, body_read.clone()))).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(413));
+  expect!(body_read.load(Ordering::SeqCst)).to(be_false());
+
+  let body_read = Arc::new(AtomicBool::new(false));
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(request("something-else", 9, body_read.clone()))).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(417));
+  expect!(body_read.load(Ordering::SeqCst)).to(be_false());
+
+  let body_read = Arc::new(AtomicBool::new(false));
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(request("100-Continue", 9, body_read.clone()))).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(204));
+  expect!(body_read.load(Ordering::SeqCst)).to(be_true());
+}
+
+#[test]
+fn finalise_response_prevents_content_sniffing_for_downloads() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    produces: vec!["*/*"],
+    prevent_content_sniffing: true,
+    render_response: callback(&|_, _| Some("<html>uploaded</html>".to_string())),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.status).to(be_equal_to(200));
+  expect(context.response.headers.get("X-Content-Type-Options").unwrap().clone()).to(be_equal_to(vec![h!("nosniff")]));
+  expect(context.response.headers.get("Content-Type").unwrap()[0].to_string()).to(be_equal_to("application/octet-stream".to_string()));
+
+  let mut context = WebmachineContext {
+    request: WebmachineRequest {
+      headers: hashmap! { "Accept".to_string() => vec![h!("image/*")] },
+      ..WebmachineRequest::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let resource = WebmachineResource {
+    produces: vec!["image/png"],
+    prevent_content_sniffing: true,
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.headers.get("X-Content-Type-Options").unwrap().clone()).to(be_equal_to(vec![h!("nosniff")]));
+  expect(context.response.headers.get("Content-Type").unwrap()[0].value.as_str()).to(be_equal_to("image/png"));
+}
+
+#[test]
+fn dispatcher_passes_the_request_to_the_raw_request_handler() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/events" => WebmachineResource {
+        available: callback(&|_, _| false),
+        raw_request_handler: Some(Arc::new(|req: Request<Body>| -> RawResponseFuture {
+          Box::pin(async move {
+            let last_event_id = req.headers().get("Last-Event-ID")
+              .and_then(|value| value.to_str().ok())
+              .unwrap_or("0")
+              .to_string();
+            let events: Vec<Result<String, std::io::Error>> = vec![
+              Ok(format!("id: {}\ndata: first\n\n", last_event_id)),
+              Ok("data: second\n\n".to_string())
+            ];
+            Response::builder()
+              .header("Content-Type", "text/event-stream")
+              .body(Body::wrap_stream(futures::stream::iter(events)))
+          })
+        })),
+        ..WebmachineResource::default()
+      }
+    },
+    ..WebmachineDispatcher::default()
+  };
+  let req = Request::builder()
+    .uri("/events")
+    .header("Last-Event-ID", "10")
+    .body(Body::empty())
+    .unwrap();
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(req)).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(200));
+  expect!(response.headers().get("Content-Type").unwrap().to_str().unwrap()).to(be_equal_to("text/event-stream"));
+  let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+  expect!(body).to(be_equal_to(Bytes::from("id: 10\ndata: first\n\ndata: second\n\n")));
+  expect!(dispatcher.status_counters()).to(be_equal_to(hashmap!{ 200 => 1 }));
+}
+
+#[test]
+fn dispatcher_runs_the_interceptors_before_the_raw_request_handler() {
+  let dispatcher = WebmachineDispatcher {
+    routes: btreemap! {
+      "/events" => WebmachineResource {
+        raw_request_handler: Some(Arc::new(|_: Request<Body>| -> RawResponseFuture {
+          panic!("raw request handler should not be called")
+        })),
+        ..WebmachineResource::default()
+      }
+    },
+    interceptors: vec![
+      Arc::new(|context: &mut WebmachineContext| if context.request.has_header("Authorization") {
+        None
+      } else {
+        Some(WebmachineResponse { status: 401, ..WebmachineResponse::default() })
+      })
+    ],
+    ..WebmachineDispatcher::default()
+  };
+  let req = Request::builder()
+    .uri("/events")
+    .body(Body::empty())
+    .unwrap();
+  let response = futures::executor::block_on(dispatcher.clone().dispatch(req)).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(401));
+  expect!(dispatcher.status_counters()).to(be_equal_to(hashmap!{ 401 => 1 }));
+}
+
+#[test]
+fn generate_http_response_sets_a_custom_reason_phrase() {
+  let context = WebmachineContext {
+    response: WebmachineResponse {
+      status: 422,
+      reason: Some("Validation Failed".to_string()),
+      ..WebmachineResponse::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let response = generate_http_response(&context).unwrap();
+  expect!(response.status().as_u16()).to(be_equal_to(422));
+  let reason = response.extensions().get::<hyper::ext::ReasonPhrase>().unwrap();
+  expect!(reason.as_bytes()).to(be_equal_to(b"Validation Failed".as_slice()));
+
+  let context = WebmachineContext {
+    response: WebmachineResponse {
+      status: 422,
+      reason: Some("Invalid\r\nphrase".to_string()),
+      ..WebmachineResponse::default()
+    },
+    ..WebmachineContext::default()
+  };
+  let response = generate_http_response(&context).unwrap();
+  expect!(response.extensions().get::<hyper::ext::ReasonPhrase>()).to(be_none());
+}
+
+#[test]
+fn finalise_response_runs_finish_request_before_the_finalise_response_callback() {
+  let mut context = WebmachineContext::default();
+  let resource = WebmachineResource {
+    finish_request: callback(&|context, _| {
+      context.response.add_header("Access-Control-Allow-Origin", vec![h!("https://example.com")]);
+      context.response.add_header("X-Finished", vec![h!("true")]);
+    }),
+    finalise_response: Some(callback(&|context, _| {
+      context.response.add_header("Access-Control-Allow-Origin", vec![h!("https://other.com")]);
+    })),
+    ..WebmachineResource::default()
+  };
+  execute_state_machine(&mut context, &resource);
+  finalise_response(&mut context, &resource);
+  expect(context.response.headers.get("X-Finished").unwrap().clone()).to(be_equal_to(vec![h!("true")]));
+  expect(context.response.headers.get("Access-Control-Allow-Origin").unwrap().clone())
+    .to(be_equal_to(vec![h!("https://other.com")]));
+}
+
+#[test]
+fn run_returns_the_complete_response_for_the_request() {
+  let resource = WebmachineResource {
+    render_response: callback(&|_, _| Some("{\"id\": 1}".to_string())),
+    ..WebmachineResource::default()
+  };
+  let response = run(&resource, WebmachineRequest::default());
+  expect(response.status).to(be_equal_to(200));
+  expect(response.headers).to(be_equal_to(btreemap! {
+    "Access-Control-Allow-Headers".to_string() => vec![h!("Content-Type")],
+    "Access-Control-Allow-Methods".to_string() => vec![h!("OPTIONS"), h!("GET"), h!("HEAD")],
+    "Access-Control-Allow-Origin".to_string() => vec![h!("*")],
+    "Content-Length".to_string() => vec![h!("9")],
+    "Content-Type".to_string() => vec![h!("application/json;charset=ISO-8859-1")]
+  }));
+  expect(response.body).to(be_some().value("{\"id\": 1}".as_bytes().to_vec()));
+}
+
+#[test]
+fn run_awaits_callbacks_that_use_the_tokio_runtime() {
+  let resource = WebmachineResource {
+    resource_exists_async: Some(async_callback(|_, _| async {
+      tokio::task::spawn_blocking(|| true).await.unwrap()
+    })),
+    render_response_async: Some(async_callback(|_, _| async {
+      tokio::task::spawn(async { Some("{\"id\": 1}".to_string()) }).await.unwrap()
+    })),
+    ..WebmachineResource::default()
+  };
+  let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let response = runtime.block_on(run_async(&resource, WebmachineRequest::default()));
+  expect(response.status).to(be_equal_to(200));
+  expect(response.body).to(be_some().value("{\"id\": 1}".as_bytes().to_vec()));
+}
+
+#[test]
+fn finalise_response_sets_the_content_length_of_buffered_bodies() {
+  let resource = WebmachineResource {
+    render_response: callback(&|_, _| Some("Gr\u{fc}\u{df}e".to_string())),
+    charsets_provided: vec!["UTF-8"],
+    ..WebmachineResource::default()
+  };
+  let response = run(&resource, WebmachineRequest::default());
+  expect(response.status).to(be_equal_to(200));
+  let body_length = response.body.as_ref().map(|body| body.len()).unwrap_or_default();
+  expect(body_length).to(be_equal_to(7));
+  expect(response.headers.get("Content-Length").cloned()).to(be_some().value(vec![h!("7")]));
+
+  let resource = WebmachineResource {
+    resource_exists: callback(&|_, _| false),
+    render_error: callback(&|_, _| Some("missing".to_string())),
+    ..WebmachineResource::default()
+  };
+  let response = run(&resource, WebmachineRequest::default());
+  expect(response.status).to(be_equal_to(404));
+  expect(response.headers.get("Content-Length").cloned()).to(be_some().value(vec![h!("7")]));
+
+  let resource = WebmachineResource {
+    render_response_stream: callback(&|_, _| {
+      let chunks: Vec<Result<Bytes, Box<dyn Error + Send + Sync>>> = vec![Ok(Bytes::from("chunk"))];
+      Some(Box::pin(futures::stream::iter(chunks)) as BodyStream)
+    }),
+    ..WebmachineResource::default()
+  };
+  let response = run(&resource, WebmachineRequest::default());
+  expect(response.status).to(be_equal_to(200));
+  expect(response.has_header("Content-Length")).to(be_false());
 }